@@ -0,0 +1,187 @@
+//! On-disk cache of the parsed and EM-reduced input (steps 1-2). Entries are keyed by
+//! the crate version, the input files' paths, sizes and modification times, and every
+//! setting that changes the output of those steps, so a stale entry is never reused.
+
+use anyhow::{anyhow, Context, Result};
+use nalgebra_sparse::CsrMatrix;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::pipeline::MetaBayesConfig;
+use crate::step2_reduce::{SeparatedReads, Step2Result};
+
+/// Configuration fields that change the output of steps 1-2
+const KEY_PARAMS: &[&str] = &[
+    "input_format", "min_ref_len", "median_aligned_only", "coverage", "min_align_len",
+    "assume_coord_sorted", "error_model", "use_md_tag", "indel_rate", "include_taxa", "exclude_taxa", "subsample",
+    "acc2taxid", "paired", "genome_lengths", "em_read_cutoff", "no_pre_filter", "em_iter", "em_accelerate",
+    "drop_empty_reads", "strict", "merge_identical_taxa", "em_tol_type", "separate_host_reads", "host_taxon",
+];
+
+/// Input files of steps 1-2 besides the alignments; their identity is part of the key too
+const KEY_FILES: &[&str] = &["include_taxa", "exclude_taxa", "acc2taxid", "genome_lengths"];
+
+/// Steps 1-2 output, as used by the rest of the pipeline
+pub struct ParsedInput {
+    pub num_reads: usize,
+    /// Read names by original row (before `kept_reads` filtering)
+    pub read_names: Vec<String>,
+    pub detected_median_len: f64,
+    pub coverage_breadth: Option<HashMap<String, f64>>,
+    pub step2: Step2Result,
+}
+
+#[derive(Serialize)]
+struct EntryRef<'a> {
+    key: &'a Value,
+    num_reads: usize,
+    read_names: &'a [String],
+    detected_median_len: f64,
+    coverage_breadth: Option<&'a HashMap<String, f64>>,
+    nrows: usize,
+    ncols: usize,
+    row_offsets: &'a [usize],
+    col_indices: &'a [usize],
+    values: &'a [f64],
+    reduced_taxons: &'a [String],
+    reduced_abundances: &'a [f64],
+    reduced_genome_lengths: &'a [f64],
+    kept_reads: Option<&'a [usize]>,
+    merged_taxa: &'a HashMap<String, Vec<String>>,
+    column_support: &'a [usize],
+    separated_reads: Option<SeparatedReads>,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    key: Value,
+    num_reads: usize,
+    read_names: Vec<String>,
+    detected_median_len: f64,
+    coverage_breadth: Option<HashMap<String, f64>>,
+    nrows: usize,
+    ncols: usize,
+    row_offsets: Vec<usize>,
+    col_indices: Vec<usize>,
+    values: Vec<f64>,
+    reduced_taxons: Vec<String>,
+    reduced_abundances: Vec<f64>,
+    reduced_genome_lengths: Vec<f64>,
+    kept_reads: Option<Vec<usize>>,
+    merged_taxa: HashMap<String, Vec<String>>,
+    column_support: Vec<usize>,
+    separated_reads: Option<SeparatedReads>,
+}
+
+/// Path, size and modification time (ns) of a file; size and time are null if it is missing
+fn file_identity(path: &str) -> Value {
+    let meta = std::fs::metadata(path).ok();
+    json!({
+        "path": std::fs::canonicalize(path).map(|p| p.display().to_string()).unwrap_or_else(|_| path.to_string()),
+        "size_bytes": meta.as_ref().map(|m| m.len()),
+        "modified_ns": meta.and_then(|m| m.modified().ok())
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos().to_string()),
+    })
+}
+
+/// Cache key for this configuration. `seed` only matters when subsampling.
+pub fn cache_key(config: &MetaBayesConfig, seed: u64) -> Result<Value> {
+    let all = serde_json::to_value(config)?;
+    let params: Map<String, Value> = KEY_PARAMS.iter()
+        .map(|&name| (name.to_string(), all[name].clone()))
+        .collect();
+    let files: Map<String, Value> = KEY_FILES.iter()
+        .filter_map(|&name| all[name].as_str().map(|path| (name.to_string(), file_identity(path))))
+        .collect();
+    Ok(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "inputs": config.input.iter().map(|p| file_identity(p)).collect::<Vec<_>>(),
+        "files": files,
+        "params": params,
+        "subsample_seed": config.subsample.map(|_| seed),
+    }))
+}
+
+/// `<dir>/<hash of key>.json` (FNV-1a over the key's JSON text)
+pub fn cache_path(dir: &str, key: &Value) -> String {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in key.to_string().bytes() {
+        h = (h ^ b as u64).wrapping_mul(0x0100_0000_01b3);
+    }
+    Path::new(dir).join(format!("{:016x}.json", h)).display().to_string()
+}
+
+/// Loads a cached entry. Returns `None` when there is none, or when it cannot be read
+/// or was written for a different key (it is then overwritten by this run).
+pub fn load(path: &str, key: &Value) -> Result<Option<ParsedInput>> {
+    let Ok(file) = File::open(path) else { return Ok(None) };
+    let entry: Entry = match serde_json::from_reader(BufReader::new(file)) {
+        Ok(entry) => entry,
+        Err(e) => {
+            eprintln!("Warning: ignoring unreadable cache entry {}: {}", path, e);
+            return Ok(None);
+        }
+    };
+    if entry.key != *key {
+        eprintln!("Warning: cache entry {} was written for different inputs; recomputing.", path);
+        return Ok(None);
+    }
+
+    let matrix = CsrMatrix::try_from_csr_data(entry.nrows, entry.ncols, entry.row_offsets, entry.col_indices, entry.values)
+        .map_err(|e| anyhow!("Cached matrix in {} is malformed: {}", path, e))?;
+    Ok(Some(ParsedInput {
+        num_reads: entry.num_reads,
+        read_names: entry.read_names,
+        detected_median_len: entry.detected_median_len,
+        coverage_breadth: entry.coverage_breadth,
+        step2: Step2Result {
+            matrix,
+            reduced_taxons: entry.reduced_taxons,
+            reduced_abundances: entry.reduced_abundances,
+            reduced_genome_lengths: entry.reduced_genome_lengths,
+            kept_reads: entry.kept_reads,
+            merged_taxa: entry.merged_taxa,
+            column_support: entry.column_support,
+            separated_reads: entry.separated_reads,
+        },
+    }))
+}
+
+pub fn save(path: &str, key: &Value, input: &ParsedInput) -> Result<()> {
+    if let Some(dir) = Path::new(path).parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create cache directory {}", dir.display()))?;
+    }
+    let step2 = &input.step2;
+    let entry = EntryRef {
+        key,
+        num_reads: input.num_reads,
+        read_names: &input.read_names,
+        detected_median_len: input.detected_median_len,
+        coverage_breadth: input.coverage_breadth.as_ref(),
+        nrows: step2.matrix.nrows(),
+        ncols: step2.matrix.ncols(),
+        row_offsets: step2.matrix.row_offsets(),
+        col_indices: step2.matrix.col_indices(),
+        values: step2.matrix.values(),
+        reduced_taxons: &step2.reduced_taxons,
+        reduced_abundances: &step2.reduced_abundances,
+        reduced_genome_lengths: &step2.reduced_genome_lengths,
+        kept_reads: step2.kept_reads.as_deref(),
+        merged_taxa: &step2.merged_taxa,
+        column_support: &step2.column_support,
+        separated_reads: step2.separated_reads,
+    };
+    // Write to a temp file first so an interrupted run never leaves a truncated entry
+    let tmp_path = format!("{}.tmp", path);
+    let file = BufWriter::new(File::create(&tmp_path).with_context(|| format!("Failed to create {}", tmp_path))?);
+    serde_json::to_writer(file, &entry)?;
+    std::fs::rename(&tmp_path, path).context("Failed to finalize cache entry")?;
+    println!("Cached parsed input to: {}", path);
+    Ok(())
+}
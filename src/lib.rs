@@ -0,0 +1,23 @@
+//! MetaMix-RS: Bayesian estimation of species abundances from read alignments.
+//!
+//! The pipeline runs in four steps: alignment parsing (`step1_parser`), EM
+//! dimension reduction (`step2_reduce`), parallel-tempering MCMC over species
+//! sets (`step3_mcmc`) and final Gibbs inference/reporting (`step4_inference`).
+//! [`run_pipeline`] chains them using a [`MetaBayesConfig`]. [`merge_results`]
+//! combines the results of several samples into cohort tables. With the `python`
+//! feature the crate also builds a Python extension exposing `run_pipeline`.
+
+pub mod step1_parser;
+pub mod step2_reduce;
+pub mod step3_mcmc;
+pub mod step4_inference;
+pub mod merge;
+
+mod cache;
+mod pipeline;
+#[cfg(feature = "python")]
+mod python;
+
+pub use merge::{merge_results, MergeConfig};
+pub use pipeline::{run_pipeline, MetaBayesConfig};
+pub use step4_inference::{InferenceResult, TaxonResult};
@@ -0,0 +1,217 @@
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Combines per-sample `_results.tsv` files into cohort-wide feature tables
+#[derive(Parser, Clone, Debug)]
+#[command(name = "merge")]
+pub struct MergeConfig {
+    /// `_results.tsv` files (`<dir>/results.tsv` for --output-dir runs), one per sample
+    #[arg(required = true)]
+    pub results: Vec<String>,
+    /// Output prefix; writes <prefix>_abundance.tsv and <prefix>_presence.tsv
+    #[arg(short, long, default_value = "metamix_merged")]
+    pub output: String,
+    /// Minimum posterior inclusion probability for a taxon to count as present (files without
+    /// a Posterior column, from --em-only runs, count any taxon with a non-zero abundance)
+    #[arg(long, default_value_t = 0.5)]
+    pub min_inclusion: f64,
+}
+
+/// One row of a results file
+struct TaxonRow {
+    name: String,
+    mean_abundance: f64,
+    /// Posterior inclusion; `None` for files without a `Posterior` column (`--em-only` results)
+    inclusion: Option<f64>,
+}
+
+impl TaxonRow {
+    /// Inclusion at or above `min_inclusion`, or, without one, a non-zero abundance
+    fn is_present(&self, min_inclusion: f64) -> bool {
+        match self.inclusion {
+            Some(p) => p >= min_inclusion,
+            None => self.mean_abundance > 0.0,
+        }
+    }
+}
+
+/// Sample name from a results path: the file name without `_results.tsv`, or the
+/// directory name for a bare `results.tsv` (written with `--output-dir`)
+fn sample_name(path: &str) -> String {
+    let path_ref = Path::new(path);
+    let file_name = path_ref.file_name().map(|f| f.to_string_lossy().to_string()).unwrap_or_else(|| path.to_string());
+    if file_name == "results.tsv"
+        && let Some(dir) = path_ref.parent().and_then(|d| d.file_name())
+    {
+        return dir.to_string_lossy().to_string();
+    }
+    file_name.strip_suffix("_results.tsv").map(str::to_string).unwrap_or(file_name)
+}
+
+fn read_results(path: &str) -> Result<HashMap<String, TaxonRow>> {
+    let file = File::open(path).with_context(|| format!("Failed to open results file: {}", path))?;
+    let mut lines = BufReader::new(file).lines();
+
+    // Skip leading comments up to the header
+    let header = loop {
+        match lines.next() {
+            Some(line) => {
+                let line = line?;
+                if !line.starts_with('#') { break line; }
+            }
+            None => bail!("{}: no header line found", path),
+        }
+    };
+    let columns: Vec<&str> = header.split('\t').collect();
+    let column = |name: &str| -> Result<usize> {
+        columns.iter().position(|c| *c == name).with_context(|| format!("{}: missing column {}", path, name))
+    };
+    let (id_col, name_col, mean_col) = (column("TaxonID")?, column("ScientificName")?, column("MeanAbundance")?);
+    let post_col = columns.iter().position(|c| *c == "Posterior");
+    if post_col.is_none() {
+        eprintln!("Warning: {} has no Posterior column (--em-only results); taxa with a non-zero abundance count as present.", path);
+    }
+
+    let mut rows = HashMap::new();
+    for line in lines {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') { continue; }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < columns.len() {
+            bail!("{}: expected {} columns, found {}", path, columns.len(), fields.len());
+        }
+        let mean_abundance: f64 = fields[mean_col].parse()
+            .with_context(|| format!("{}: invalid MeanAbundance '{}'", path, fields[mean_col]))?;
+        // The optional Unknown row reports NA here
+        let inclusion = post_col.map(|col| fields[col].parse().unwrap_or(0.0));
+        rows.insert(fields[id_col].to_string(), TaxonRow { name: fields[name_col].to_string(), mean_abundance, inclusion });
+    }
+    Ok(rows)
+}
+
+/// Writes a taxon x sample matrix of mean abundances and a 0/1 presence matrix
+/// (posterior inclusion >= `min_inclusion`). Taxa missing from a sample get 0.
+pub fn merge_results(config: &MergeConfig) -> Result<()> {
+    let samples: Vec<String> = config.results.iter().map(|p| sample_name(p)).collect();
+    let mut seen: HashMap<&str, &str> = HashMap::new();
+    for (sample, path) in samples.iter().zip(&config.results) {
+        if let Some(other) = seen.insert(sample, path) {
+            bail!("{} and {} would both be named sample '{}'; rename one of them", other, path, sample);
+        }
+    }
+    let tables = config.results.iter().map(|p| read_results(p)).collect::<Result<Vec<_>>>()?;
+
+    // Sorted taxon IDs with the first scientific name seen ("NA" only if no sample named it)
+    let mut taxa: BTreeMap<&str, &str> = BTreeMap::new();
+    for table in &tables {
+        for (id, row) in table {
+            let name = taxa.entry(id.as_str()).or_insert(row.name.as_str());
+            if *name == "NA" { *name = row.name.as_str(); }
+        }
+    }
+    println!("Merging {} samples covering {} taxa.", samples.len(), taxa.len());
+
+    let abundance_path = format!("{}_abundance.tsv", config.output);
+    let presence_path = format!("{}_presence.tsv", config.output);
+    let mut abundance = BufWriter::new(File::create(&abundance_path)?);
+    let mut presence = BufWriter::new(File::create(&presence_path)?);
+
+    for file in [&mut abundance, &mut presence] {
+        write!(file, "TaxonID\tScientificName")?;
+        for sample in &samples { write!(file, "\t{}", sample)?; }
+        writeln!(file)?;
+    }
+
+    for (id, name) in &taxa {
+        write!(abundance, "{}\t{}", id, name)?;
+        write!(presence, "{}\t{}", id, name)?;
+        for table in &tables {
+            let row = table.get(*id);
+            let mean = row.map(|r| r.mean_abundance).unwrap_or(0.0);
+            let present = row.is_some_and(|r| r.is_present(config.min_inclusion));
+            write!(abundance, "\t{:.6}", mean)?;
+            write!(presence, "\t{}", present as u8)?;
+        }
+        writeln!(abundance)?;
+        writeln!(presence)?;
+    }
+
+    println!("Wrote {} and {}", abundance_path, presence_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fresh scratch directory for one test
+    fn scratch(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("metamix_merge_{}_{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_results(path: &Path, rows: &[&str]) {
+        let mut text = String::from("# CI_Method: quantile\nTaxonID\tScientificName\tMeanAbundance\tPosterior\n");
+        for row in rows {
+            text.push_str(row);
+            text.push('\n');
+        }
+        std::fs::write(path, text).unwrap();
+    }
+
+    #[test]
+    fn output_dir_runs_are_named_after_their_directories() {
+        let dir = scratch("output_dir");
+        for (sample, rows) in [("gut", ["100\tA\t0.7\t1.0"]), ("skin", ["200\tB\t0.4\t0.9"])] {
+            std::fs::create_dir_all(dir.join(sample)).unwrap();
+            write_results(&dir.join(sample).join("results.tsv"), &rows);
+        }
+        let results = ["gut", "skin"].map(|s| dir.join(s).join("results.tsv").display().to_string());
+        let output = dir.join("cohort").display().to_string();
+        merge_results(&MergeConfig { results: results.to_vec(), output: output.clone(), min_inclusion: 0.5 }).unwrap();
+
+        let abundance = std::fs::read_to_string(format!("{}_abundance.tsv", output)).unwrap();
+        assert_eq!(abundance.lines().next(), Some("TaxonID\tScientificName\tgut\tskin"));
+
+        // The same directory twice cannot give two distinct columns
+        let twice = vec![results[0].clone(), results[0].clone()];
+        assert!(merge_results(&MergeConfig { results: twice, output, min_inclusion: 0.5 }).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_fills_missing_taxa_and_handles_unknown_and_em_only_rows() {
+        let dir = scratch("tables");
+        let a = dir.join("a_results.tsv");
+        let b = dir.join("b_results.tsv");
+        let c = dir.join("c_results.tsv");
+        write_results(&a, &["100\tAlpha\t0.6\t0.95", "200\tBeta\t0.3\t0.2", "Unknown\tUnknown\t0.1\tNA"]);
+        write_results(&b, &["200\tBeta\t0.8\t1.0"]);
+        std::fs::write(&c, "# Mode: em-only\nTaxonID\tScientificName\tMeanAbundance\tEstimatedReads\tAssignedReads\n\
+                            100\tAlpha\t0.5\t5.00\t5.00\n300\tGamma\t0.0\t0.00\t0.00\n# Unassigned_Reads: 0\n").unwrap();
+        let results = [a, b, c].map(|p| p.display().to_string()).to_vec();
+        let output = dir.join("cohort").display().to_string();
+        merge_results(&MergeConfig { results, output: output.clone(), min_inclusion: 0.5 }).unwrap();
+
+        let read = |kind: &str| std::fs::read_to_string(format!("{}_{}.tsv", output, kind)).unwrap();
+        assert_eq!(read("abundance"), "TaxonID\tScientificName\ta\tb\tc\n\
+                                       100\tAlpha\t0.600000\t0.000000\t0.500000\n\
+                                       200\tBeta\t0.300000\t0.800000\t0.000000\n\
+                                       300\tGamma\t0.000000\t0.000000\t0.000000\n\
+                                       Unknown\tUnknown\t0.100000\t0.000000\t0.000000\n");
+        // Presence follows the inclusion threshold, or a non-zero abundance in em-only results;
+        // the Unknown row has no inclusion and is never present
+        assert_eq!(read("presence"), "TaxonID\tScientificName\ta\tb\tc\n\
+                                      100\tAlpha\t1\t0\t1\n\
+                                      200\tBeta\t0\t1\t0\n\
+                                      300\tGamma\t0\t0\t0\n\
+                                      Unknown\tUnknown\t0\t0\t0\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
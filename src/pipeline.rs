@@ -0,0 +1,1027 @@
+use clap::Parser;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::cache;
+use crate::step1_parser;
+use crate::step2_reduce;
+use crate::step3_mcmc;
+use crate::step4_inference::{self, InferenceResult};
+
+/// Full pipeline configuration. Doubles as the command-line interface of the
+/// `metamix_rs` binary; library users can build one with [`MetaBayesConfig::new`]
+/// and adjust fields directly.
+#[derive(Parser, Clone, Debug, Serialize)]
+#[command(name = "MetaMix-RS")]
+pub struct MetaBayesConfig {
+    /// BAM file(s) sorted by read name (eg: output of samtools sort -n).
+    /// Repeat the flag or pass a comma-separated list to merge several files by read name
+    #[arg(short, long, required = true, num_args = 1.., value_delimiter = ',')]
+    pub input: Vec<String>,
+    /// Accept coordinate-sorted BAMs; reads are grouped by name in memory instead of requiring samtools sort -n
+    #[arg(long, default_value_t = false)]
+    pub assume_coord_sorted: bool,
+    /// Accession -> taxon ID table (two columns, or NCBI accession2taxid); reference
+    /// names are looked up here so accessions of one taxon share a matrix column
+    #[arg(long)]
+    pub acc2taxid: Option<String>,
+    /// Treat mates (SAM segment flags, `/1` `/2` name suffixes stripped) as one read and
+    /// score them jointly; requires a name-sorted BAM
+    #[arg(long, default_value_t = false, conflicts_with = "assume_coord_sorted")]
+    pub paired: bool,
+    /// Alignment input format
+    #[arg(long, value_enum, default_value_t = step1_parser::InputFormat::Bam)]
+    pub input_format: step1_parser::InputFormat,
+    /// Output prefix
+    #[arg(short, long, default_value = "metamix_out")]
+    pub output: String,
+    /// Treat --output as a directory (created if needed) holding every output file
+    /// under a fixed name (results.tsv, mcmc_trace.tsv, manifest.json, ...)
+    #[arg(long, default_value_t = false)]
+    pub output_dir: bool,
+    /// Output posterior distribution of read counts per species
+    #[arg(long, default_value_t = false)]
+    pub output_posterior: bool,
+    /// Write names (and posterior probability) of reads assigned to the Unknown bin to this file
+    #[arg(long)]
+    pub unknown_reads: Option<String>,
+    /// Gzip the read-assignment and posterior-sample files (adds .gz)
+    #[arg(long, default_value_t = false)]
+    pub compress_output: bool,
+    /// Format of the main results file
+    #[arg(long, value_enum, default_value_t = step4_inference::OutputFormat::Tsv)]
+    pub output_format: step4_inference::OutputFormat,
+    /// Row order of the TSV and JSON results (ties broken by taxon ID). The default keeps
+    /// rows in the same order across runs, so results can be diffed
+    #[arg(long, value_enum, default_value_t = step4_inference::SortBy::TaxonId)]
+    pub sort_by: step4_inference::SortBy,
+    #[arg(short, long)]
+    pub threads: Option<usize>,
+    /// Path to NCBI names.dmp file for scientific name mapping
+    #[arg(long)]
+    pub taxonomy_names: Option<String>,
+    /// Path to NCBI nodes.dmp; writes <output>_ranks.tsv with abundances summed at every
+    /// standard rank (domain to species)
+    #[arg(long)]
+    pub report_all_ranks: Option<String>,
+
+    #[arg(long, default_value_t = false)]
+    pub verbose: bool,
+    /// Fail when any read has no scored alignment instead of keeping it as an all-Unknown
+    /// row (reads dropped by --include-taxa / --exclude-taxa are allowed); --verbose lists them
+    #[arg(long, default_value_t = false)]
+    pub strict: bool,
+    /// Seed for reproducible random draws (random if not set; the value used is recorded in the results)
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Keep each read with this probability (0-1) for quick exploratory runs; reproducible with --seed
+    #[arg(long)]
+    pub subsample: Option<f64>,
+    /// Run steps 1-2, report matrix dimensions and the L-penalty, then exit before MCMC
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+    /// Print reference sequence statistics from the BAM headers, then exit
+    #[arg(long, default_value_t = false)]
+    pub list_references: bool,
+    /// Record only every Nth iteration of the MCMC history and Gibbs samples (bounds memory)
+    #[arg(long, default_value_t = 1)]
+    pub thin: usize,
+    // --- STEP 2 ARGS ---
+    /// Minimum aligned reads to consider a species valid for EM
+    #[arg(long, default_value_t = 1)]
+    pub em_read_cutoff: usize,
+    /// Skip the post-EM effective-count filter and pass every species with at least one read
+    /// to MCMC, leaving all pruning to the L-penalty (more sensitive for rare taxa, but slower)
+    #[arg(long, default_value_t = false, conflicts_with = "em_read_cutoff")]
+    pub no_pre_filter: bool,
+    /// Maximum EM iterations
+    #[arg(long, default_value_t = 1000)]
+    pub em_iter: usize,
+    /// Accelerate the EM with SQUAREM extrapolation (iterations count EM evaluations)
+    #[arg(long, default_value_t = false)]
+    pub em_accelerate: bool,
+    /// EM stopping criterion: summed absolute abundance change or relative log-likelihood change
+    #[arg(long, value_enum, default_value_t = step2_reduce::EmTolType::Abs)]
+    pub em_tol_type: step2_reduce::EmTolType,
+    /// Remove reads with no alignment to a retained taxon instead of assigning them to Unknown
+    #[arg(long, default_value_t = false)]
+    pub drop_empty_reads: bool,
+    /// Write the step 2 log-probability matrix in MatrixMarket format to this path,
+    /// with read names in <path>.rows and taxon IDs in <path>.cols
+    #[arg(long)]
+    pub export_matrix_mtx: Option<String>,
+    /// Write <output>_support.tsv: reads aligned to each retained taxon, its step 2 EM
+    /// abundance and the effective count checked against --em-read-cutoff
+    #[arg(long, default_value_t = false)]
+    pub report_support: bool,
+    /// Cache the parsed and EM-reduced input here, keyed by the input files and parsing
+    /// settings; later runs with the same key skip steps 1-2
+    #[arg(long)]
+    pub cache_dir: Option<String>,
+    /// Write matrix entries to a temporary file in this directory while parsing instead of
+    /// keeping them in memory (for inputs with more alignments than fit in RAM)
+    #[arg(long)]
+    pub spill_dir: Option<String>,
+    /// Collapse taxa whose matrix columns are identical (indistinguishable strains) into one
+    /// representative; the results list the merged members
+    #[arg(long, default_value_t = false)]
+    pub merge_identical_taxa: bool,
+
+    // --- STEP 3 ARGS ---
+    /// Number of MCMC chains
+    #[arg(long, default_value_t = 12)]
+    pub chains: usize,
+    /// Total MCMC iterations per chain
+    #[arg(long, default_value_t = 1000)]
+    pub mcmc_iter: usize,
+    /// MCMC iterations discarded before the trace, convergence report, posterior inclusion and
+    /// chain pooling (default: the first 10% of each chain's recorded history).
+    /// The final Gibbs sampler uses --gibbs-burnin instead
+    #[arg(long)]
+    pub mcmc_burnin: Option<usize>,
+    /// Start the cold chain from the step 2 EM solution (all retained taxa) instead of
+    /// an empty set; the other chains still start empty
+    #[arg(long)]
+    pub em_init_cold: bool,
+    /// Never let the species set grow beyond this many taxa (add moves are disabled at the cap)
+    #[arg(long, conflicts_with = "em_init_cold")]
+    pub max_species: Option<usize>,
+    /// Favour high-weight taxa in add proposals early on by raising their weights to
+    /// --anneal-power, relaxing to the plain weights over the first --anneal-fraction of iterations
+    #[arg(long)]
+    pub anneal_proposals: bool,
+    /// Initial exponent on the add-proposal weights with --anneal-proposals
+    #[arg(long, default_value_t = 2.0)]
+    pub anneal_power: f64,
+    /// Fraction of --mcmc-iter over which --anneal-proposals relaxes
+    #[arg(long, default_value_t = 0.2)]
+    pub anneal_fraction: f64,
+    /// Number of chains kept at temperature 1.0; their samples are pooled for inference
+    #[arg(long, default_value_t = 1)]
+    pub cold_chains: usize,
+    /// Temperature ladder step: T(i) = (T(i-1) - k)^a
+    #[arg(long, default_value_t = 0.001)]
+    pub temp_k: f64,
+    /// Temperature ladder exponent: T(i) = (T(i-1) - k)^a
+    #[arg(long, default_value_t = 1.5)]
+    pub temp_a: f64,
+    /// Adapt the temperature ladder during warmup to target ~0.3 swap acceptance
+    #[arg(long, default_value_t = false)]
+    pub auto_temp: bool,
+    /// Number of warmup iterations used to tune the ladder with --auto-temp
+    #[arg(long, default_value_t = 200)]
+    pub auto_temp_warmup: usize,
+    /// Exchange interval for Parallel Tempering
+    #[arg(long, default_value_t = 1)]
+    pub exchange_interval: usize,
+    /// Adapt the exchange interval to the swap acceptance rate: doubled while swaps are
+    /// mostly accepted, halved while mostly rejected (between 1 and 16x --exchange-interval).
+    /// Not resumable: checkpoints do not record the adapted block layout
+    #[arg(long, default_value_t = false)]
+    pub adaptive_exchange: bool,
+    /// Stop MCMC early once the cold chain's species set is stable
+    #[arg(long, default_value_t = false)]
+    pub mcmc_autostop: bool,
+    /// Number of iterations the cold chain's species set must stay unchanged to trigger autostop
+    #[arg(long, default_value_t = 200)]
+    pub autostop_window: usize,
+    /// Wall-clock budget for the MCMC phase in seconds; chains stop early and inference
+    /// runs on their current state once it is exceeded
+    #[arg(long)]
+    pub max_runtime: Option<u64>,
+    /// Skip MCMC and inference: assign reads by the step 2 EM abundances and write a
+    /// simplified TSV results file (no credible intervals, Bayes factors or Unknown row)
+    #[arg(long, default_value_t = false, conflicts_with = "fixed_species")]
+    pub em_only: bool,
+    /// File of taxon IDs (one per line) to quantify directly, skipping the MCMC species search
+    #[arg(long)]
+    pub fixed_species: Option<String>,
+    /// Periodically save all chain states to this file; their history is appended to <file>.history
+    #[arg(long)]
+    pub checkpoint: Option<String>,
+    /// Write a checkpoint every N exchange blocks
+    #[arg(long, default_value_t = 100)]
+    pub checkpoint_every: usize,
+    /// Resume MCMC from a checkpoint file written by --checkpoint
+    #[arg(long)]
+    pub resume: Option<String>,
+    /// Checkpoint (--checkpoint) of a previous run, possibly on different input: its cold
+    /// chain's species and abundances seed the cold chain here and are mixed 50/50 into
+    /// the add-proposal weights
+    #[arg(long, conflicts_with_all = ["resume", "em_init_cold", "max_species"])]
+    pub warm_start_from: Option<String>,
+    /// Scale each species' penalty by --read-support over the number of reads aligned to it,
+    /// instead of applying the same penalty to every species
+    #[arg(long, default_value_t = false)]
+    pub adaptive_penalty: bool,
+    /// TSV of `<taxon ID>\t<log prior>` added to the presence penalty when that species is
+    /// in the set (positive favours inclusion); unlisted taxa use the uniform penalty
+    #[arg(long)]
+    pub presence_prior: Option<String>,
+    /// Dirichlet concentration for the starting abundances of proposed species sets
+    /// (affects only where the mini-EM starts, not the posterior)
+    #[arg(long, default_value_t = 1.0)]
+    pub init_concentration: f64,
+    /// Start proposals from the current abundances mixed with this weight of a random draw (0-1)
+    #[arg(long)]
+    pub init_warm_start: Option<f64>,
+    /// Read support threshold for Penalty calculation
+    #[arg(long, default_value_t = 30)]
+    pub read_support: usize,
+    /// Per-read Unknown probability used to derive the penalty (lpenalty = l_null - l_one, with
+    /// l_null = N*ln(p) for all reads unknown and l_one with --read-support reads moved to a
+    /// species). Lower values raise the likelihood gain a species must bring, so fewer taxa are accepted
+    #[arg(long, default_value_t = 1e-20)]
+    pub unknown_penalty_ref: f64,
+    /// Assumed genome length of the organisms behind Unknown reads (e.g. database size or a
+    /// typical novel genome). The penalty then uses 1/L as the reference Unknown probability in
+    /// place of --unknown-penalty-ref, decoupled from the median genome length of the known
+    /// species. Larger values lower the reference probability, so fewer taxa are accepted
+    #[arg(long, conflicts_with = "unknown_penalty_ref")]
+    pub unknown_genome_len: Option<f64>,
+    /// Median genome length (default: auto-detected from BAM header)
+    #[arg(long)]
+    pub median_genome_len: Option<f64>,
+    /// Ignore references shorter than this when auto-detecting the median genome length
+    #[arg(long, default_value_t = 0)]
+    pub min_ref_len: u64,
+    /// Auto-detect the median genome length only over references with at least one alignment
+    #[arg(long, default_value_t = false)]
+    pub median_aligned_only: bool,
+    /// TSV of reference name or taxon ID and true genome length, overriding header lengths
+    #[arg(long)]
+    pub genome_lengths: Option<String>,
+    /// File of taxon IDs / reference names (one per line); all other taxa are dropped before EM
+    #[arg(long)]
+    pub include_taxa: Option<String>,
+    /// File of taxon IDs / reference names (one per line) to drop before EM
+    #[arg(long)]
+    pub exclude_taxa: Option<String>,
+    /// Alignment error model: phred-poisson, fixed-rate[:<rate>] or binomial[:<rate>] (rate defaults to 0.03)
+    #[arg(long, default_value = "phred-poisson")]
+    pub error_model: step1_parser::ErrorModel,
+    /// Count substitutions from the MD tag and indels from the CIGAR, scoring substitutions
+    /// with --error-model and indels with --indel-rate (BAM only; alignments without MD use NM)
+    #[arg(long, default_value_t = false)]
+    pub use_md_tag: bool,
+    /// Per-base indel rate for --use-md-tag
+    #[arg(long, default_value_t = 0.001, requires = "use_md_tag")]
+    pub indel_rate: f64,
+    /// Skip alignments with fewer aligned read bases than this (soft clips excluded)
+    #[arg(long, default_value_t = 0)]
+    pub min_align_len: u64,
+    /// Compute per-species breadth of coverage and report it in the results
+    #[arg(long, default_value_t = false)]
+    pub coverage: bool,
+    /// Drop reported species whose breadth of coverage is below this (0-1; requires --coverage).
+    /// Their reads are reclassified and the species listed in <output>_filtered.tsv
+    #[arg(long)]
+    pub min_breadth: Option<f64>,
+    /// Drop reported species whose Gibbs mean abundance is below this (0-1); the sampler is
+    /// rerun without them and they are listed in <output>_filtered.tsv
+    #[arg(long)]
+    pub min_abundance: Option<f64>,
+    /// Widen credible intervals by sqrt(weighted / effective read count) so downweighted reads
+    /// (e.g. from --subsample) do not overstate precision
+    #[arg(long, default_value_t = false)]
+    pub ess_adjusted_ci: bool,
+    /// Also estimate frequentist intervals from this many Poisson bootstrap replicates over
+    /// reads, refitting the final species set by mini-EM; written to <output>_bootstrap.tsv
+    #[arg(long)]
+    pub bootstrap: Option<usize>,
+    /// Lower bound for the learned Unknown-bin read probability
+    #[arg(long, default_value_t = 1e-300)]
+    pub unk_prob_min: f64,
+    /// Upper bound for the learned Unknown-bin read probability.
+    /// Lower this for host-depleted samples to keep reads off the Unknown bin
+    #[arg(long, default_value_t = 1e-5)]
+    pub unk_prob_max: f64,
+    /// Factor applied to the median alignment probability when proposing the Unknown floor.
+    /// Larger values send more reads to Unknown (raises its abundance estimate)
+    #[arg(long, default_value_t = 1e-12)]
+    pub unk_floor_factor: f64,
+    /// Weight of each newly proposed Unknown floor in the mini-EM update (0-1]; higher values
+    /// adapt the floor faster, lower values keep it closer to its previous value
+    #[arg(long, default_value_t = 0.2)]
+    pub unk_floor_smoothing: f64,
+    /// Only update the Unknown floor when more than this many reads align to the species set
+    #[arg(long, default_value_t = 10)]
+    pub unk_floor_min_reads: usize,
+
+    // --- STEP 4 ARGS ---
+    /// Write read assignments as one line per read-taxon pair with its posterior weight
+    /// (summing to 1 per read) instead of a single best taxon per read
+    #[arg(long, default_value_t = false)]
+    pub fractional_assignments: bool,
+    /// Add a Log10BF_Unknown column: evidence for each species against its reads
+    /// belonging to an unknown/novel organism (H0 moves them to the Unknown bin)
+    #[arg(long, default_value_t = false)]
+    pub bf_unknown: bool,
+    /// Write the cold chain's sorted species set at every recorded MCMC iteration to this file
+    #[arg(long)]
+    pub species_trace: Option<String>,
+    /// Write the per-iteration Gibbs abundances (one column per reported taxon plus Unknown) to this TSV
+    #[arg(long)]
+    pub export_abundance_history: Option<String>,
+    /// Write a posterior predictive check of per-species read counts to <output>_ppc.tsv
+    #[arg(long, default_value_t = false)]
+    pub ppc: bool,
+    /// Taxon ID of a host reference (e.g. 9606); reported as "Host" and excluded
+    /// from microbial normalization. Repeatable or comma-separated
+    #[arg(long, value_delimiter = ',')]
+    pub host_taxon: Vec<String>,
+    /// Remove reads aligning only to --host-taxon taxa, or only to taxa rejected by
+    /// --include-taxa/--exclude-taxa, from the matrix and report their counts as
+    /// HostOnly/ExcludedOnly instead of leaving them to the Unknown bin
+    #[arg(long)]
+    pub separate_host_reads: bool,
+    /// Add an "Unknown" row with the unclassified fraction's abundance and CI to the results
+    #[arg(long, default_value_t = false)]
+    pub report_unknown: bool,
+    /// Add an EstimatedReadsInt column: estimated reads rounded so they sum exactly to the read count
+    #[arg(long, default_value_t = false)]
+    pub integer_reads: bool,
+    /// Number of iterations for the final Gibbs sampler
+    #[arg(long, default_value_t = 100)]
+    pub gibbs_iter: usize,
+    /// Number of burn-in iterations for the final Gibbs sampler
+    #[arg(long, default_value_t = 20)]
+    pub gibbs_burnin: usize,
+    /// Independent final Gibbs runs (differently initialized, run in parallel) whose samples are pooled
+    #[arg(long, default_value_t = 1)]
+    pub gibbs_replicates: usize,
+    /// Credible interval method for abundances
+    #[arg(long, value_enum, default_value_t = step4_inference::CiMethod::Quantile)]
+    pub ci_method: step4_inference::CiMethod,
+    /// Label a read "Ambiguous" in the read assignments when its second-best candidate
+    /// (species or Unknown) scores within this relative distance of the best. Without it,
+    /// exact ties go to Unknown, then to the lowest matrix column
+    #[arg(long)]
+    pub tie_epsilon: Option<f64>,
+    /// Dirichlet prior concentration for each species in the Gibbs sampler
+    #[arg(long, default_value_t = 1.0)]
+    pub dirichlet_alpha: f64,
+    /// Dirichlet prior concentration for the Unknown bin (defaults to --dirichlet-alpha)
+    #[arg(long)]
+    pub unk_alpha: Option<f64>,
+}
+
+impl MetaBayesConfig {
+    /// Configuration with all defaults for the given alignment files. Fails (rather than
+    /// exiting the process, as clap's `parse_from` would) if no input is given
+    pub fn new<S: AsRef<str>>(inputs: &[S]) -> Result<Self> {
+        let mut args = vec!["metamix_rs".to_string()];
+        for input in inputs {
+            args.push("--input".to_string());
+            args.push(input.as_ref().to_string());
+        }
+        Self::try_parse_from(args).context("Invalid pipeline configuration")
+    }
+
+    /// Rejects invalid or contradictory settings before any work is done
+    pub fn validate(&self) -> Result<()> {
+        anyhow::ensure!(self.cold_chains >= 1 && self.cold_chains <= self.chains, "--cold-chains must be between 1 and --chains");
+        anyhow::ensure!(self.thin >= 1, "--thin must be at least 1");
+        anyhow::ensure!(self.gibbs_iter >= 1, "--gibbs-iter must be at least 1");
+        anyhow::ensure!(self.gibbs_replicates >= 1, "--gibbs-replicates must be at least 1");
+        anyhow::ensure!(self.bootstrap.is_none_or(|n| n >= 2), "--bootstrap needs at least 2 replicates");
+        anyhow::ensure!(self.dirichlet_alpha > 0.0, "--dirichlet-alpha must be positive");
+        anyhow::ensure!(self.unk_alpha.is_none_or(|a| a > 0.0), "--unk-alpha must be positive");
+        anyhow::ensure!(!self.paired || self.input_format == step1_parser::InputFormat::Bam, "--paired requires BAM input");
+        anyhow::ensure!(!self.list_references || self.input_format == step1_parser::InputFormat::Bam, "--list-references requires BAM input");
+        anyhow::ensure!(!self.use_md_tag || self.input_format == step1_parser::InputFormat::Bam, "--use-md-tag requires BAM input");
+        anyhow::ensure!(self.indel_rate > 0.0 && self.indel_rate < 1.0, "--indel-rate must be in (0, 1)");
+        anyhow::ensure!(self.subsample.is_none_or(|f| f > 0.0 && f <= 1.0), "--subsample must be in (0, 1]");
+        anyhow::ensure!(self.unknown_penalty_ref > 0.0 && self.unknown_penalty_ref < 1.0, "--unknown-penalty-ref must be in (0, 1)");
+        anyhow::ensure!(self.unknown_genome_len.is_none_or(|l| l > 1.0), "--unknown-genome-len must be greater than 1");
+        anyhow::ensure!(self.unk_floor_smoothing > 0.0 && self.unk_floor_smoothing <= 1.0, "--unk-floor-smoothing must be in (0, 1]");
+        anyhow::ensure!(self.init_concentration > 0.0, "--init-concentration must be positive");
+        anyhow::ensure!(self.init_warm_start.is_none_or(|w| w > 0.0 && w <= 1.0), "--init-warm-start must be in (0, 1]");
+        anyhow::ensure!(self.min_breadth.is_none() || self.coverage, "--min-breadth requires --coverage");
+        anyhow::ensure!(self.min_breadth.is_none_or(|b| (0.0..=1.0).contains(&b)), "--min-breadth must be in [0, 1]");
+        anyhow::ensure!(self.min_abundance.is_none_or(|a| (0.0..1.0).contains(&a)), "--min-abundance must be in [0, 1)");
+        anyhow::ensure!(self.tie_epsilon.is_none_or(|e| (0.0..1.0).contains(&e)), "--tie-epsilon must be in [0, 1)");
+        anyhow::ensure!(self.max_species.is_none_or(|m| m >= 1), "--max-species must be at least 1");
+        anyhow::ensure!(self.anneal_power >= 1.0, "--anneal-power must be at least 1");
+        anyhow::ensure!(self.anneal_fraction > 0.0 && self.anneal_fraction <= 1.0, "--anneal-fraction must be in (0, 1]");
+        anyhow::ensure!(self.exchange_interval >= 1, "--exchange-interval must be at least 1");
+        anyhow::ensure!(
+            !self.em_only || self.output_format == step4_inference::OutputFormat::Tsv,
+            "--em-only writes a simplified TSV results file; --output-format json is not supported with it"
+        );
+        anyhow::ensure!(!self.em_only || !self.report_unknown, "--report-unknown needs the Gibbs posterior and cannot be used with --em-only");
+        let runs_mcmc = !self.em_only && self.fixed_species.is_none();
+        anyhow::ensure!(
+            !runs_mcmc || self.exchange_interval <= self.mcmc_iter,
+            "--exchange-interval ({}) is larger than --mcmc-iter ({}); the MCMC would run no iterations",
+            self.exchange_interval, self.mcmc_iter
+        );
+        anyhow::ensure!(
+            !runs_mcmc || self.mcmc_burnin.is_none_or(|b| b < self.mcmc_iter),
+            "--mcmc-burnin must be smaller than --mcmc-iter, or no MCMC samples are left"
+        );
+        anyhow::ensure!(
+            self.gibbs_iter.div_ceil(self.thin) >= 2,
+            "--gibbs-iter ({}) with --thin ({}) keeps fewer than 2 Gibbs samples; credible intervals need more",
+            self.gibbs_iter, self.thin
+        );
+        anyhow::ensure!(self.checkpoint_every >= 1, "--checkpoint-every must be at least 1");
+        anyhow::ensure!(
+            !self.adaptive_exchange || self.resume.is_none(),
+            "--adaptive-exchange cannot be combined with --resume: the adapted exchange blocks are not checkpointed, \
+             so the resumed run would not continue the original one"
+        );
+        Ok(())
+    }
+}
+
+fn unix_secs(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0)
+}
+
+/// Wall-clock start and end of each pipeline step, in seconds since the Unix epoch,
+/// plus the time since the run started
+struct StepTimings {
+    steps: Vec<Value>,
+    run_start: Instant,
+}
+
+impl StepTimings {
+    fn new() -> Self {
+        Self { steps: Vec::new(), run_start: Instant::now() }
+    }
+
+    fn record(&mut self, step: &str, started: SystemTime) {
+        let finished = SystemTime::now();
+        let seconds = finished.duration_since(started).map(|d| d.as_secs_f64()).unwrap_or(0.0);
+        println!("Step '{}' took {:.2}s", step, seconds);
+        self.steps.push(json!({
+            "step": step,
+            "started": unix_secs(started),
+            "finished": unix_secs(finished),
+            "seconds": seconds,
+        }));
+    }
+
+    fn total_seconds(&self) -> f64 {
+        self.run_start.elapsed().as_secs_f64()
+    }
+}
+
+/// Quantities derived during the run that are recorded in the manifest
+struct RunFacts {
+    seed: u64,
+    total_reads: usize,
+    median_genome_len: f64,
+    lpenalty: f64,
+}
+
+/// Output prefix passed to `step4_inference::output_file`; with `--output-dir` it is
+/// the directory itself, so files get fixed names inside it
+fn output_prefix(config: &MetaBayesConfig) -> String {
+    if config.output_dir { format!("{}/", config.output.trim_end_matches('/')) } else { config.output.clone() }
+}
+
+/// Writes `<output>_manifest.json` (`<output>/manifest.json` with `--output-dir`): every configuration value, crate version,
+/// input file sizes and modification times, derived run facts and step timings.
+fn write_manifest(config: &MetaBayesConfig, facts: &RunFacts, timings: &StepTimings) -> Result<()> {
+    let inputs: Vec<Value> = config.input.iter()
+        .map(|path| {
+            let meta = std::fs::metadata(path).ok();
+            json!({
+                "path": path,
+                "size_bytes": meta.as_ref().map(|m| m.len()),
+                "modified": meta.and_then(|m| m.modified().ok()).map(unix_secs),
+            })
+        })
+        .collect();
+
+    let total_seconds = timings.total_seconds();
+    println!("Total runtime: {:.2}s", total_seconds);
+
+    let manifest = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "config": config,
+        "inputs": inputs,
+        "seed": facts.seed,
+        "total_reads": facts.total_reads,
+        "median_genome_len": facts.median_genome_len,
+        "lpenalty": facts.lpenalty,
+        "steps": timings.steps,
+        "total_seconds": total_seconds,
+    });
+
+    let path = step4_inference::output_file(&output_prefix(config), "manifest.json");
+    println!("Writing run manifest to: {}", path);
+    let file = BufWriter::new(File::create(&path).context("Failed to create manifest file")?);
+    serde_json::to_writer_pretty(file, &manifest)?;
+    Ok(())
+}
+
+/// Builds the initial (or resumed) chains and runs parallel tempering
+fn run_step3(
+    config: &MetaBayesConfig,
+    ctx: &Arc<step3_mcmc::McmcContext>,
+    seed: u64,
+    warm_start: Option<&HashMap<usize, f64>>,
+) -> Result<Vec<step3_mcmc::ChainState>> {
+    let (initial_states, start_iter) = if let Some(resume_path) = &config.resume {
+        let checkpoint = step3_mcmc::load_checkpoint(resume_path)?;
+        anyhow::ensure!(
+            checkpoint.chains.len() == config.chains,
+            "Checkpoint has {} chains but --chains is {}", checkpoint.chains.len(), config.chains
+        );
+        // Chain states index matrix columns, so they are only valid against the same columns
+        anyhow::ensure!(
+            checkpoint.taxons == ctx.taxons,
+            "Checkpoint {} was written for different taxa ({} columns, this run has {}); resume with the same \
+             input and include/exclude/merge settings",
+            resume_path, checkpoint.taxons.len(), ctx.taxons.len()
+        );
+        anyhow::ensure!(
+            checkpoint.iteration <= config.mcmc_iter,
+            "Checkpoint is at iteration {} but --mcmc-iter is {}", checkpoint.iteration, config.mcmc_iter
+        );
+        println!("Resuming {} chains from {} at iteration {}.", checkpoint.chains.len(), resume_path, checkpoint.iteration);
+        (checkpoint.chains, checkpoint.iteration)
+    } else {
+        let mut initial_states = Vec::with_capacity(config.chains);
+        let k_temp = config.temp_k;
+        let a_temp = config.temp_a;
+        let mut prev_temp = 1.0;
+
+        for i in 0..config.chains {
+            let temp = if i < config.cold_chains { 1.0 } else {
+                let base: f64 = prev_temp - k_temp;
+                if base < 0.0 { 0.0 } else { base.powf(a_temp) }
+            };
+            prev_temp = temp;
+
+            if config.verbose {
+                println!("Initializing Chain {} (Temp: {:.4})", i, temp);
+            }
+
+            if i == 0 && config.em_init_cold {
+                let mut state = step3_mcmc::em_solution_state(ctx, 10);
+                state.history = Vec::with_capacity(config.mcmc_iter / config.thin + 1);
+                println!("Cold chain starts from the EM solution ({} taxa).", state.species_set.len());
+                initial_states.push(state);
+                continue;
+            }
+            if i == 0 && let Some(init) = warm_start {
+                let mut state = step3_mcmc::warm_start_state(ctx, init, 10);
+                state.history = Vec::with_capacity(config.mcmc_iter / config.thin + 1);
+                println!("Cold chain starts from the previous run's community ({} taxa).", state.species_set.len());
+                initial_states.push(state);
+                continue;
+            }
+
+            let species_set = HashSet::new();
+            let abundances = HashMap::new(); 
+
+            initial_states.push(step3_mcmc::ChainState {
+                id: i,
+                temperature: temp,
+                species_set,
+                abundances,
+                current_unk_prob: config.unk_prob_min, 
+                current_log_likelihood: -1e10, 
+                moves_attempted: 0,
+                moves_accepted: 0,
+                swaps_attempted: 0,
+                swaps_accepted: 0,
+                add_moves: step3_mcmc::MoveCounter::default(),
+                remove_moves: step3_mcmc::MoveCounter::default(),
+                swap_moves: step3_mcmc::MoveCounter::default(),
+                membership_changed_at: 0,
+                history: Vec::with_capacity(config.mcmc_iter / config.thin + 1),
+            });
+        }
+
+        if !config.verbose {
+            println!("Initialized {} chains.", config.chains);
+        }
+
+        (initial_states, 0)
+    };
+
+    let run_config = step3_mcmc::McmcRunConfig {
+        start_iter,
+        total_iter: config.mcmc_iter,
+        exchange_interval: config.exchange_interval,
+        adaptive_exchange: config.adaptive_exchange,
+        autostop_window: config.mcmc_autostop.then_some(config.autostop_window),
+        checkpoint: config.checkpoint.clone().map(|path| step3_mcmc::CheckpointConfig { path, every_blocks: config.checkpoint_every }),
+        auto_temp_warmup: config.auto_temp.then_some(config.auto_temp_warmup),
+        thin: config.thin,
+        max_runtime: config.max_runtime.map(std::time::Duration::from_secs),
+        seed,
+        verbose: config.verbose,
+    };
+
+    step3_mcmc::run_mcmc_parallel(
+        ctx.clone(),
+        initial_states,
+        run_config,
+    )
+}
+
+/// Maps a file of taxon IDs (one per line) onto matrix columns. IDs that did
+/// not survive step 2 are reported and skipped.
+fn fixed_species_set(path: &str, ctx: &step3_mcmc::McmcContext) -> Result<HashSet<usize>> {
+    let wanted = step2_reduce::load_taxon_list(path)?;
+    let species_set: HashSet<usize> = ctx.taxons.iter()
+        .enumerate()
+        .filter(|(_, tid)| wanted.contains(*tid))
+        .map(|(idx, _)| idx)
+        .collect();
+    if species_set.len() < wanted.len() {
+        eprintln!(
+            "Warning: {} of {} fixed taxa are not in the reduced matrix and were skipped.",
+            wanted.len() - species_set.len(), wanted.len()
+        );
+    }
+    anyhow::ensure!(!species_set.is_empty(), "None of the taxa in {} are present after step 2", path);
+    Ok(species_set)
+}
+
+/// Cold-chain abundances of a previous run's checkpoint, keyed by this run's matrix
+/// columns and renormalized. Taxa absent from the reduced matrix are skipped; returns
+/// `None` (with a warning) if none remain.
+fn previous_community(path: &str, ctx: &step3_mcmc::McmcContext) -> Result<Option<HashMap<usize, f64>>> {
+    let checkpoint = step3_mcmc::load_checkpoint(path)?;
+    anyhow::ensure!(
+        !checkpoint.taxons.is_empty(),
+        "Checkpoint {} does not record taxon IDs (written by an older version); it can only be used with --resume", path
+    );
+    let cold = step3_mcmc::cold_chain(&checkpoint.chains).with_context(|| format!("Checkpoint {} has no chains", path))?;
+    let columns: HashMap<&str, usize> = ctx.taxons.iter().enumerate().map(|(idx, tid)| (tid.as_str(), idx)).collect();
+    let mut community: HashMap<usize, f64> = cold.abundances.iter()
+        .filter_map(|(&old, &abund)| {
+            let tid = checkpoint.taxons.get(old)?;
+            columns.get(tid.as_str()).map(|&col| (col, abund))
+        })
+        .collect();
+    if community.len() < cold.abundances.len() {
+        eprintln!(
+            "Warning: {} of {} taxa from {} are not in the reduced matrix and were skipped.",
+            cold.abundances.len() - community.len(), cold.abundances.len(), path
+        );
+    }
+    let total: f64 = community.values().sum();
+    if community.is_empty() || total <= 0.0 {
+        eprintln!("Warning: no taxa from {} are usable; starting without a warm start.", path);
+        return Ok(None);
+    }
+    for v in community.values_mut() { *v /= total; }
+    println!("Warm start from {}: {} taxa from the previous run's cold chain.", path, community.len());
+    Ok(Some(community))
+}
+
+/// Reads `<taxon ID>\t<log prior>` lines and keys them by matrix column.
+/// Taxa not in the reduced matrix are skipped.
+fn presence_prior(path: &str, ctx: &step3_mcmc::McmcContext) -> Result<HashMap<usize, f64>> {
+    let file = File::open(path).with_context(|| format!("Failed to open presence prior: {}", path))?;
+    let columns: HashMap<&str, usize> = ctx.taxons.iter().enumerate().map(|(idx, tid)| (tid.as_str(), idx)).collect();
+    let mut prior = HashMap::new();
+    let mut skipped = 0;
+    for (line_no, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') { continue; }
+        let Some((tid, value)) = line.split_once('\t') else {
+            anyhow::bail!("{}:{}: expected <taxon ID>\t<log prior>", path, line_no + 1);
+        };
+        let (tid, value) = (tid.trim(), value.trim());
+        let value: f64 = value.parse()
+            .with_context(|| format!("{}:{}: invalid log prior '{}'", path, line_no + 1, value))?;
+        match columns.get(tid) {
+            Some(&idx) => { prior.insert(idx, value); }
+            None => skipped += 1,
+        }
+    }
+    println!("Loaded presence prior for {} taxa ({} not in the reduced matrix).", prior.len(), skipped);
+    Ok(prior)
+}
+
+/// Reference Unknown probability for the penalty: 1/--unknown-genome-len when set
+fn unknown_penalty_ref(config: &MetaBayesConfig) -> f64 {
+    config.unknown_genome_len.map(|len| 1.0 / len).unwrap_or(config.unknown_penalty_ref)
+}
+
+/// Steps 1-2: parses the alignments and reduces the taxa by EM
+fn parse_and_reduce(config: &MetaBayesConfig, seed: u64, timings: &mut StepTimings) -> Result<cache::ParsedInput> {
+    println!("\n=== STEP 1: ALIGNMENT PARSING ===");
+    let step_start = SystemTime::now();
+    let parse_options = step1_parser::ParseOptions {
+        min_ref_len: config.min_ref_len,
+        median_aligned_only: config.median_aligned_only,
+        coverage: config.coverage,
+        min_align_len: config.min_align_len,
+        assume_coord_sorted: config.assume_coord_sorted,
+        error_model: config.error_model,
+        include_taxa: config.include_taxa.as_deref().map(step2_reduce::load_taxon_list).transpose()?,
+        exclude_taxa: match &config.exclude_taxa {
+            Some(path) => step2_reduce::load_taxon_list(path)?,
+            None => HashSet::new(),
+        },
+        subsample: config.subsample,
+        subsample_seed: seed,
+        acc2taxid: config.acc2taxid.clone(),
+        paired: config.paired,
+        spill_dir: config.spill_dir.clone(),
+        strict: config.strict,
+        use_md_tag: config.use_md_tag,
+        indel_rate: config.indel_rate,
+        verbose: config.verbose,
+        genome_length_overrides: match &config.genome_lengths {
+            Some(path) => step1_parser::load_genome_lengths(path)?,
+            None => HashMap::new(),
+        },
+    };
+    let (entries, num_reads, mut parser, read_names, detected_median_len) = match config.input_format {
+        step1_parser::InputFormat::Bam => step1_parser::process_bams(&config.input, &parse_options)?,
+        step1_parser::InputFormat::Paf => step1_parser::process_pafs(&config.input, &parse_options)?,
+        step1_parser::InputFormat::M8 => step1_parser::process_blast(&config.input, &parse_options)?,
+    };
+
+    let coverage_breadth = config.coverage.then(|| std::mem::take(&mut parser.coverage_breadth));
+    timings.record("parse", step_start);
+
+    println!("\n=== STEP 2: EM DIMENSION REDUCTION ===");
+    let step_start = SystemTime::now();
+    
+    let step2_result = step2_reduce::run_em_reduction(
+        entries, 
+        num_reads, 
+        parser, 
+        &step2_reduce::EmConfig {
+            read_cutoff: config.em_read_cutoff,
+            pre_filter: !config.no_pre_filter,
+            iterations: config.em_iter,
+            accelerate: config.em_accelerate,
+            drop_empty_reads: config.drop_empty_reads,
+            merge_identical: config.merge_identical_taxa,
+            separate_host: config.separate_host_reads.then(|| config.host_taxon.iter().cloned().collect()),
+            tol_type: config.em_tol_type,
+            verbose: config.verbose,
+        },
+    )?;
+    timings.record("em_reduction", step_start);
+
+    Ok(cache::ParsedInput { num_reads, read_names, detected_median_len, coverage_breadth, step2: step2_result })
+}
+
+/// Runs all four steps and returns the in-memory inference result.
+/// Output files are still written under `config.output` (see `output_prefix`).
+/// With `dry_run` set, stops after building the MCMC context and returns an empty result.
+pub fn run_pipeline(config: &MetaBayesConfig) -> Result<InferenceResult> {
+    config.validate()?;
+
+    if config.list_references {
+        step1_parser::list_references(&config.input)?;
+        return Ok(InferenceResult::default());
+    }
+
+    let seed = config.seed.unwrap_or_else(rand::random);
+
+    if config.output_dir {
+        std::fs::create_dir_all(&config.output)
+            .with_context(|| format!("Failed to create output directory {}", config.output))?;
+    }
+
+    if let Some(t) = config.threads {
+        // The global pool can only be built once per process (e.g. repeated library calls)
+        if let Err(e) = rayon::ThreadPoolBuilder::new()
+            .num_threads(t)
+            .build_global()
+        {
+            eprintln!("Warning: Could not configure thread pool: {}", e);
+        }
+    }
+
+    let mut timings = StepTimings::new();
+
+    let input = match &config.cache_dir {
+        Some(dir) => {
+            let key = cache::cache_key(config, seed)?;
+            let path = cache::cache_path(dir, &key);
+            let step_start = SystemTime::now();
+            match cache::load(&path, &key)? {
+                Some(input) => {
+                    println!("\nLoaded parsed input from cache: {} (skipping steps 1-2)", path);
+                    timings.record("cache_load", step_start);
+                    input
+                }
+                None => {
+                    let input = parse_and_reduce(config, seed, &mut timings)?;
+                    cache::save(&path, &key, &input)?;
+                    input
+                }
+            }
+        }
+        None => parse_and_reduce(config, seed, &mut timings)?,
+    };
+    let cache::ParsedInput { num_reads, read_names, detected_median_len, coverage_breadth, step2: step2_result } = input;
+    let final_median_len = config.median_genome_len.unwrap_or(detected_median_len);
+
+    println!("\n=== STEP 3: MCMC PARALLEL TEMPERING ===");
+    let step_start = SystemTime::now();
+
+    // Keep read names and weights aligned with the (possibly row-filtered) matrix
+    let read_names = match &step2_result.kept_reads {
+        Some(kept) => kept.iter().map(|&r| read_names[r].clone()).collect(),
+        None => read_names,
+    };
+    if let Some(path) = &config.export_matrix_mtx {
+        step2_reduce::export_matrix_mtx(path, &step2_result.matrix, &read_names, &step2_result.reduced_taxons)?;
+    }
+    if config.report_support {
+        let path = step4_inference::output_file(&output_prefix(config), "support.tsv");
+        step2_reduce::export_column_support(&path, &step2_result, num_reads, config.em_read_cutoff)?;
+    }
+    // Each subsampled read stands in for 1/fraction reads of the full dataset
+    let read_weights = vec![1.0 / config.subsample.unwrap_or(1.0); step2_result.matrix.nrows()];
+
+    let unk_config = step3_mcmc::UnknownBinConfig {
+        prob_min: config.unk_prob_min,
+        prob_max: config.unk_prob_max,
+        floor_factor: config.unk_floor_factor,
+        smoothing: config.unk_floor_smoothing,
+        min_reads: config.unk_floor_min_reads,
+    };
+
+    let mut ctx = step3_mcmc::McmcContext::new(
+        &step2_result.matrix,
+        read_weights,
+        step2_result.reduced_taxons.clone(),    
+        step2_result.reduced_abundances.clone(),
+        step2_result.reduced_genome_lengths.clone(),
+        step3_mcmc::PenaltyConfig {
+            median_genome_len: final_median_len,
+            read_support: config.read_support,
+            p_unknown_penalty_ref: unknown_penalty_ref(config),
+        },
+        unk_config,
+    );
+    ctx.proposal = step3_mcmc::ProposalInit {
+        concentration: config.init_concentration,
+        warm_start: config.init_warm_start,
+    };
+    if let Some(path) = &config.presence_prior {
+        ctx.presence_prior = presence_prior(path, &ctx)?;
+    }
+    if config.anneal_proposals {
+        ctx.anneal = Some(step3_mcmc::ProposalAnneal {
+            power: config.anneal_power,
+            until_iter: (config.mcmc_iter as f64 * config.anneal_fraction).ceil() as usize,
+        });
+    }
+    ctx.max_species = config.max_species;
+    if config.adaptive_penalty {
+        ctx.adaptive_support = Some(step2_result.column_support.clone());
+    }
+    let warm_start = match &config.warm_start_from {
+        Some(path) => previous_community(path, &ctx)?,
+        None => None,
+    };
+    if let Some(previous) = &warm_start {
+        let total: f64 = ctx.taxon_weights.iter().sum();
+        ctx.add_weights = Some((0..ctx.taxons.len())
+            .map(|sp| {
+                let em = if total > 0.0 { ctx.taxon_weights[sp] / total } else { 0.0 };
+                0.5 * em + 0.5 * previous.get(&sp).copied().unwrap_or(0.0)
+            })
+            .collect());
+    }
+    let ctx = Arc::new(ctx);
+
+    let facts = RunFacts {
+        seed,
+        total_reads: num_reads,
+        median_genome_len: final_median_len,
+        lpenalty: ctx.lpenalty,
+    };
+
+    if config.dry_run {
+        println!("\n=== DRY RUN SUMMARY ===");
+        println!("Matrix dimensions: {} reads x {} taxa", ctx.matrix.nrows(), ctx.matrix.ncols());
+        println!("Retained taxa: {}", ctx.taxons.len());
+        println!("Median genome length: {:.0}", final_median_len);
+        println!("L-Penalty: {:.4}", ctx.lpenalty);
+        println!("Dry run: stopping before MCMC.");
+        write_manifest(config, &facts, &timings)?;
+        return Ok(InferenceResult::default());
+    }
+
+    let report_config = step4_inference::ReportConfig {
+        output_prefix: output_prefix(config),
+        output_format: config.output_format,
+        sort_by: config.sort_by,
+        output_posterior: config.output_posterior,
+        unknown_reads_path: config.unknown_reads.clone(),
+        posterior_predictive: config.ppc,
+        report_unknown: config.report_unknown,
+        integer_reads: config.integer_reads,
+        host_taxa: config.host_taxon.iter().cloned().collect(),
+        compress: config.compress_output,
+        merged_taxa: step2_result.merged_taxa.clone(),
+        species_trace_path: config.species_trace.clone(),
+        abundance_history_path: config.export_abundance_history.clone(),
+        bf_unknown: config.bf_unknown,
+        fractional_assignments: config.fractional_assignments,
+        min_breadth: config.min_breadth,
+        min_abundance: config.min_abundance,
+        separated_reads: step2_result.separated_reads,
+        ess_adjusted_ci: config.ess_adjusted_ci,
+        bootstrap: config.bootstrap,
+    };
+
+    if config.em_only {
+        let taxonomy_map = config.taxonomy_names.as_deref().map(step4_inference::load_taxonomy_names).transpose()?;
+        let result = step4_inference::run_em_only(&ctx, taxonomy_map.as_ref(), &read_names, &report_config)?;
+        timings.record("em_only", step_start);
+        write_manifest(config, &facts, &timings)?;
+        return Ok(result);
+    }
+
+    let final_chains = match &config.fixed_species {
+        Some(path) => {
+            let species_set = fixed_species_set(path, &ctx)?;
+            println!("Using fixed species set of {} taxa from {}; skipping MCMC.", species_set.len(), path);
+            vec![step3_mcmc::fixed_species_state(&ctx, species_set, config.em_iter)]
+        }
+        None => run_step3(config, &ctx, seed, warm_start.as_ref())?,
+    };
+
+    println!("Step 3 Completed.");
+    timings.record("mcmc", step_start);
+
+    println!("\n=== STEP 4: INFERENCE ===");
+    let step_start = SystemTime::now();
+    let taxonomy_map = if let Some(path) = &config.taxonomy_names {
+        Some(step4_inference::load_taxonomy_names(path)?)
+    } else {
+        None
+    };
+
+    let mcmc_burnin = match config.mcmc_burnin {
+        Some(iterations) => step4_inference::McmcBurnin::Iterations(iterations),
+        None => step4_inference::McmcBurnin::Ratio(0.1),
+    };
+    let gibbs_config = step4_inference::GibbsConfig {
+        iterations: config.gibbs_iter,
+        burnin: config.gibbs_burnin,
+        alpha: config.dirichlet_alpha,
+        unk_alpha: config.unk_alpha.unwrap_or(config.dirichlet_alpha),
+        thin: config.thin,
+        ci_method: config.ci_method,
+        tie_epsilon: config.tie_epsilon,
+        replicates: config.gibbs_replicates,
+    };
+
+    // Every configuration value, with defaults resolved where the run derived them
+    let mut parameters = serde_json::to_value(config)?;
+    parameters["median_genome_len"] = json!(final_median_len);
+    parameters["unk_alpha"] = json!(gibbs_config.unk_alpha);
+    parameters["unknown_penalty_ref"] = json!(unknown_penalty_ref(config));
+
+    let run_metadata = json!({
+        "input": config.input,
+        "total_reads": num_reads,
+        "version": env!("CARGO_PKG_VERSION"),
+        "seed": seed,
+        "parameters": parameters,
+    });
+
+    let result = step4_inference::run_inference(
+        &ctx,
+        &final_chains,
+        mcmc_burnin,
+        step4_inference::InferenceInputs {
+            taxonomy_map: taxonomy_map.as_ref(),
+            read_names: &read_names,
+            coverage_breadth: coverage_breadth.as_ref(),
+            run_metadata: &run_metadata,
+        },
+        gibbs_config,
+        &report_config,
+        seed,
+    )?;
+
+    if let Some(nodes_path) = &config.report_all_ranks {
+        let nodes = step4_inference::load_taxonomy_nodes(nodes_path)?;
+        let ranks_path = step4_inference::output_file(&output_prefix(config), "ranks.tsv");
+        step4_inference::export_rank_profiles(&ranks_path, &result.taxa, &nodes, taxonomy_map.as_ref())?;
+    }
+    timings.record("inference", step_start);
+
+    write_manifest(config, &facts, &timings)?;
+
+    Ok(result)
+}
\ No newline at end of file
@@ -0,0 +1,64 @@
+//! Python bindings (feature `python`). Exposes `metamix_rs.run_pipeline(config)`,
+//! where `config` maps command-line option names (underscores or dashes) to values,
+//! e.g. `{"input": ["a.bam"], "chains": 8, "coverage": True}`.
+
+use clap::Parser;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyList, PyTuple};
+
+use crate::{run_pipeline as run_pipeline_rs, MetaBayesConfig};
+
+/// Turns a config dict into command-line arguments: `True` becomes a bare flag
+/// (`False` is dropped), lists and tuples repeat the flag, anything else is passed via `str()`.
+fn config_args(config: &Bound<'_, PyDict>) -> PyResult<Vec<String>> {
+    let mut args = vec!["metamix_rs".to_string()];
+    for (key, value) in config.iter() {
+        let flag = format!("--{}", key.extract::<String>()?.replace('_', "-"));
+        if value.is_instance_of::<PyBool>() {
+            if value.extract::<bool>()? { args.push(flag); }
+        } else if value.is_instance_of::<PyList>() || value.is_instance_of::<PyTuple>() {
+            for item in value.try_iter()? {
+                args.push(flag.clone());
+                args.push(item?.str()?.to_string());
+            }
+        } else if !value.is_none() {
+            args.push(flag);
+            args.push(value.str()?.to_string());
+        }
+    }
+    Ok(args)
+}
+
+/// Runs the full pipeline and returns the reported taxa as parallel lists
+/// (`taxon_id`, `mean_abundance`, `ci_lower`, `ci_upper`, `log10_bf`,
+/// `posterior_inclusion`), ready for `pandas.DataFrame(result["taxa"])`,
+/// plus the learned Unknown floor and Unknown-bin abundance.
+/// Output files are still written under the configured prefix.
+#[pyfunction]
+fn run_pipeline<'py>(py: Python<'py>, config: &Bound<'py, PyDict>) -> PyResult<Bound<'py, PyDict>> {
+    let config = MetaBayesConfig::try_parse_from(config_args(config)?)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let result = py.allow_threads(|| run_pipeline_rs(&config))
+        .map_err(|e| PyValueError::new_err(format!("{:#}", e)))?;
+
+    let taxa = PyDict::new(py);
+    taxa.set_item("taxon_id", result.taxa.iter().map(|t| t.taxon_id.clone()).collect::<Vec<_>>())?;
+    taxa.set_item("mean_abundance", result.taxa.iter().map(|t| t.stats.mean).collect::<Vec<_>>())?;
+    taxa.set_item("ci_lower", result.taxa.iter().map(|t| t.stats.ci_low).collect::<Vec<_>>())?;
+    taxa.set_item("ci_upper", result.taxa.iter().map(|t| t.stats.ci_high).collect::<Vec<_>>())?;
+    taxa.set_item("log10_bf", result.taxa.iter().map(|t| t.log10_bf).collect::<Vec<_>>())?;
+    taxa.set_item("posterior_inclusion", result.taxa.iter().map(|t| t.posterior_inclusion).collect::<Vec<_>>())?;
+
+    let out = PyDict::new(py);
+    out.set_item("taxa", taxa)?;
+    out.set_item("unknown_floor", result.unknown_floor)?;
+    out.set_item("unknown_abundance", result.unknown.mean)?;
+    Ok(out)
+}
+
+#[pymodule]
+fn metamix_rs(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(run_pipeline, m)?)?;
+    Ok(())
+}
@@ -1,14 +1,87 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use indicatif::{ProgressBar, ProgressStyle};
 use noodles::bam;
 use noodles::sam::alignment::record::data::field::Tag;
 use noodles::sam::alignment::record::cigar::op::Kind; 
+use noodles::sam::header::record::value::map::header::{sort_order, tag};
+use statrs::function::beta::beta_reg;
 use statrs::function::gamma::{gamma_lr, ln_gamma}; 
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader};
 use std::path::Path;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use rayon::prelude::*;
 
-use crate::step2_reduce::{MatrixEntry, TaxonomyParser};
+use crate::step2_reduce::{load_acc2taxid, MatrixEntries, MatrixEntry, TaxonomyParser, UNMAPPED_TAXON};
+
+/// (matrix entries, number of reads, taxonomy parser, read names, median genome length)
+pub type ParseOutput = (MatrixEntries, usize, TaxonomyParser, Vec<String>, f64);
+
+/// Options controlling alignment parsing
+#[derive(Clone, Debug, Default)]
+pub struct ParseOptions {
+    /// References shorter than this are ignored when computing the median genome length
+    pub min_ref_len: u64,
+    /// Compute the median only over references that received at least one alignment
+    pub median_aligned_only: bool,
+    /// Record aligned reference intervals and compute per-taxon breadth of coverage
+    pub coverage: bool,
+    /// True genome lengths keyed by reference name or taxon ID, used instead of header lengths
+    pub genome_length_overrides: HashMap<String, u64>,
+    /// Skip alignments covering fewer aligned read bases than this (clips excluded)
+    pub min_align_len: u64,
+    /// Accept BAMs whose records for one read are not contiguous (e.g. coordinate-sorted)
+    pub assume_coord_sorted: bool,
+    pub error_model: ErrorModel,
+    /// If set, only alignments to these taxon IDs / reference names are kept
+    pub include_taxa: Option<HashSet<String>>,
+    /// Alignments to these taxon IDs / reference names are dropped
+    pub exclude_taxa: HashSet<String>,
+    /// If set, keep each read with this probability (decided from its name, so
+    /// all of a read's alignments are kept or dropped together)
+    pub subsample: Option<f64>,
+    /// Seed for the subsampling decision
+    pub subsample_seed: u64,
+    /// Accession -> taxon ID mapping file; reference names are treated as accessions
+    pub acc2taxid: Option<String>,
+    /// Score both mates of a pair jointly as one read (name-sorted BAM only)
+    pub paired: bool,
+    /// Spill matrix entries to a temporary file in this directory instead of keeping them in memory
+    pub spill_dir: Option<String>,
+    /// Fail on reads left without any scored alignment (other than those rejected by the
+    /// include/exclude lists) instead of keeping them as all-Unknown matrix rows
+    pub strict: bool,
+    /// List the names of such reads
+    pub verbose: bool,
+    /// Score substitutions (from the MD tag) and indels (from the CIGAR) separately;
+    /// alignments without MD fall back to NM
+    pub use_md_tag: bool,
+    /// Per-base indel rate used for the indel term when `use_md_tag` is set
+    pub indel_rate: f64,
+}
+
+/// Alignment input format
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputFormat {
+    Bam,
+    Paf,
+    /// BLAST tabular (`-outfmt 6`)
+    M8,
+}
+
+/// Byte-based progress bar over an input file (hidden automatically when stderr is not a TTY)
+fn file_progress_bar(file: &File, label: &str) -> ProgressBar {
+    let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+    let pb = ProgressBar::new(len);
+    pb.set_style(
+        ProgressStyle::with_template("{msg} [{bar:40}] {bytes}/{total_bytes} ({eta})")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    pb.set_message(label.to_string());
+    pb
+}
 
 fn calculate_total_lambda(scores: &[u8]) -> f64 {
     let mut lambda = 0.0;
@@ -40,42 +113,781 @@ fn calculate_r_score(k: u32, lambda: f64, genome_len: u64) -> f64 {
         log_poisson - (genome_len as f64).ln()
     };
 
-    if log_pij < -700.0 { -700.0 } else { log_pij }
+    if log_pij < LOG_PROB_FLOOR { LOG_PROB_FLOOR } else { log_pij }
 }
 
-pub fn process_bam<P: AsRef<Path>>(path: P) -> Result<(Vec<MatrixEntry>, usize, TaxonomyParser, Vec<String>, f64)> {
-    
-    let file = File::open(path).context("Failed to open BAM file")?;
-    let mut reader = bam::io::Reader::new(BufReader::new(file));
-    let header = reader.read_header().context("Failed to read BAM header")?;
-    let references = header.reference_sequences();
+/// Genome length used when none is known (no usable references, or BLAST subjects without an override)
+const DEFAULT_GENOME_LEN: f64 = 284332.0;
 
-    println!("--- Parsing BAM (Assuming Name-Sorted) ---");
+/// Lowest per-alignment log-probability written to the matrix
+const LOG_PROB_FLOOR: f64 = -700.0;
 
-    // --- 1. Calculate Median Genome Length ---
-    let mut ref_lengths: Vec<u64> = references
-        .values()
-        .map(|rs| rs.length().get() as u64) // Explicit get() and cast
-        .collect();
-    
-    let median_len = if ref_lengths.is_empty() {
-        eprintln!("Warning: No reference sequences found in BAM header. Using default 284332.0");
-        284332.0 
+/// Per-base error rate assumed when a model is given without one
+const DEFAULT_ERROR_RATE: f64 = 0.03;
+
+/// Fraction of BAM alignments without NM or =/X CIGARs above which the parse warns
+/// that mismatches are effectively unknown
+const MISSING_NM_WARN_FRACTION: f64 = 0.5;
+
+/// How the probability of observing `k` mismatches in an alignment is scored.
+/// Parsed from `phred-poisson`, `fixed-rate[:<rate>]` or `binomial[:<rate>]`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize)]
+pub enum ErrorModel {
+    /// Poisson tail with the expected error count summed from base qualities
+    /// (falls back to a 0.03 per-base rate when qualities are missing). Suits Illumina.
+    #[default]
+    PhredPoisson,
+    /// Poisson tail with a fixed per-base error rate, ignoring base qualities
+    FixedRate(f64),
+    /// Binomial tail over the aligned read length with a fixed per-base error
+    /// rate. Better behaved than Poisson for high-error long reads (ONT, PacBio CLR)
+    Binomial(f64),
+}
+
+impl std::str::FromStr for ErrorModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rate) = match s.split_once(':') {
+            Some((name, rate)) => {
+                let rate: f64 = rate.parse().map_err(|_| format!("invalid error rate '{}'", rate))?;
+                if !(rate > 0.0 && rate < 1.0) {
+                    return Err(format!("error rate must be in (0, 1), got {}", rate));
+                }
+                (name, rate)
+            }
+            None => (s, DEFAULT_ERROR_RATE),
+        };
+        match name {
+            "phred-poisson" => Ok(ErrorModel::PhredPoisson),
+            "fixed-rate" => Ok(ErrorModel::FixedRate(rate)),
+            "binomial" => Ok(ErrorModel::Binomial(rate)),
+            _ => Err(format!("unknown error model '{}' (expected phred-poisson, fixed-rate[:rate] or binomial[:rate])", name)),
+        }
+    }
+}
+
+impl ErrorModel {
+    /// Log-probability of an alignment with `k` mismatches over `read_len` bases
+    /// against a genome of `genome_len`. `phred_lambda` is the expected error count
+    /// from base qualities (or the format's fallback) and is only used by `PhredPoisson`.
+    fn log_prob(&self, k: u32, phred_lambda: f64, read_len: u64, genome_len: u64) -> f64 {
+        let len = read_len as f64;
+        match *self {
+            ErrorModel::PhredPoisson => calculate_r_score(k, phred_lambda.max(0.01 * len), genome_len),
+            ErrorModel::FixedRate(rate) => calculate_r_score(k, rate * len, genome_len),
+            ErrorModel::Binomial(rate) => calculate_binomial_score(k, read_len, rate, genome_len),
+        }
+    }
+
+    /// Like `log_prob`, with substitutions scored by this model and indels by an
+    /// independent Poisson tail with a per-base `indel_rate`
+    fn log_prob_split(&self, subs: u32, indels: u32, phred_lambda: f64, read_len: u64, genome_len: u64, indel_rate: f64) -> f64 {
+        let subs_term = self.log_prob(subs, phred_lambda, read_len, genome_len);
+        // A genome length of 1 leaves just the log tail probability
+        let indel_term = calculate_r_score(indels, indel_rate * read_len as f64, 1);
+        (subs_term + indel_term).max(LOG_PROB_FLOOR)
+    }
+}
+
+/// Binomial analogue of `calculate_r_score`: log(P(X >= k) / G) with X ~ Bin(n, p)
+fn calculate_binomial_score(k: u32, n: u64, p: f64, genome_len: u64) -> f64 {
+    if k == 0 || n == 0 {
+        return -(genome_len as f64).ln();
+    }
+
+    let n_val = n as f64;
+    let k_val = (k as f64).min(n_val);
+
+    // P(X >= k) = I_p(k, n - k + 1)
+    let prob_tail = beta_reg(k_val, n_val - k_val + 1.0, p);
+
+    let log_pij = if prob_tail > 0.0 {
+        (prob_tail / (genome_len as f64)).ln()
     } else {
-        ref_lengths.sort_unstable();
-        let mid = ref_lengths.len() / 2;
-        if ref_lengths.len() % 2 == 0 {
-            (ref_lengths[mid - 1] + ref_lengths[mid]) as f64 / 2.0
+        // Log-Space Approximation (point mass at k)
+        let log_binom = ln_gamma(n_val + 1.0) - ln_gamma(k_val + 1.0) - ln_gamma(n_val - k_val + 1.0)
+            + k_val * p.ln() + (n_val - k_val) * (1.0 - p).ln();
+        log_binom - (genome_len as f64).ln()
+    };
+
+    if log_pij < LOG_PROB_FLOOR { LOG_PROB_FLOOR } else { log_pij }
+}
+
+/// Number of bases covered by the union of `[start, end)` intervals (sorts them in place)
+fn covered_bases(intervals: &mut [(u64, u64)]) -> u64 {
+    intervals.sort_unstable();
+    let Some(&(mut cur_start, mut cur_end)) = intervals.first() else { return 0 };
+    let mut bases = 0;
+    for &(start, end) in &intervals[1..] {
+        if start > cur_end {
+            bases += cur_end - cur_start;
+            cur_start = start;
+        }
+        cur_end = cur_end.max(end);
+    }
+    bases + (cur_end - cur_start)
+}
+
+/// Accumulates matrix entries and read/reference bookkeeping across input files
+struct ParseState {
+    entries: MatrixEntries,
+    parser: TaxonomyParser,
+    index_to_read_name: Vec<String>,
+    read_name_to_index: HashMap<Vec<u8>, usize>,
+    ref_lengths: HashMap<String, u64>,
+    aligned_refs: HashSet<String>,
+    /// Aligned reference intervals `[start, end)` per reference, only filled when tracking coverage
+    ref_intervals: HashMap<String, Vec<(u64, u64)>>,
+    track_coverage: bool,
+    length_overrides: HashMap<String, u64>,
+    min_align_len: u64,
+    /// Alignments skipped by the minimum alignment length filter
+    short_alignments: usize,
+    assume_coord_sorted: bool,
+    /// Per read row: (lambda, read length) from the record carrying qualities.
+    /// Only kept for coordinate-sorted input, where a read's records are scattered.
+    read_lambdas: HashMap<usize, (f64, u64)>,
+    error_model: ErrorModel,
+    /// Scored BAM alignments, and those without NM or =/X CIGARs (mismatches taken as 0)
+    scored_alignments: usize,
+    missing_nm: usize,
+    use_md_tag: bool,
+    indel_rate: f64,
+    /// Scored alignments without an MD tag when `use_md_tag` is set
+    missing_md: usize,
+    subsample: Option<f64>,
+    subsample_seed: u64,
+    paired: bool,
+    /// Name hashes of the reads dropped by subsampling, so a read whose alignments are
+    /// scattered (coordinate-sorted input) is still counted once
+    subsampled_out: HashSet<u64>,
+    /// Rows with at least one alignment rejected by the include/exclude lists
+    excluded_rows: HashSet<usize>,
+}
+
+impl ParseState {
+    /// `references`, when known up front (BAM headers), limits the acc2taxid mappings kept
+    fn new(options: &ParseOptions, references: Option<&HashSet<String>>) -> Result<Self> {
+        let mut parser = TaxonomyParser::new()?;
+        parser.include = options.include_taxa.clone();
+        parser.exclude = options.exclude_taxa.clone();
+        parser.acc2taxid = options.acc2taxid.as_deref()
+            .map(|path| load_acc2taxid(path, references))
+            .transpose()?;
+        Ok(Self {
+            entries: match &options.spill_dir {
+                Some(dir) => MatrixEntries::spilled(dir)?,
+                None => MatrixEntries::in_memory(),
+            },
+            parser,
+            index_to_read_name: Vec::new(),
+            read_name_to_index: HashMap::new(),
+            ref_lengths: HashMap::new(),
+            aligned_refs: HashSet::new(),
+            ref_intervals: HashMap::new(),
+            track_coverage: options.coverage,
+            length_overrides: options.genome_length_overrides.clone(),
+            min_align_len: options.min_align_len,
+            short_alignments: 0,
+            assume_coord_sorted: options.assume_coord_sorted,
+            read_lambdas: HashMap::new(),
+            error_model: options.error_model,
+            scored_alignments: 0,
+            missing_nm: 0,
+            use_md_tag: options.use_md_tag,
+            indel_rate: options.indel_rate,
+            missing_md: 0,
+            subsample: options.subsample,
+            subsample_seed: options.subsample_seed,
+            paired: options.paired,
+            subsampled_out: HashSet::new(),
+            excluded_rows: HashSet::new(),
+        })
+    }
+
+    /// Whether subsampling keeps this read. The decision is a seeded hash of the
+    /// name, so it is the same for every record and input file of a read.
+    fn keep_read(&mut self, name: &[u8]) -> bool {
+        let Some(fraction) = self.subsample else { return true };
+        // FNV-1a over the name, then a splitmix64 finaliser mixed with the seed
+        let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+        for &b in name {
+            h = (h ^ b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+        h ^= self.subsample_seed.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        h ^= h >> 31;
+        let unit = (h >> 11) as f64 / (1u64 << 53) as f64;
+        let keep = unit < fraction;
+        if !keep {
+            self.subsampled_out.insert(h);
+        }
+        keep
+    }
+
+    /// Global row index for a read name. IDs are shared across input files,
+    /// so the same read in another file maps back onto the same row.
+    fn read_row(&mut self, name: &[u8]) -> usize {
+        if let Some(&idx) = self.read_name_to_index.get(name) {
+            return idx;
+        }
+        // Assign new ID and store name for output (Step 4 needs names)
+        let idx = self.index_to_read_name.len();
+        self.read_name_to_index.insert(name.to_vec(), idx);
+        self.index_to_read_name.push(String::from_utf8_lossy(name).to_string());
+        idx
+    }
+
+    /// Length used for scoring a reference: an override for the reference name,
+    /// then for its taxon ID, then the length from the alignment file
+    fn reference_length(&self, name: &str, file_len: u64) -> u64 {
+        self.length_overrides.get(name)
+            .or_else(|| self.parser.taxon_id(name).and_then(|tid| self.length_overrides.get(tid)))
+            .copied()
+            .unwrap_or(file_len)
+    }
+
+    fn add_interval(&mut self, ref_name: &str, start: u64, end: u64) {
+        if self.track_coverage && end > start {
+            self.ref_intervals.entry(ref_name.to_string()).or_default().push((start, end));
+        }
+    }
+
+    /// Fraction of each taxon's total reference length covered by at least one alignment.
+    /// Interval lists are collapsed per reference in parallel; only the per-taxon sums are serial.
+    fn coverage_breadth(&mut self) -> HashMap<String, f64> {
+        let per_ref: Vec<(&String, u64)> = self.ref_intervals.par_iter_mut()
+            .map(|(name, intervals)| (name, covered_bases(intervals)))
+            .collect();
+
+        let mut covered: HashMap<String, u64> = HashMap::new();
+        for (name, bases) in per_ref {
+            if let Some(tid) = self.parser.taxon_id(name) {
+                *covered.entry(tid.to_string()).or_insert(0) += bases;
+            }
+        }
+
+        covered.into_iter()
+            .filter_map(|(tid, bases)| {
+                let len = self.parser.genome_lengths.get(&tid).copied().filter(|&l| l > 0)?;
+                Some((tid, (bases as f64 / len as f64).min(1.0)))
+            })
+            .collect()
+    }
+
+    fn finish(mut self, num_files: usize, options: &ParseOptions) -> Result<ParseOutput> {
+        // --- Merge: keep best score per read-taxon pair ---
+        // A read seen in several files (or in non-contiguous blocks) is flushed more
+        // than once; the sparse matrix would otherwise sum the duplicate entries.
+        // Spilled entries are merged in step 2 while the matrix is built.
+        let before = self.entries.len();
+        if let Some(entries) = self.entries.in_memory_mut() {
+            entries.sort_unstable_by(|a, b| {
+                (a.read_idx, a.taxon_idx).cmp(&(b.read_idx, b.taxon_idx))
+                    .then(b.log_prob.partial_cmp(&a.log_prob).unwrap_or(std::cmp::Ordering::Equal))
+            });
+            entries.dedup_by(|later, first| later.read_idx == first.read_idx && later.taxon_idx == first.taxon_idx);
+        }
+        if self.min_align_len > 0 {
+            println!("Dropped {} alignments shorter than {} bp.", self.short_alignments, self.min_align_len);
+        }
+        if let Some(fraction) = self.subsample {
+            println!("Subsampled reads at {:.4}: kept {}, dropped {}.",
+                fraction, self.index_to_read_name.len(), self.subsampled_out.len());
+        }
+        if self.missing_nm > 0 {
+            let fraction = self.missing_nm as f64 / self.scored_alignments as f64;
+            if fraction > MISSING_NM_WARN_FRACTION {
+                eprintln!("Warning: {} of {} alignments ({:.1}%) have no NM tag or =/X CIGAR operations.",
+                    self.missing_nm, self.scored_alignments, 100.0 * fraction);
+                eprintln!("Warning: their mismatches are taken as 0, so alignment scores cannot tell taxa apart.");
+                eprintln!("Warning: re-run the aligner with NM output (e.g. minimap2 --MD or samtools calmd) for reliable results.");
+            } else {
+                eprintln!("Warning: {} alignments have no NM tag or =/X CIGAR operations; their mismatches are taken as 0.",
+                    self.missing_nm);
+            }
+        }
+        if self.missing_md > 0 {
+            eprintln!("Warning: {} of {} alignments have no MD tag; they were scored from NM instead.",
+                self.missing_md, self.scored_alignments);
+        }
+        if !self.parser.unmapped_accessions.is_empty() {
+            eprintln!("Warning: {} accessions have no taxon ID mapping; assigned to taxon '{}'.",
+                self.parser.unmapped_accessions.len(), UNMAPPED_TAXON);
+        }
+        if self.parser.include.is_some() || !self.parser.exclude.is_empty() {
+            println!("Dropped {} alignments by taxon include/exclude lists.", self.parser.filtered_alignments);
+        }
+        if num_files > 1 && !self.entries.is_spilled() {
+            println!("Merged {} input files: {} -> {} entries.", num_files, before, self.entries.len());
+        }
+
+        for (name, &len) in &self.ref_lengths {
+            self.parser.add_reference_length(name, len);
+        }
+        // Taxon-level overrides replace the per-reference sum
+        for (key, &len) in &self.length_overrides {
+            if let Some(total) = self.parser.genome_lengths.get_mut(key) {
+                *total = len;
+            }
+        }
+
+        // Reads whose every alignment was rejected by the include/exclude lists
+        let mut has_entries = vec![false; self.index_to_read_name.len()];
+        self.entries.for_each(|e| has_entries[e.read_idx] = true)?;
+        let mut excluded_only: Vec<usize> = self.excluded_rows.iter().cloned().filter(|&r| !has_entries[r]).collect();
+        excluded_only.sort_unstable();
+        let unexplained: Vec<usize> = (0..has_entries.len())
+            .filter(|&r| !has_entries[r] && excluded_only.binary_search(&r).is_err())
+            .collect();
+        self.parser.excluded_only_reads = excluded_only;
+        if options.strict && !unexplained.is_empty() {
+            if options.verbose {
+                for &r in &unexplained {
+                    eprintln!("Read without alignments: {}", self.index_to_read_name[r]);
+                }
+            }
+            bail!(
+                "--strict: {} reads have no scored alignment (unmapped, unresolved reference or too short), e.g. '{}'",
+                unexplained.len(), self.index_to_read_name[unexplained[0]]
+            );
+        }
+
+        if self.track_coverage {
+            self.parser.coverage_breadth = self.coverage_breadth();
+            println!("Computed coverage breadth for {} taxa.", self.parser.coverage_breadth.len());
+        }
+
+        // --- Calculate Median Genome Length ---
+        let total_refs = self.ref_lengths.len();
+        let mut lengths: Vec<u64> = self.ref_lengths.iter()
+            .filter(|&(name, &len)| {
+                len >= options.min_ref_len && len > 0
+                    && (!options.median_aligned_only || self.aligned_refs.contains(name))
+            })
+            .map(|(_, &len)| len)
+            .collect();
+        if lengths.len() < total_refs {
+            println!("Median genome length computed over {} / {} references.", lengths.len(), total_refs);
+        }
+
+        let median_len = if lengths.is_empty() {
+            eprintln!("Warning: No reference sequences left for the median genome length. Using default {:.1}", DEFAULT_GENOME_LEN);
+            DEFAULT_GENOME_LEN
         } else {
-            ref_lengths[mid] as f64
+            lengths.sort_unstable();
+            let mid = lengths.len() / 2;
+            if lengths.len().is_multiple_of(2) {
+                (lengths[mid - 1] + lengths[mid]) as f64 / 2.0
+            } else {
+                lengths[mid] as f64
+            }
+        };
+        println!("Detected Median Genome Length: {:.0} bp", median_len);
+
+        let num_reads = self.index_to_read_name.len();
+        println!("Parsed {} entries for {} unique reads.", self.entries.len(), num_reads);
+        Ok((self.entries, num_reads, self.parser, self.index_to_read_name, median_len))
+    }
+}
+
+/// Reads a two-column TSV (reference name or taxon ID, genome length in bp).
+/// Blank lines and lines starting with `#` are skipped.
+pub fn load_genome_lengths(path: &str) -> Result<HashMap<String, u64>> {
+    println!("Loading genome length overrides from: {}", path);
+    let file = File::open(path).with_context(|| format!("Failed to open genome length table: {}", path))?;
+    let mut lengths = HashMap::new();
+    for (line_no, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') { continue; }
+        let mut fields = line.split('\t');
+        let (Some(name), Some(len)) = (fields.next(), fields.next()) else {
+            bail!("{}:{}: expected <name>\t<length>", path, line_no + 1);
+        };
+        let len: u64 = len.trim().parse()
+            .with_context(|| format!("{}:{}: invalid genome length '{}'", path, line_no + 1, len))?;
+        if len == 0 {
+            bail!("{}:{}: genome length must be positive", path, line_no + 1);
         }
+        lengths.insert(name.trim().to_string(), len);
+    }
+    println!("Loaded {} genome length overrides.", lengths.len());
+    Ok(lengths)
+}
+
+/// Prints reference sequence statistics from each BAM header without reading any records
+pub fn list_references<P: AsRef<Path>>(paths: &[P]) -> Result<()> {
+    for path in paths {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Failed to open BAM file: {}", path.display()))?;
+        let mut reader = bam::io::Reader::new(BufReader::new(file));
+        let header = reader.read_header().context("Failed to read BAM header")?;
+        let references = header.reference_sequences();
+
+        let mut lens: Vec<u64> = references.values().map(|rs| rs.length().get() as u64).collect();
+        lens.sort_unstable();
+        let with_taxid = references.keys()
+            .filter(|name| {
+                let name = name.to_string();
+                name.starts_with("ti|") && name.split('|').nth(1).is_some_and(|t| !t.is_empty() && t.bytes().all(|b| b.is_ascii_digit()))
+            })
+            .count();
+
+        println!("--- References in {} ---", path.display());
+        println!("Reference sequences: {}", lens.len());
+        if let (Some(min), Some(max)) = (lens.first(), lens.last()) {
+            let mid = lens.len() / 2;
+            let median = if lens.len().is_multiple_of(2) { (lens[mid - 1] + lens[mid]) as f64 / 2.0 } else { lens[mid] as f64 };
+            println!("Length min/median/max: {} / {:.0} / {}", min, median, max);
+        }
+        println!("Names with ti|<taxid>| format: {} ({:.1}%)", with_taxid,
+            100.0 * with_taxid as f64 / lens.len().max(1) as f64);
+        if with_taxid * 2 < lens.len() {
+            eprintln!("Warning: most reference names in {} do not match the 'ti|<taxid>|' format; use --acc2taxid to map accessions to taxon IDs.", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Reference names across the BAM headers, so only their accessions are kept from
+/// (typically huge) NCBI accession2taxid files
+fn bam_reference_names<P: AsRef<Path>>(paths: &[P]) -> Result<HashSet<String>> {
+    let mut names = HashSet::new();
+    for path in paths {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Failed to open BAM file: {}", path.display()))?;
+        let header = bam::io::Reader::new(BufReader::new(file)).read_header().context("Failed to read BAM header")?;
+        names.extend(header.reference_sequences().keys().map(|name| name.to_string()));
+    }
+    Ok(names)
+}
+
+pub fn process_bams<P: AsRef<Path>>(paths: &[P], options: &ParseOptions) -> Result<ParseOutput> {
+    let references = match options.acc2taxid {
+        Some(_) => Some(bam_reference_names(paths)?),
+        None => None,
     };
-    println!("Detected Median Genome Length: {:.0} bp", median_len);
+    let mut state = ParseState::new(options, references.as_ref())?;
+    for path in paths {
+        parse_bam(path.as_ref(), &mut state)?;
+    }
+    state.finish(paths.len(), options)
+}
+
+/// Parses minimap2 PAF files. Mismatches come from `NM:i`, else `de:f` times the
+/// block length, else block length minus matching bases. Lambda is the block
+/// length times a fixed per-base error rate since PAF carries no qualities.
+pub fn process_pafs<P: AsRef<Path>>(paths: &[P], options: &ParseOptions) -> Result<ParseOutput> {
+    let mut state = ParseState::new(options, None)?;
+    for path in paths {
+        parse_paf(path.as_ref(), &mut state)?;
+    }
+    state.finish(paths.len(), options)
+}
+
+const PAF_ERROR_RATE: f64 = 0.03;
+
+/// Parses BLAST tabular files (`-outfmt 6`, the standard 12 columns). Mismatches are
+/// the larger of the mismatch column and length x (1 - pident/100), the latter also
+/// counting gap columns. Lambda is the alignment length times a fixed per-base error
+/// rate. m8 carries no subject lengths: use --genome-lengths, otherwise subjects are
+/// scored against a default length.
+pub fn process_blast<P: AsRef<Path>>(paths: &[P], options: &ParseOptions) -> Result<ParseOutput> {
+    let mut state = ParseState::new(options, None)?;
+    for path in paths {
+        parse_blast(path.as_ref(), &mut state)?;
+    }
+    state.finish(paths.len(), options)
+}
+
+fn parse_blast(path: &Path, state: &mut ParseState) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("Failed to open BLAST file: {}", path.display()))?;
+    let pb = file_progress_bar(&file, "Parsing m8");
+    let reader = BufReader::new(pb.wrap_read(file));
+
+    println!("--- Parsing BLAST m8 {} ---", path.display());
+
+    let mut unknown_lengths: HashSet<String> = HashSet::new();
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') { continue; }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 12 {
+            bail!("{}:{}: expected 12 BLAST tabular columns, found {}", path.display(), line_no + 1, fields.len());
+        }
+
+        let parse_u64 = |i: usize| -> Result<u64> {
+            fields[i].trim().parse::<u64>()
+                .with_context(|| format!("{}:{}: invalid integer in column {}", path.display(), line_no + 1, i + 1))
+        };
+
+        let subject = fields[1];
+        let pident: f64 = fields[2].trim().parse()
+            .with_context(|| format!("{}:{}: invalid percent identity '{}'", path.display(), line_no + 1, fields[2]))?;
+        let align_len = parse_u64(3)?;
+        let mismatches = parse_u64(4)?;
+        let (query_start, query_end) = (parse_u64(6)?, parse_u64(7)?);
+        let (subject_start, subject_end) = (parse_u64(8)?, parse_u64(9)?);
+
+        if !state.keep_read(fields[0].as_bytes()) { continue; }
+        // Assign the row first so reads whose alignments are all filtered fall to Unknown
+        let read_idx = state.read_row(fields[0].as_bytes());
+        if query_start.abs_diff(query_end) + 1 < state.min_align_len {
+            state.short_alignments += 1;
+            continue;
+        }
+
+        let target_len = state.reference_length(subject, 0);
+        state.ref_lengths.insert(subject.to_string(), target_len);
+        state.aligned_refs.insert(subject.to_string());
+        // 1-based inclusive, reversed for minus-strand hits
+        state.add_interval(subject, subject_start.min(subject_end).saturating_sub(1), subject_start.max(subject_end));
+
+        let taxon_idx = match state.parser.get_taxon_index(subject) {
+            Some(idx) => idx,
+            None => {
+                state.excluded_rows.insert(read_idx);
+                continue;
+            }
+        };
+
+        let scoring_len = if target_len > 0 {
+            target_len
+        } else {
+            unknown_lengths.insert(subject.to_string());
+            DEFAULT_GENOME_LEN as u64
+        };
+        let from_identity = (align_len as f64 * (1.0 - pident / 100.0)).round().max(0.0) as u64;
+        let k = mismatches.max(from_identity) as u32;
+        let lambda = DEFAULT_ERROR_RATE * align_len as f64;
+        let log_pij = state.error_model.log_prob(k, lambda, align_len, scoring_len);
+
+        state.entries.push(MatrixEntry { read_idx, taxon_idx, log_prob: log_pij })?;
+    }
+
+    if !unknown_lengths.is_empty() {
+        eprintln!("Warning: {} subjects in {} have no genome length (see --genome-lengths); scored with {:.0} bp.",
+            unknown_lengths.len(), path.display(), DEFAULT_GENOME_LEN);
+    }
+    pb.finish_and_clear();
+    Ok(())
+}
+
+fn parse_paf(path: &Path, state: &mut ParseState) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("Failed to open PAF file: {}", path.display()))?;
+    let pb = file_progress_bar(&file, "Parsing PAF");
+    let reader = BufReader::new(pb.wrap_read(file));
+
+    println!("--- Parsing PAF {} ---", path.display());
+
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() { continue; }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 12 {
+            bail!("{}:{}: expected at least 12 PAF columns, found {}", path.display(), line_no + 1, fields.len());
+        }
+
+        let parse_u64 = |i: usize| -> Result<u64> {
+            fields[i].parse::<u64>()
+                .with_context(|| format!("{}:{}: invalid integer in column {}", path.display(), line_no + 1, i + 1))
+        };
+
+        if !state.keep_read(fields[0].as_bytes()) { continue; }
+        // Assign the row first so unmapped reads (`*` target, from --paf-no-hit) and reads
+        // whose alignments are all filtered fall to Unknown, as in BAM input
+        let read_idx = state.read_row(fields[0].as_bytes());
+        let target_name = fields[5];
+        if target_name == "*" { continue; }
+        let target_len = state.reference_length(target_name, parse_u64(6)?);
+        let target_start = parse_u64(7)?;
+        let target_end = parse_u64(8)?;
+        let num_matches = parse_u64(9)?;
+        let block_len = parse_u64(10)?;
+        let query_aligned = parse_u64(3)?.saturating_sub(parse_u64(2)?);
+        if query_aligned < state.min_align_len {
+            state.short_alignments += 1;
+            continue;
+        }
+
+        state.ref_lengths.insert(target_name.to_string(), target_len);
+        state.aligned_refs.insert(target_name.to_string());
+        state.add_interval(target_name, target_start, target_end);
+
+        let taxon_idx = match state.parser.get_taxon_index(target_name) {
+            Some(idx) => idx,
+            None => {
+                state.excluded_rows.insert(read_idx);
+                continue;
+            }
+        };
+
+        let mut nm: Option<u64> = None;
+        let mut divergence: Option<f64> = None;
+        for tag in &fields[12..] {
+            if let Some(v) = tag.strip_prefix("NM:i:") {
+                nm = v.parse().ok();
+            } else if let Some(v) = tag.strip_prefix("de:f:") {
+                divergence = v.parse().ok();
+            }
+        }
+        let k = nm
+            .or_else(|| divergence.map(|d| (d * block_len as f64).round() as u64))
+            .unwrap_or(block_len.saturating_sub(num_matches)) as u32;
+
+        let lambda = PAF_ERROR_RATE * block_len as f64;
+        let log_pij = state.error_model.log_prob(k, lambda, block_len, target_len);
+
+        state.entries.push(MatrixEntry { read_idx, taxon_idx, log_prob: log_pij })?;
+    }
+
+    pb.finish_and_clear();
+    Ok(())
+}
+
+/// Read name shared by both mates: drops a trailing `/1` or `/2`
+fn mate_name(name: &[u8]) -> &[u8] {
+    match name {
+        [head @ .., b'/', b'1' | b'2'] => head,
+        _ => name,
+    }
+}
+
+/// Joint per-taxon scores of a read's mates (drains both buffers). A taxon hit by both
+/// mates scores the mean of their best scores, so a pair stays on the per-read scale of
+/// singletons and of the Unknown floor. For a taxon hit by only one mate (a discordant
+/// pair), the other mate contributes its weakest score over the taxa it did hit, so the
+/// taxon is kept but cannot outscore a concordant one on that mate. A mate without any
+/// alignment contributes nothing. Single-end reads pass through unchanged.
+fn combine_mates(buffer: &mut [HashMap<usize, f64>; 2]) -> Vec<(usize, f64)> {
+    let [first, second] = buffer;
+    if second.is_empty() {
+        return first.drain().collect();
+    }
+    if first.is_empty() {
+        return second.drain().collect();
+    }
+    let weakest = |m: &HashMap<usize, f64>| m.values().cloned().fold(f64::INFINITY, f64::min);
+    let (weakest_first, weakest_second) = (weakest(first), weakest(second));
+
+    let mut combined: Vec<(usize, f64)> = first.iter()
+        .map(|(&t, &p1)| (t, 0.5 * (p1 + second.get(&t).copied().unwrap_or(weakest_second))))
+        .collect();
+    combined.extend(second.iter()
+        .filter(|(t, _)| !first.contains_key(t))
+        .map(|(&t, &p2)| (t, 0.5 * (weakest_first + p2))));
+    first.clear();
+    second.clear();
+    combined
+}
+
+/// Read IDs are assigned on name changes, so coordinate-sorted input would silently
+/// split reads. Aligner output is grouped by name but often has no SO tag (or
+/// `unsorted`), so only `coordinate` is fatal. Skipped with `assume_coord_sorted`.
+fn check_sort_order(header: &noodles::sam::Header, path: &Path) -> Result<()> {
+    let sort_order = header
+        .header()
+        .and_then(|hd| hd.other_fields().get(&tag::SORT_ORDER))
+        .map(|so| so.as_ref());
+
+    match sort_order {
+        Some(so) if so == sort_order::QUERY_NAME => {}
+        Some(so) if so == sort_order::COORDINATE => {
+            bail!(
+                "BAM {} is coordinate-sorted; sort it by read name first (samtools sort -n) or pass --assume-coord-sorted",
+                path.display()
+            );
+        }
+        Some(so) => {
+            eprintln!(
+                "WARNING: BAM {} has sort order '{}', not queryname. Reads must be grouped by name; \
+                 if unsure, run samtools sort -n.",
+                path.display(),
+                String::from_utf8_lossy(so)
+            );
+        }
+        None => {
+            eprintln!(
+                "WARNING: BAM {} has no @HD SO tag. Reads must be grouped by name; \
+                 if unsure, run samtools sort -n.",
+                path.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Number of reference bases covered by an alignment's CIGAR
+fn reference_span(record: &noodles::sam::alignment::RecordBuf) -> u64 {
+    record.cigar().as_ref().iter()
+        .filter(|op| matches!(op.kind(), Kind::Match | Kind::Deletion | Kind::Skip | Kind::SequenceMatch | Kind::SequenceMismatch))
+        .map(|op| op.len() as u64)
+        .sum()
+}
 
-    let mut entries: Vec<MatrixEntry> = Vec::new();
-    let mut parser = TaxonomyParser::new()?;
-    let mut index_to_read_name: Vec<String> = Vec::new();
-    let mut next_read_idx = 0;
+/// Edit distance from an extended (`=`/`X`) CIGAR: mismatches plus inserted and
+/// deleted bases, matching the NM definition. `None` for plain `M` CIGARs.
+fn cigar_edit_distance(record: &noodles::sam::alignment::RecordBuf) -> Option<u32> {
+    let ops = record.cigar();
+    let extended = ops.as_ref().iter().any(|op| matches!(op.kind(), Kind::SequenceMatch | Kind::SequenceMismatch));
+    if !extended {
+        return None;
+    }
+    let k = ops.as_ref().iter()
+        .filter(|op| matches!(op.kind(), Kind::SequenceMismatch | Kind::Insertion | Kind::Deletion))
+        .map(|op| op.len() as u32)
+        .sum();
+    Some(k)
+}
+
+/// Substitutions from the MD tag and inserted plus deleted bases from the CIGAR.
+/// `None` when the record has no MD tag.
+fn md_edit_counts(record: &noodles::sam::alignment::RecordBuf) -> Option<(u32, u32)> {
+    use noodles::sam::alignment::record_buf::data::field::Value;
+    let Some(Value::String(md)) = record.data().get(&Tag::MISMATCHED_POSITIONS) else { return None };
+    // MD is [0-9]+(([A-Z]|\^[A-Z]+)[0-9]+)*: letters after '^' are deleted reference
+    // bases, every other letter is one substituted base
+    let mut subs = 0;
+    let mut in_deletion = false;
+    for &b in md.iter() {
+        match b {
+            b'^' => in_deletion = true,
+            b'0'..=b'9' => in_deletion = false,
+            _ if !in_deletion => subs += 1,
+            _ => {}
+        }
+    }
+    let indels = record.cigar().as_ref().iter()
+        .filter(|op| matches!(op.kind(), Kind::Insertion | Kind::Deletion))
+        .map(|op| op.len() as u32)
+        .sum();
+    Some((subs, indels))
+}
+
+/// Number of read bases aligned to the reference (soft/hard clips excluded)
+fn aligned_length(record: &noodles::sam::alignment::RecordBuf) -> u64 {
+    record.cigar().as_ref().iter()
+        .filter(|op| matches!(op.kind(), Kind::Match | Kind::Insertion | Kind::SequenceMatch | Kind::SequenceMismatch))
+        .map(|op| op.len() as u64)
+        .sum()
+}
+
+fn parse_bam(path: &Path, state: &mut ParseState) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("Failed to open BAM file: {}", path.display()))?;
+    let pb = file_progress_bar(&file, "Parsing BAM");
+    let mut reader = bam::io::Reader::new(BufReader::new(pb.wrap_read(file)));
+    let header = reader.read_header().context("Failed to read BAM header")?;
+    let references = header.reference_sequences();
+
+    if state.assume_coord_sorted {
+        println!("--- Parsing BAM {} (Records of a read may be non-contiguous) ---", path.display());
+    } else {
+        check_sort_order(&header, path)?;
+        println!("--- Parsing BAM {} (Assuming Name-Sorted) ---", path.display());
+    }
 
     // Pre-build Reference Name Map
     let ref_names: Vec<String> = references
@@ -83,59 +895,96 @@ pub fn process_bam<P: AsRef<Path>>(path: P) -> Result<(Vec<MatrixEntry>, usize,
         .map(|k| k.to_string())
         .collect();
 
+    let ref_lens: Vec<u64> = ref_names.iter().zip(references.values())
+        .map(|(name, rs)| state.reference_length(name, rs.length().get() as u64))
+        .collect();
+    for (name, &len) in ref_names.iter().zip(&ref_lens) {
+        state.ref_lengths.insert(name.clone(), len);
+    }
+
     // State
     let mut last_name: Vec<u8> = Vec::new();
-    let mut cached_lambda: f64 = 0.0;
-    let mut cached_len: u64 = 0; 
+    // (lambda, read length) per mate; only index 0 is used for single-end reads
+    let mut cached: [(f64, u64); 2] = [(0.0, 0); 2];
     let unknown_name: &[u8] = b"unknown";
 
-    let mut read_buffer: HashMap<usize, f64> = HashMap::new();
+    // Best score per taxon, per mate
+    let mut read_buffer: [HashMap<usize, f64>; 2] = [HashMap::new(), HashMap::new()];
     let mut current_read_row_idx = 0; 
+    let mut skip_read = false;
 
     // Helper to flush buffer
-    let flush_buffer = |entries: &mut Vec<MatrixEntry>, buffer: &mut HashMap<usize, f64>, r_idx: usize| {
-        for (t_idx, log_p) in buffer.drain() {
-            entries.push(MatrixEntry { read_idx: r_idx, taxon_idx: t_idx, log_prob: log_p });
+    let flush_buffer = |entries: &mut MatrixEntries, buffer: &mut [HashMap<usize, f64>; 2], r_idx: usize| -> Result<()> {
+        for (t_idx, log_p) in combine_mates(buffer) {
+            entries.push(MatrixEntry { read_idx: r_idx, taxon_idx: t_idx, log_prob: log_p })?;
         }
+        Ok(())
     };
 
     for result in reader.record_bufs(&header) {
         let record = result.context("Failed to parse a record")?;
-        let current_name_bytes = record.name().map(|n| n.as_ref()).unwrap_or(unknown_name);
+        let mut current_name_bytes = record.name().map(|n| n.as_ref()).unwrap_or(unknown_name);
+        let mut mate = 0;
+        if state.paired {
+            current_name_bytes = mate_name(current_name_bytes);
+            let flags = record.flags();
+            if flags.is_segmented() && flags.is_last_segment() && !flags.is_first_segment() {
+                mate = 1;
+            }
+        }
         
         // --- 2. Read ID Management (Streaming Mode) ---
-        // Since we assume Name-Sorted, any change in name means a new read ID.
+        // Since we assume Name-Sorted, any change in name means a new read.
+        // IDs are global across input files, so the same read in another BAM
+        // maps back onto the same row.
         let is_name_change = current_name_bytes != last_name.as_slice();
         
         if is_name_change {
             // Flush previous read if it existed
             if !last_name.is_empty() {
-                flush_buffer(&mut entries, &mut read_buffer, current_read_row_idx);
+                flush_buffer(&mut state.entries, &mut read_buffer, current_read_row_idx)?;
             }
 
-            // Assign new ID
-            current_read_row_idx = next_read_idx;
-            next_read_idx += 1;
-            
-            // Store name for output (Step 4 needs names)
-            index_to_read_name.push(String::from_utf8_lossy(current_name_bytes).to_string());
-
-            // Reset Cache
-            cached_lambda = -1.0; 
-            cached_len = 0;
             last_name = current_name_bytes.to_vec();
+            skip_read = !state.keep_read(current_name_bytes);
+            if skip_read { continue; }
+
+            current_read_row_idx = state.read_row(current_name_bytes);
+
+            // Reset Cache (restore it if this read was seen earlier in a coordinate-sorted file).
+            // Reads seen again are merged per read-taxon pair in `finish`.
+            cached = [state.read_lambdas.get(&current_read_row_idx).copied().unwrap_or((-1.0, 0)), (-1.0, 0)];
+        } else if skip_read {
+            continue;
+        }
+
+        if state.min_align_len > 0 && record.reference_sequence_id().is_some()
+            && aligned_length(&record) < state.min_align_len
+        {
+            state.short_alignments += 1;
+            continue;
         }
 
         // --- 3. Resolve Taxon ---
         let (taxon_idx, genome_len) = if let Some(ref_id) = record.reference_sequence_id() {
              if let Some(name_str) = ref_names.get(ref_id) {
-                 match parser.get_taxon_index(name_str) {
+                 match state.parser.get_taxon_index(name_str) {
                      Some(idx) => {
-                         if let Some(map_record) = references.get(name_str.as_bytes()) {
-                             (idx, map_record.length().get() as u64)
+                         if references.get(name_str.as_bytes()).is_some() {
+                             if !state.aligned_refs.contains(name_str) {
+                                 state.aligned_refs.insert(name_str.to_string());
+                             }
+                             if state.track_coverage && let Some(start) = record.alignment_start() {
+                                 let start = start.get() as u64 - 1;
+                                 state.add_interval(name_str, start, start + reference_span(&record));
+                             }
+                             (idx, ref_lens[ref_id])
                          } else { continue; }
                      },
-                     None => continue, 
+                     None => {
+                         state.excluded_rows.insert(current_read_row_idx);
+                         continue;
+                     }
                  }
              } else { continue; }
         } else { continue; };
@@ -159,39 +1008,103 @@ pub fn process_bam<P: AsRef<Path>>(path: P) -> Result<(Vec<MatrixEntry>, usize,
         let raw_quality = record.quality_scores();
         let current_lambda = if !raw_quality.is_empty() {
             let val = calculate_total_lambda(raw_quality.as_ref());
-            cached_lambda = val;
-            cached_len = raw_len;
+            cached[mate] = (val, raw_len);
+            if state.assume_coord_sorted {
+                state.read_lambdas.insert(current_read_row_idx, (val, raw_len));
+            }
             val
         } 
-        else if cached_lambda >= 0.0 && raw_len == cached_len {
-            cached_lambda
+        else if cached[mate].0 >= 0.0 && raw_len == cached[mate].1 {
+            cached[mate].0
         }
         else {
             0.03 * (raw_len as f64)
         };
 
-        let lambda_final = current_lambda.max(0.01 * (raw_len as f64));
 
         // --- 5. Get Mismatches (k) ---
-        let data = record.data();
-        let nm_value = data.get(&Tag::EDIT_DISTANCE).or_else(|| data.get(&Tag::ALIGNMENT_HIT_COUNT)); 
-        let k = match nm_value {
-            Some(value) => value.as_int().unwrap_or(0) as u32,
-            None => 0, 
+        state.scored_alignments += 1;
+        let md_counts = if state.use_md_tag { md_edit_counts(&record) } else { None };
+        if state.use_md_tag && md_counts.is_none() {
+            state.missing_md += 1;
+        }
+        let k = match (md_counts, record.data().get(&Tag::EDIT_DISTANCE)) {
+            (Some((subs, indels)), _) => subs + indels,
+            (None, Some(value)) => value.as_int().unwrap_or(0) as u32,
+            (None, None) => match cigar_edit_distance(&record) {
+                Some(k) => k,
+                None => {
+                    state.missing_nm += 1;
+                    0
+                }
+            },
         };
 
         // --- 6. Score Alignment ---
-        let new_log_pij = calculate_r_score(k, lambda_final, genome_len);
+        let new_log_pij = match md_counts {
+            Some((subs, indels)) => state.error_model.log_prob_split(subs, indels, current_lambda, raw_len, genome_len, state.indel_rate),
+            None => state.error_model.log_prob(k, current_lambda, raw_len, genome_len),
+        };
 
         // Keep best score per read-taxon pair
-        read_buffer.entry(taxon_idx)
+        read_buffer[mate].entry(taxon_idx)
             .and_modify(|e| *e = e.max(new_log_pij))
             .or_insert(new_log_pij);
     }
     
     // Flush final read
-    flush_buffer(&mut entries, &mut read_buffer, current_read_row_idx);
+    if !last_name.is_empty() {
+        flush_buffer(&mut state.entries, &mut read_buffer, current_read_row_idx)?;
+    }
+
+    pb.finish_and_clear();
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    println!("Parsed {} entries for {} unique reads.", entries.len(), next_read_idx);
-    Ok((entries, next_read_idx, parser, index_to_read_name, median_len))
-}
\ No newline at end of file
+    fn sorted(mut scores: Vec<(usize, f64)>) -> Vec<(usize, f64)> {
+        scores.sort_by_key(|&(t, _)| t);
+        scores
+    }
+
+    #[test]
+    fn pairs_score_on_the_singleton_scale() {
+        // A concordant pair whose mates both fit taxon 0 as well as a singleton does
+        let mut pair = [HashMap::from([(0, -2.0), (1, -6.0)]), HashMap::from([(0, -4.0), (2, -5.0)])];
+        let mut single = [HashMap::from([(0, -3.0)]), HashMap::new()];
+        let pair_scores = sorted(combine_mates(&mut pair));
+        let single_scores = combine_mates(&mut single);
+
+        assert_eq!(single_scores, vec![(0, -3.0)]);
+        assert_eq!(pair_scores, vec![(0, -3.0), (1, -5.5), (2, -5.5)]);
+        // Against the same per-read Unknown floor, the pair and the singleton rank taxon 0 alike
+        assert_eq!(pair_scores[0].1, single_scores[0].1);
+        assert!(pair.iter().chain(single.iter()).all(HashMap::is_empty));
+    }
+
+    #[test]
+    fn unmapped_paf_reads_keep_their_row() {
+        let path = std::env::temp_dir().join(format!("metamix_unmapped_{}.paf", std::process::id()));
+        std::fs::write(&path, "r1\t150\t0\t150\t+\tti|100|a\t5000\t10\t160\t149\t150\t60\tNM:i:1\n\
+                               r2\t150\t0\t0\t*\t*\t0\t0\t0\t0\t0\t0\n").unwrap();
+        let (entries, num_reads, _, read_names, _) = process_pafs(&[&path], &ParseOptions::default()).unwrap();
+        std::fs::remove_file(&path).ok();
+        // The unmapped read counts towards the total and is left to the Unknown bin
+        assert_eq!(num_reads, 2);
+        assert_eq!(read_names, ["r1", "r2"]);
+        assert_eq!(entries.len(), 1);
+    }
+
+    #[test]
+    fn interleaved_alignments_of_a_dropped_read_count_once() {
+        let options = ParseOptions { subsample: Some(0.0), ..ParseOptions::default() };
+        let mut state = ParseState::new(&options, None).unwrap();
+        // Coordinate-sorted input: each read's alignments are scattered between others
+        for name in [b"r1", b"r2", b"r1", b"r3", b"r2", b"r1"] {
+            assert!(!state.keep_read(name));
+        }
+        assert_eq!(state.subsampled_out.len(), 3);
+    }
+}
@@ -1,7 +1,10 @@
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use nalgebra_sparse::coo::CooMatrix;
 use nalgebra_sparse::csr::CsrMatrix;
 use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Debug)]
 pub struct MatrixEntry {
@@ -10,62 +13,342 @@ pub struct MatrixEntry {
     pub log_prob: f64,
 }
 
+/// Size of one spilled entry: read and taxon index as u64, log-probability as f64 (little-endian)
+const SPILL_RECORD_BYTES: usize = 24;
+
+/// Matrix entries collected during parsing, either in memory or spilled to a temporary
+/// file of fixed-size binary records so parsing does not hold them all in RAM
+pub struct MatrixEntries {
+    memory: Vec<MatrixEntry>,
+    spill: Option<EntrySpill>,
+}
+
+struct EntrySpill {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    len: usize,
+}
+
+impl Drop for EntrySpill {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl MatrixEntries {
+    pub fn in_memory() -> Self {
+        Self { memory: Vec::new(), spill: None }
+    }
+
+    /// Entries written to a new temporary file in `dir` (removed when dropped)
+    pub fn spilled(dir: &str) -> Result<Self> {
+        let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        let path = Path::new(dir).join(format!("metamix_entries_{}_{}.bin", std::process::id(), nanos));
+        let file = File::create(&path).with_context(|| format!("Failed to create spill file {}", path.display()))?;
+        println!("Spilling matrix entries to: {}", path.display());
+        Ok(Self { memory: Vec::new(), spill: Some(EntrySpill { path, writer: BufWriter::new(file), len: 0 }) })
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.spill.as_ref().map(|s| s.len).unwrap_or(self.memory.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&mut self, entry: MatrixEntry) -> Result<()> {
+        match &mut self.spill {
+            Some(spill) => {
+                let mut record = [0u8; SPILL_RECORD_BYTES];
+                record[..8].copy_from_slice(&(entry.read_idx as u64).to_le_bytes());
+                record[8..16].copy_from_slice(&(entry.taxon_idx as u64).to_le_bytes());
+                record[16..].copy_from_slice(&entry.log_prob.to_le_bytes());
+                spill.writer.write_all(&record).context("Failed to write to the spill file")?;
+                spill.len += 1;
+            }
+            None => self.memory.push(entry),
+        }
+        Ok(())
+    }
+
+    /// Mutable access to in-memory entries (`None` when spilled)
+    pub fn in_memory_mut(&mut self) -> Option<&mut Vec<MatrixEntry>> {
+        if self.spill.is_some() { None } else { Some(&mut self.memory) }
+    }
+
+    /// Calls `f` on every entry in insertion order, streaming spilled entries back from disk
+    pub fn for_each(&mut self, mut f: impl FnMut(&MatrixEntry)) -> Result<()> {
+        let Some(spill) = &mut self.spill else {
+            self.memory.iter().for_each(f);
+            return Ok(());
+        };
+        spill.writer.flush().context("Failed to flush the spill file")?;
+        let mut reader = BufReader::new(File::open(&spill.path).context("Failed to reopen the spill file")?);
+        let mut record = [0u8; SPILL_RECORD_BYTES];
+        for _ in 0..spill.len {
+            reader.read_exact(&mut record).context("Failed to read the spill file")?;
+            let field = |i: usize| -> [u8; 8] { record[i..i + 8].try_into().unwrap() };
+            f(&MatrixEntry {
+                read_idx: u64::from_le_bytes(field(0)) as usize,
+                taxon_idx: u64::from_le_bytes(field(8)) as usize,
+                log_prob: f64::from_le_bytes(field(16)),
+            });
+        }
+        Ok(())
+    }
+}
+
 pub struct TaxonomyParser {
     pub name_to_id: HashMap<String, usize>,
     pub index_to_id: Vec<String>,
+    /// Total reference length per taxon ID (sum over all its sequences)
+    pub genome_lengths: HashMap<String, u64>,
+    /// Breadth of coverage per taxon ID (only filled when coverage tracking is enabled)
+    pub coverage_breadth: HashMap<String, f64>,
+    /// If set, only these taxon IDs / reference names enter the matrix
+    pub include: Option<HashSet<String>>,
+    /// Taxon IDs / reference names kept out of the matrix
+    pub exclude: HashSet<String>,
+    /// Alignments rejected by the include/exclude lists
+    pub filtered_alignments: usize,
+    /// Accession -> taxon ID; when set, reference names are looked up here instead of parsed
+    pub acc2taxid: Option<HashMap<String, String>>,
+    /// Accessions missing from `acc2taxid`, routed to `UNMAPPED_TAXON`
+    pub unmapped_accessions: HashSet<String>,
+    /// Rows whose every alignment was rejected by the include/exclude lists (sorted)
+    pub excluded_only_reads: Vec<usize>,
 }
 
+/// Taxon ID given to references without an accession2taxid entry
+pub const UNMAPPED_TAXON: &str = "unmapped";
+
 impl TaxonomyParser {
     pub fn new() -> Result<Self> {
         Ok(Self {
             name_to_id: HashMap::new(),
             index_to_id: Vec::new(),
+            genome_lengths: HashMap::new(),
+            coverage_breadth: HashMap::new(),
+            include: None,
+            exclude: HashSet::new(),
+            filtered_alignments: 0,
+            acc2taxid: None,
+            unmapped_accessions: HashSet::new(),
+            excluded_only_reads: Vec::new(),
         })
     }
 
-    pub fn get_taxon_index(&mut self, name: &str) -> Option<usize> {
-        let id_part = if name.starts_with("ti|") {
-            name.split('|').nth(1)?
+    /// Whether a reference (matched by full name or taxon ID) passes the include/exclude lists
+    fn is_allowed(&self, name: &str, id_part: &str) -> bool {
+        let listed = |set: &HashSet<String>| set.contains(id_part) || set.contains(name);
+        !listed(&self.exclude) && self.include.as_ref().is_none_or(listed)
+    }
+
+    /// Taxon ID of a reference: its accession2taxid entry when a mapping is loaded
+    /// (`UNMAPPED_TAXON` if missing), else parsed from `ti|<taxid>|...` or the bare name
+    pub fn taxon_id<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        if let Some(map) = &self.acc2taxid {
+            Some(map.get(name).map(|s| s.as_str()).unwrap_or(UNMAPPED_TAXON))
+        } else if name.starts_with("ti|") {
+            name.split('|').nth(1)
         } else {
-            name 
-        };
+            Some(name)
+        }
+    }
 
-        if let Some(&idx) = self.name_to_id.get(id_part) {
+    pub fn add_reference_length(&mut self, name: &str, len: u64) {
+        if let Some(id_part) = self.taxon_id(name).map(str::to_string) {
+            *self.genome_lengths.entry(id_part).or_insert(0) += len;
+        }
+    }
+
+    /// Matrix column for a reference, or `None` if it has no taxon or is filtered out.
+    /// Reads whose alignments are all filtered keep their row and fall to Unknown.
+    pub fn get_taxon_index(&mut self, name: &str) -> Option<usize> {
+        let id_part = self.taxon_id(name)?.to_string();
+        if !self.is_allowed(name, &id_part) {
+            self.filtered_alignments += 1;
+            return None;
+        }
+        if id_part == UNMAPPED_TAXON && self.acc2taxid.is_some() && !self.unmapped_accessions.contains(name) {
+            self.unmapped_accessions.insert(name.to_string());
+        }
+
+        if let Some(&idx) = self.name_to_id.get(&id_part) {
             Some(idx)
         } else {
             let idx = self.index_to_id.len();
-            self.name_to_id.insert(id_part.to_string(), idx);
-            self.index_to_id.push(id_part.to_string());
+            self.name_to_id.insert(id_part.clone(), idx);
+            self.index_to_id.push(id_part);
             Some(idx)
         }
     }
 }
 
+/// Reads a list of taxon IDs or reference names, one per line (`#` comments allowed)
+pub fn load_taxon_list(path: &str) -> Result<HashSet<String>> {
+    let file = File::open(path).with_context(|| format!("Failed to open taxon list: {}", path))?;
+    let mut taxa = HashSet::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let entry = line.trim();
+        if !entry.is_empty() && !entry.starts_with('#') {
+            taxa.insert(entry.to_string());
+        }
+    }
+    println!("Loaded {} taxa from {}", taxa.len(), path);
+    Ok(taxa)
+}
+
+/// Reads an accession -> taxon ID table. Accepts two-column `<accession>\t<taxid>`
+/// files and NCBI `accession2taxid` files (`accession accession.version taxid gi`,
+/// header line skipped), where both the bare and versioned accession are mapped.
+/// With `keep`, only those accessions are stored, so a full NCBI table is streamed
+/// through rather than held in memory.
+pub fn load_acc2taxid(path: &str, keep: Option<&HashSet<String>>) -> Result<HashMap<String, String>> {
+    let file = File::open(path).with_context(|| format!("Failed to open accession2taxid file: {}", path))?;
+    let wanted = |acc: &str| keep.is_none_or(|k| k.contains(acc));
+    let mut map = HashMap::new();
+    for (line_no, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') || line.starts_with("accession\t") { continue; }
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        match fields.as_slice() {
+            [acc, taxid] => {
+                if wanted(acc) { map.insert(acc.to_string(), taxid.to_string()); }
+            }
+            [acc, acc_version, taxid, ..] => {
+                if wanted(acc) { map.insert(acc.to_string(), taxid.to_string()); }
+                if wanted(acc_version) { map.insert(acc_version.to_string(), taxid.to_string()); }
+            }
+            _ => anyhow::bail!("{}:{}: expected <accession>\t<taxid>", path, line_no + 1),
+        }
+    }
+    println!("Loaded {} accession to taxon ID mappings from {}", map.len(), path);
+    Ok(map)
+}
+
 pub struct Step2Result {
     pub matrix: CsrMatrix<f64>,
     pub reduced_taxons: Vec<String>, 
     pub reduced_abundances: Vec<f64>,
+    pub reduced_genome_lengths: Vec<f64>,
+    /// Original read indices of the matrix rows, when empty rows were dropped
+    pub kept_reads: Option<Vec<usize>>,
+    /// Representative taxon ID -> taxa whose identical columns were merged into it
+    pub merged_taxa: HashMap<String, Vec<String>>,
+    /// Number of reads with an alignment to each column of `matrix`
+    pub column_support: Vec<usize>,
+    /// Reads removed from the matrix as host-only or excluded-only, when separated
+    pub separated_reads: Option<SeparatedReads>,
+}
+
+/// Reads kept out of the microbial matrix (and so out of the Unknown bin)
+#[derive(Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct SeparatedReads {
+    /// Reads whose only alignments are to host taxa
+    pub host_only: usize,
+    /// Reads whose only alignments were rejected by the include/exclude lists
+    pub excluded_only: usize,
+}
+
+/// Settings for `run_em_reduction`
+#[derive(Clone, Debug)]
+pub struct EmConfig {
+    /// Minimum effective read count for a species to survive the post-EM filter
+    pub read_cutoff: usize,
+    /// Apply the post-EM filter; when false every species with at least one read reaches MCMC
+    pub pre_filter: bool,
+    pub iterations: usize,
+    /// Use SQUAREM acceleration
+    pub accelerate: bool,
+    /// Remove matrix rows left without any alignment
+    pub drop_empty_reads: bool,
+    /// Collapse taxa with identical matrix columns into one representative
+    pub merge_identical: bool,
+    pub tol_type: EmTolType,
+    /// Remove host-only reads (alignments only to these taxon IDs) and excluded-only
+    /// reads from the matrix, counting them separately
+    pub separate_host: Option<HashSet<String>>,
+    pub verbose: bool,
+}
+
+/// EM stopping criterion
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmTolType {
+    /// Summed absolute abundance change below 1e-6
+    Abs,
+    /// Relative log-likelihood change |ΔlogL| / |logL| below 1e-8
+    Rel,
+}
+
+const EM_ABS_TOL: f64 = 1e-6;
+const EM_REL_TOL: f64 = 1e-8;
+
+/// Convergence check for one EM run; tracks the previous log-likelihood for `Rel`
+struct EmStop {
+    tol_type: EmTolType,
+    prev_log_l: Option<f64>,
+}
+
+impl EmStop {
+    fn new(tol_type: EmTolType) -> Self {
+        Self { tol_type, prev_log_l: None }
+    }
+
+    fn converged(&mut self, diff: f64, log_l: f64) -> bool {
+        match self.tol_type {
+            EmTolType::Abs => diff < EM_ABS_TOL,
+            EmTolType::Rel => {
+                let prev = self.prev_log_l.replace(log_l);
+                prev.is_some_and(|p| (log_l - p).abs() / log_l.abs().max(f64::MIN_POSITIVE) < EM_REL_TOL)
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.tol_type {
+            EmTolType::Abs => format!("abundance diff < {:e}", EM_ABS_TOL),
+            EmTolType::Rel => format!("relative logL change < {:e}", EM_REL_TOL),
+        }
+    }
 }
 
 pub fn run_em_reduction(
-    entries: Vec<MatrixEntry>,
+    mut entries: MatrixEntries,
     num_reads: usize,
     parser: TaxonomyParser,
-    read_cutoff: usize,
-    iterations: usize,
-    verbose: bool,
+    config: &EmConfig,
 ) -> Result<Step2Result> {
+    let EmConfig { read_cutoff, pre_filter, iterations, accelerate, drop_empty_reads, merge_identical, tol_type, verbose, .. } = *config;
     println!("Step 2: Filtering and building matrix...");
+    anyhow::ensure!(
+        !pre_filter || read_cutoff <= num_reads,
+        "--em-read-cutoff ({}) exceeds the number of reads ({}); no species could pass the post-EM filter",
+        read_cutoff, num_reads
+    );
 
     // 1. Identification: Identify all species present in the input
     //    We do NOT filter by read_cutoff here anymore. We match R's logic:
     //    Run EM on *everything* that has at least 1 read.
     let mut species_present = HashSet::new();
-    for e in &entries {
+    let mut row_counts = vec![0usize; num_reads];
+    entries.for_each(|e| {
         species_present.insert(e.taxon_idx);
-    }
+        row_counts[e.read_idx] += 1;
+    })?;
 
     println!("Pre-EM: Found {} unique species in BAM input.", species_present.len());
+    anyhow::ensure!(
+        !species_present.is_empty(),
+        "No alignments left after parsing; check the input files and the taxon/length filters"
+    );
 
     // 2. Remap Indices (Old ID -> New Matrix Column)
     let mut old_to_new_map = HashMap::new();
@@ -82,60 +365,208 @@ pub fn run_em_reduction(
     }
 
     let num_species_pre = sorted_species.len();
-    let mut coo = CooMatrix::new(num_reads, num_species_pre);
-
-    for e in entries {
-        if let Some(&new_col) = old_to_new_map.get(&e.taxon_idx) {
-            coo.push(e.read_idx, new_col, e.log_prob);
-        }
-    }
-
-    let csr = CsrMatrix::from(&coo);
+    let csr = build_log_matrix(&mut entries, &row_counts, &old_to_new_map, num_species_pre)?;
+    drop(entries);
 
     // 3. Run EM (On EVERYTHING)
-    println!("Running EM for {} iterations...", iterations);
-    let (em_abundances, final_iter) = run_em(&csr, iterations, verbose);
+    println!("Running EM for {} iterations{}...", iterations, if accelerate { " (SQUAREM-accelerated)" } else { "" });
+    let mut stop = EmStop::new(tol_type);
+    let (em_abundances, final_iter, converged) = run_em(&csr, iterations, verbose, accelerate, &mut stop);
 
-    println!("EM Converged/Stopped at iteration {}.", final_iter);
+    println!("EM {} at iteration {}{} ({}).",
+        if converged { "Converged" } else { "Stopped" }, final_iter,
+        if accelerate { " (SQUAREM-accelerated)" } else { "" },
+        if converged { stop.describe() } else { "iteration limit".to_string() });
 
     // 4. Post-Filter: Match R Logic (Effective Count)
     //    R: ordered.species <- ordered.species[which(ordered.species$countReads >= read.cutoff), ]
     //    where countReads = round(abundance * total_reads)
-    println!("Applying Post-EM filter (Effective Count >= {})...", read_cutoff);
+    if pre_filter {
+        println!("Applying Post-EM filter (Effective Count >= {})...", read_cutoff);
+    } else {
+        eprintln!(
+            "Warning: post-EM filter disabled; all {} species go to MCMC. Expect a larger matrix and slower MCMC.",
+            num_species_pre
+        );
+    }
 
     let total_reads_f64 = num_reads as f64;
     let mut survivor_indices = Vec::new();
     let mut reduced_taxons = Vec::new();
     let mut reduced_abundances = Vec::new();
+    let mut reduced_genome_lengths = Vec::new();
 
     for (col_idx, &abund) in em_abundances.iter().enumerate() {
         // Match R's rounding logic
         let effective_count = (abund * total_reads_f64).round();
         
-        if effective_count >= (read_cutoff as f64) {
+        if !pre_filter || effective_count >= (read_cutoff as f64) {
             survivor_indices.push(col_idx);
             
             // Map back to original Taxon ID
             let old_idx = new_to_old_map[col_idx];
             let taxon_id = parser.index_to_id[old_idx].clone();
+            let genome_len = parser.genome_lengths.get(&taxon_id).copied().unwrap_or(0) as f64;
             
             reduced_taxons.push(taxon_id);
+            reduced_genome_lengths.push(genome_len);
             reduced_abundances.push(abund);
         }
     }
 
     println!("Post-EM: Retained {} / {} species", reduced_taxons.len(), num_species_pre);
+    if reduced_taxons.is_empty() {
+        let best = em_abundances.iter().cloned().fold(0.0, f64::max) * total_reads_f64;
+        anyhow::bail!(
+            "No species passed the post-EM filter (--em-read-cutoff {}); the best-supported species has ~{:.0} reads. Try a lower cutoff.",
+            read_cutoff, best
+        );
+    }
 
     // 5. Subset the Matrix for Step 3
-    let final_matrix = subset_matrix_columns(&csr, &survivor_indices);
+    let mut final_matrix = subset_matrix_columns(&csr, &survivor_indices);
+
+    // 5b. Collapse indistinguishable taxa
+    let mut merged_taxa = HashMap::new();
+    if merge_identical {
+        let groups = identical_column_groups(&final_matrix);
+        if groups.iter().any(|g| g.len() > 1) {
+            let keep: Vec<usize> = groups.iter().map(|g| g[0]).collect();
+            for group in &groups {
+                if group.len() > 1 {
+                    let members = group[1..].iter().map(|&c| reduced_taxons[c].clone()).collect();
+                    merged_taxa.insert(reduced_taxons[group[0]].clone(), members);
+                }
+            }
+            // The representative carries the group's combined EM abundance
+            reduced_abundances = groups.iter().map(|g| g.iter().map(|&c| reduced_abundances[c]).sum()).collect();
+            reduced_taxons = keep.iter().map(|&c| reduced_taxons[c].clone()).collect();
+            reduced_genome_lengths = keep.iter().map(|&c| reduced_genome_lengths[c]).collect();
+            final_matrix = subset_matrix_columns(&final_matrix, &keep);
+        }
+        println!("Merged {} taxa with identical columns into {} representatives.",
+            merged_taxa.values().map(Vec::len).sum::<usize>(), merged_taxa.len());
+    }
+
+    // 6. Reads without any surviving alignment can only be explained by Unknown
+    let empty_before = count_empty_rows(&csr);
+    let empty_after = count_empty_rows(&final_matrix);
+    println!(
+        "Reads with no surviving alignments: {} ({} unaligned or filtered during parsing, {} lost in the post-EM filter)",
+        empty_after, empty_before, empty_after - empty_before
+    );
+
+    // 6b. Host-only and excluded-only reads leave the matrix instead of inflating Unknown
+    let mut separated_rows: HashSet<usize> = HashSet::new();
+    let separated_reads = config.separate_host.as_ref().map(|host_taxa| {
+        let host_cols: HashSet<usize> = new_to_old_map.iter().enumerate()
+            .filter(|&(_, &old)| host_taxa.contains(&parser.index_to_id[old]))
+            .map(|(col, _)| col)
+            .collect();
+        let host_only: Vec<usize> = csr.row_iter().enumerate()
+            .filter(|(_, row)| row.nnz() > 0 && row.col_indices().iter().all(|c| host_cols.contains(c)))
+            .map(|(idx, _)| idx)
+            .collect();
+        separated_rows.extend(&host_only);
+        separated_rows.extend(&parser.excluded_only_reads);
+        let counts = SeparatedReads { host_only: host_only.len(), excluded_only: parser.excluded_only_reads.len() };
+        println!("Separated {} host-only and {} excluded-only reads from the matrix.", counts.host_only, counts.excluded_only);
+        counts
+    });
+
+    let drop_empty = drop_empty_reads && empty_after > 0;
+    let kept_reads = if drop_empty || !separated_rows.is_empty() {
+        let kept: Vec<usize> = final_matrix.row_iter()
+            .enumerate()
+            .filter(|(idx, row)| !separated_rows.contains(idx) && (!drop_empty || row.nnz() > 0))
+            .map(|(idx, _)| idx)
+            .collect();
+        final_matrix = subset_matrix_rows(&final_matrix, &kept);
+        if drop_empty {
+            println!("Dropped {} empty reads; {} reads remain.", empty_after, kept.len());
+        }
+        Some(kept)
+    } else {
+        None
+    };
+
+    let mut column_support = vec![0usize; final_matrix.ncols()];
+    for &col in final_matrix.col_indices() {
+        column_support[col] += 1;
+    }
 
     Ok(Step2Result {
         matrix: final_matrix,
         reduced_taxons,
         reduced_abundances,
+        reduced_genome_lengths,
+        kept_reads,
+        merged_taxa,
+        column_support,
+        separated_reads,
     })
 }
 
+/// Groups columns with identical nonzero rows and values, in column order.
+/// Each group lists its column indices, the first being the representative.
+/// Empty columns are never merged.
+fn identical_column_groups(matrix: &CsrMatrix<f64>) -> Vec<Vec<usize>> {
+    let columns = matrix.transpose();
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut seen: HashMap<(Vec<usize>, Vec<u64>), usize> = HashMap::new();
+    for (col_idx, col) in columns.row_iter().enumerate() {
+        if col.nnz() > 0 {
+            let key = (col.col_indices().to_vec(), col.values().iter().map(|v| v.to_bits()).collect());
+            if let Some(&group) = seen.get(&key) {
+                groups[group].push(col_idx);
+                continue;
+            }
+            seen.insert(key, groups.len());
+        }
+        groups.push(vec![col_idx]);
+    }
+    groups
+}
+
+/// Writes the log-probability matrix in MatrixMarket coordinate format (1-based),
+/// plus `<path>.rows` (read name per row) and `<path>.cols` (taxon ID per column).
+pub fn export_matrix_mtx(path: &str, matrix: &CsrMatrix<f64>, read_names: &[String], taxons: &[String]) -> Result<()> {
+    println!("Exporting matrix ({} x {}, {} entries) to: {}", matrix.nrows(), matrix.ncols(), matrix.nnz(), path);
+    let mut file = BufWriter::new(File::create(path).with_context(|| format!("Failed to create {}", path))?);
+    writeln!(file, "%%MatrixMarket matrix coordinate real general")?;
+    writeln!(file, "% rows: reads ({}.rows), columns: taxa ({}.cols), values: log-probabilities", path, path)?;
+    writeln!(file, "{} {} {}", matrix.nrows(), matrix.ncols(), matrix.nnz())?;
+    for (row_idx, row_vec) in matrix.row_iter().enumerate() {
+        for (col_idx, &val) in row_vec.col_indices().iter().zip(row_vec.values()) {
+            writeln!(file, "{} {} {}", row_idx + 1, col_idx + 1, val)?;
+        }
+    }
+    file.flush()?;
+
+    let mut rows = BufWriter::new(File::create(format!("{}.rows", path))?);
+    for name in read_names { writeln!(rows, "{}", name)?; }
+    rows.flush()?;
+    let mut cols = BufWriter::new(File::create(format!("{}.cols", path))?);
+    for tid in taxons { writeln!(cols, "{}", tid)?; }
+    cols.flush()?;
+    Ok(())
+}
+
+/// Writes one row per retained taxon: reads aligned to it (column nonzeros), step 2 EM
+/// abundance, and the effective count `round(abundance * num_reads)` compared against `read_cutoff`
+pub fn export_column_support(path: &str, result: &Step2Result, num_reads: usize, read_cutoff: usize) -> Result<()> {
+    println!("Exporting column support to: {}", path);
+    let mut file = BufWriter::new(File::create(path).with_context(|| format!("Failed to create {}", path))?);
+    writeln!(file, "# EM_Read_Cutoff: {}", read_cutoff)?;
+    writeln!(file, "TaxonID\tSupportReads\tEMAbundance\tEffectiveCount")?;
+    for (col, tid) in result.reduced_taxons.iter().enumerate() {
+        let abundance = result.reduced_abundances[col];
+        writeln!(file, "{}\t{}\t{:.6}\t{:.0}", tid, result.column_support[col], abundance, (abundance * num_reads as f64).round())?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
 // Helper: Efficiently create a new matrix containing only specific columns
 fn subset_matrix_columns(input: &CsrMatrix<f64>, keep_cols: &[usize]) -> CsrMatrix<f64> {
     let num_rows = input.nrows();
@@ -160,57 +591,245 @@ fn subset_matrix_columns(input: &CsrMatrix<f64>, keep_cols: &[usize]) -> CsrMatr
     CsrMatrix::from(&coo)
 }
 
-fn run_em(matrix: &CsrMatrix<f64>, iterations: usize, verbose: bool) -> (Vec<f64>, usize) {
-    let _num_reads = matrix.nrows();
+/// Reads with no alignment left in the matrix
+fn count_empty_rows(matrix: &CsrMatrix<f64>) -> usize {
+    matrix.row_iter().filter(|row| row.nnz() == 0).count()
+}
+
+/// Builds the read x species log-probability matrix in two streaming passes (row sizes are
+/// already counted), so spilled entries are never all in memory next to the matrix.
+/// Duplicate read-taxon pairs (spilled entries are not merged during parsing) keep the best score.
+fn build_log_matrix(
+    entries: &mut MatrixEntries,
+    row_counts: &[usize],
+    old_to_new_map: &HashMap<usize, usize>,
+    ncols: usize,
+) -> Result<CsrMatrix<f64>> {
+    let mut offsets = Vec::with_capacity(row_counts.len() + 1);
+    offsets.push(0);
+    for &count in row_counts {
+        offsets.push(offsets.last().unwrap() + count);
+    }
+    let nnz = *offsets.last().unwrap();
+    let mut cols = vec![0usize; nnz];
+    let mut vals = vec![0.0; nnz];
+    let mut fill = offsets[..row_counts.len()].to_vec();
+    entries.for_each(|e| {
+        let pos = fill[e.read_idx];
+        cols[pos] = old_to_new_map[&e.taxon_idx];
+        vals[pos] = e.log_prob;
+        fill[e.read_idx] += 1;
+    })?;
+
+    // Sort each row by column and merge duplicates in place
+    let mut write = 0;
+    let mut new_offsets = Vec::with_capacity(offsets.len());
+    new_offsets.push(0);
+    for row in 0..row_counts.len() {
+        let mut pairs: Vec<(usize, f64)> = (offsets[row]..offsets[row + 1]).map(|i| (cols[i], vals[i])).collect();
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0).then(b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal)));
+        pairs.dedup_by_key(|p| p.0);
+        for (col, val) in pairs {
+            cols[write] = col;
+            vals[write] = val;
+            write += 1;
+        }
+        new_offsets.push(write);
+    }
+    cols.truncate(write);
+    vals.truncate(write);
+
+    CsrMatrix::try_from_csr_data(row_counts.len(), ncols, new_offsets, cols, vals)
+        .map_err(|e| anyhow!("Failed to build the alignment matrix: {}", e))
+}
+
+// Helper: Keep only the given rows, in order
+fn subset_matrix_rows(input: &CsrMatrix<f64>, kept: &[usize]) -> CsrMatrix<f64> {
+    let mut coo = CooMatrix::new(kept.len(), input.ncols());
+    for (new_row, &old_row) in kept.iter().enumerate() {
+        let row_vec = input.row(old_row);
+        for (&col_idx, &val) in row_vec.col_indices().iter().zip(row_vec.values()) {
+            coo.push(new_row, col_idx, val);
+        }
+    }
+
+    CsrMatrix::from(&coo)
+}
+
+/// One EM update: writes the new abundances into `next` and returns the
+/// log-likelihood of the current `abundances`
+fn em_step(matrix: &CsrMatrix<f64>, abundances: &[f64], next: &mut [f64]) -> f64 {
+    next.fill(0.0);
+    let mut total_log_l = 0.0;
+
+    for row in matrix.row_iter() {
+        let mut max_val = -f64::INFINITY;
+        for (col_idx, &log_p) in row.col_indices().iter().zip(row.values()) {
+            let term = log_p + abundances[*col_idx].ln();
+            if term > max_val { max_val = term; }
+        }
+        
+        let mut sum_exp = 0.0;
+        for (col_idx, &log_p) in row.col_indices().iter().zip(row.values()) {
+            let term = log_p + abundances[*col_idx].ln();
+            sum_exp += (term - max_val).exp();
+        }
+        
+        let log_l_i = max_val + sum_exp.ln();
+        total_log_l += log_l_i;
+
+        for (col_idx, &log_p) in row.col_indices().iter().zip(row.values()) {
+            let log_numerator = log_p + abundances[*col_idx].ln();
+            let z_ij = (log_numerator - log_l_i).exp();
+            next[*col_idx] += z_ij;
+        }
+    }
+
+    let total_weight: f64 = next.iter().sum();
+    for val in next.iter_mut() {
+        *val /= total_weight;
+    }
+    total_log_l
+}
+
+fn l1_diff(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| (x - y).abs()).sum()
+}
+
+/// Returns (abundances, EM evaluations used, whether the stopping criterion was met)
+fn run_em(matrix: &CsrMatrix<f64>, iterations: usize, verbose: bool, accelerate: bool, stop: &mut EmStop) -> (Vec<f64>, usize, bool) {
+    if accelerate {
+        return run_em_squarem(matrix, iterations, verbose, stop);
+    }
+
     let num_species = matrix.ncols();
     
     let mut abundances = vec![1.0 / num_species as f64; num_species];
     let mut next_abundances = vec![0.0; num_species];
 
     for iter in 0..iterations {
-        next_abundances.fill(0.0);
-        let mut total_log_l = 0.0;
-        let mut diff = 0.0;
-
-        for row in matrix.row_iter() {
-            let mut max_val = -f64::INFINITY;
-            for (col_idx, &log_p) in row.col_indices().iter().zip(row.values()) {
-                let term = log_p + abundances[*col_idx].ln();
-                if term > max_val { max_val = term; }
-            }
-            
-            let mut sum_exp = 0.0;
-            for (col_idx, &log_p) in row.col_indices().iter().zip(row.values()) {
-                let term = log_p + abundances[*col_idx].ln();
-                sum_exp += (term - max_val).exp();
-            }
-            
-            let log_l_i = max_val + sum_exp.ln();
-            total_log_l += log_l_i;
+        let total_log_l = em_step(matrix, &abundances, &mut next_abundances);
+        let diff = l1_diff(&next_abundances, &abundances);
 
-            for (col_idx, &log_p) in row.col_indices().iter().zip(row.values()) {
-                let log_numerator = log_p + abundances[*col_idx].ln();
-                let z_ij = (log_numerator - log_l_i).exp();
-                next_abundances[*col_idx] += z_ij;
-            }
+        abundances.copy_from_slice(&next_abundances);
+
+        if verbose && (iter % 10 == 0 || iter == iterations - 1) {
+            println!("EM Iter: {} | LogL: {:.2} | Diff: {:.6e}", iter, total_log_l, diff);
         }
 
-        let total_weight: f64 = next_abundances.iter().sum();
-        for (j, val) in next_abundances.iter_mut().enumerate() {
-            *val /= total_weight;
-            diff += (*val - abundances[j]).abs();
+        if stop.converged(diff, total_log_l) {
+            return (abundances, iter + 1, true);
         }
+    }
 
-        abundances.copy_from_slice(&next_abundances);
+    (abundances, iterations, false)
+}
 
-        if verbose && (iter % 10 == 0 || iter == iterations - 1) {
-            println!("EM Iter: {} | LogL: {:.2} | Diff: {:.6e}", iter, total_log_l, diff);
+/// SQUAREM (Varadhan & Roland 2008, scheme S3). Each cycle takes two EM steps
+/// θ1 = F(θ0), θ2 = F(θ1), extrapolates θ' = θ0 - 2αr + α²v with r = θ1 - θ0,
+/// v = θ2 - 2θ1 + θ0, α = -|r|/|v|, then applies one stabilizing EM step.
+/// α is halved towards -1 (a plain double step) until θ' is strictly positive,
+/// and a cycle that lowers the likelihood falls back to θ2.
+/// Returns the number of EM evaluations used, which count towards `iterations`; those left
+/// for less than a full cycle run as plain EM steps.
+fn run_em_squarem(matrix: &CsrMatrix<f64>, iterations: usize, verbose: bool, stop: &mut EmStop) -> (Vec<f64>, usize, bool) {
+    let num_species = matrix.ncols();
+
+    let mut theta0 = vec![1.0 / num_species as f64; num_species];
+    let mut theta1 = vec![0.0; num_species];
+    let mut theta2 = vec![0.0; num_species];
+    let mut extrapolated = vec![0.0; num_species];
+    let mut stabilized = vec![0.0; num_species];
+    let mut evals = 0;
+    let mut cycle = 0;
+
+    while evals + 3 <= iterations {
+        em_step(matrix, &theta0, &mut theta1);
+        let log_l1 = em_step(matrix, &theta1, &mut theta2);
+        evals += 2;
+
+        let r: Vec<f64> = theta1.iter().zip(&theta0).map(|(a, b)| a - b).collect();
+        let v: Vec<f64> = theta2.iter().zip(&theta1).zip(&r).map(|((b, a), r)| b - a - r).collect();
+        let r_norm = r.iter().map(|x| x * x).sum::<f64>().sqrt();
+        let v_norm = v.iter().map(|x| x * x).sum::<f64>().sqrt();
+
+        if r_norm < 1e-12 || v_norm < 1e-12 {
+            theta0.copy_from_slice(&theta2);
+            return (theta0, evals, true);
+        }
+
+        let mut alpha = (-r_norm / v_norm).min(-1.0);
+        let mut backtracked = false;
+        loop {
+            for j in 0..num_species {
+                extrapolated[j] = theta0[j] - 2.0 * alpha * r[j] + alpha * alpha * v[j];
+            }
+            if alpha >= -1.0 || extrapolated.iter().all(|&x| x > 0.0) { break; }
+            alpha = ((alpha - 1.0) / 2.0).max(-1.0);
+            backtracked = true;
+        }
+        if alpha >= -1.0 {
+            // α = -1 reproduces the plain double step
+            extrapolated.copy_from_slice(&theta2);
+        }
+
+        let log_l = em_step(matrix, &extrapolated, &mut stabilized);
+        evals += 1;
+
+        let fell_back = log_l < log_l1;
+        if fell_back {
+            stabilized.copy_from_slice(&theta2);
+        }
+        let diff = l1_diff(&stabilized, &theta0);
+        theta0.copy_from_slice(&stabilized);
+
+        if verbose && cycle % 10 == 0 {
+            println!(
+                "EM (SQUAREM) Cycle: {} | Evals: {} | LogL: {:.2} | Alpha: {:.2}{} | Diff: {:.6e}",
+                cycle, evals, log_l.max(log_l1), alpha,
+                if fell_back { " (fallback)" } else if backtracked { " (backtracked)" } else { "" },
+                diff
+            );
         }
+        cycle += 1;
 
-        if diff < 1e-6 {
-            return (abundances, iter + 1);
+        if stop.converged(diff, log_l.max(log_l1)) {
+            return (theta0, evals, true);
         }
     }
 
-    (abundances, iterations)
-}
\ No newline at end of file
+    // Evaluations left over for less than a full cycle (all of them when `iterations` < 3)
+    // go to plain EM steps, so every requested evaluation is used
+    while evals < iterations {
+        let log_l = em_step(matrix, &theta0, &mut theta1);
+        evals += 1;
+        let diff = l1_diff(&theta1, &theta0);
+        theta0.copy_from_slice(&theta1);
+        if stop.converged(diff, log_l) {
+            return (theta0, evals, true);
+        }
+    }
+
+    (theta0, evals, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn squarem_runs_plain_em_steps_below_a_full_cycle() {
+        let mut coo = CooMatrix::new(4, 2);
+        for (r, c, p) in [(0, 0, 0.9), (1, 0, 0.5), (1, 1, 0.5), (2, 1, 0.2), (3, 0, 0.3), (3, 1, 0.6)] {
+            coo.push(r, c, p);
+        }
+        let matrix = CsrMatrix::from(&coo);
+        for iterations in 1..3 {
+            let plain = run_em(&matrix, iterations, false, false, &mut EmStop::new(EmTolType::Abs));
+            let squarem = run_em(&matrix, iterations, false, true, &mut EmStop::new(EmTolType::Abs));
+            assert_eq!(squarem.1, iterations);
+            assert_eq!(squarem.0, plain.0);
+            assert_ne!(squarem.0, vec![0.5, 0.5]);
+        }
+    }
+}
@@ -1,11 +1,18 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use nalgebra_sparse::csr::CsrMatrix;
 use rand::prelude::*;
 use rand::distr::weighted::WeightedIndex;
 use rand_distr::{Gamma, Distribution};
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, Barrier, RwLock};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, PoisonError, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
 // ================================================================================================
 // PART 1: DATA STRUCTURES & MINI-EM
@@ -17,12 +24,114 @@ pub struct McmcContext {
     pub read_weights: Vec<f64>,
     pub taxons: Vec<String>,
     pub taxon_weights: Vec<f64>,
+    pub genome_lengths: Vec<f64>,
     pub median_genome_len: f64,
     pub read_support: usize,
     pub lpenalty: f64,
+    pub unk_config: UnknownBinConfig,
+    pub proposal: ProposalInit,
+    /// Log-prior adjustment per column, added on top of `lpenalty` when the species is present
+    pub presence_prior: HashMap<usize, f64>,
+    /// Reads supporting each column; when set, each species' penalty is scaled by
+    /// `read_support / support` (see `species_penalty`)
+    pub adaptive_support: Option<Vec<usize>>,
+    /// Sharpen the add-proposal weights early in the run (see `add_weight_power`)
+    pub anneal: Option<ProposalAnneal>,
+    /// Hard cap on the species set size; add moves are not proposed at the cap
+    pub max_species: Option<usize>,
+    /// Per-column add-proposal weights used instead of `taxon_weights` (see `add_weight`)
+    pub add_weights: Option<Vec<f64>>,
 }
 
-#[derive(Clone)]
+/// Upper bound on the adaptive penalty scale, reached by columns with very little support
+const ADAPTIVE_PENALTY_MAX_SCALE: f64 = 10.0;
+
+/// How starting abundances for a proposed species set are drawn before the
+/// mini-EM refines them. This only changes where the optimisation starts, not
+/// the target distribution of the sampler.
+#[derive(Clone, Copy, Debug)]
+pub struct ProposalInit {
+    /// Gamma shape of the symmetric Dirichlet draw (1.0 = flat over the simplex,
+    /// larger = closer to uniform abundances, smaller = sparser)
+    pub concentration: f64,
+    /// If set, mix this weight of the Dirichlet draw into the current state's
+    /// abundances instead of starting from the draw alone
+    pub warm_start: Option<f64>,
+}
+
+impl Default for ProposalInit {
+    fn default() -> Self {
+        Self { concentration: 1.0, warm_start: None }
+    }
+}
+
+/// Early-run sharpening of the add proposal: taxon weights are raised to `power`
+/// at iteration 0, relaxing linearly to the plain weights at `until_iter`.
+/// Forward and reverse moves use the same exponent, so each step still satisfies
+/// detailed balance for its own kernel and the chain is exact once annealing ends.
+#[derive(Clone, Copy, Debug)]
+pub struct ProposalAnneal {
+    pub power: f64,
+    pub until_iter: usize,
+}
+
+impl ProposalInit {
+    /// Starting abundances over `species`. `previous` supplies the warm-start
+    /// base; species it lacks start at 1/n.
+    pub fn sample(&self, species: &[usize], previous: Option<&HashMap<usize, f64>>, rng: &mut impl Rng) -> HashMap<usize, f64> {
+        let n = species.len();
+        if n == 0 {
+            return HashMap::new();
+        }
+
+        let gamma = Gamma::new(self.concentration, 1.0).unwrap();
+        let draws: Vec<f64> = species.iter().map(|_| gamma.sample(rng)).collect();
+        let draw_sum: f64 = draws.iter().sum();
+
+        match (self.warm_start, previous) {
+            (Some(noise), Some(prev)) => {
+                let base: Vec<f64> = species.iter().map(|id| prev.get(id).copied().unwrap_or(1.0 / n as f64)).collect();
+                let base_sum: f64 = base.iter().sum();
+                species.iter().zip(base.iter().zip(&draws))
+                    .map(|(&id, (&b, &d))| (id, (1.0 - noise) * b / base_sum + noise * d / draw_sum))
+                    .collect()
+            }
+            _ => species.iter().zip(&draws).map(|(&id, &d)| (id, d / draw_sum)).collect(),
+        }
+    }
+}
+
+/// Controls the adaptive Unknown-bin probability learned inside the mini-EM.
+/// Each iteration proposes `median(P(read | known)) * floor_factor`, moves the
+/// current probability `smoothing` of the way towards it, and clamps the result
+/// into `[prob_min, prob_max]`. A higher ceiling or factor
+/// lets more poorly-aligned reads fall into Unknown, inflating its abundance;
+/// lowering them forces reads onto known taxa. Calibrate against a negative
+/// control by checking the reported Unknown abundance.
+#[derive(Clone, Copy, Debug)]
+pub struct UnknownBinConfig {
+    pub prob_min: f64,
+    pub prob_max: f64,
+    pub floor_factor: f64,
+    /// Weight of the proposed floor in each update (1.0 = jump straight to it)
+    pub smoothing: f64,
+    /// The floor is only updated when more than this many reads align to the current set
+    pub min_reads: usize,
+}
+
+impl Default for UnknownBinConfig {
+    fn default() -> Self {
+        Self { prob_min: 1e-300, prob_max: 1e-5, floor_factor: 1e-12, smoothing: 0.2, min_reads: 10 }
+    }
+}
+
+impl UnknownBinConfig {
+    pub fn clamp(&self, p: f64) -> f64 {
+        p.clamp(self.prob_min, self.prob_max)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 #[allow(dead_code)]
 pub struct ChainState {
     pub id: usize,
@@ -35,15 +144,223 @@ pub struct ChainState {
     pub moves_accepted: usize,
     pub swaps_attempted: usize,
     pub swaps_accepted: usize,
+    /// Within-chain moves broken down by type (species swap = remove one, add another)
+    #[serde(default)]
+    pub add_moves: MoveCounter,
+    #[serde(default)]
+    pub remove_moves: MoveCounter,
+    #[serde(default)]
+    pub swap_moves: MoveCounter,
+    /// Completed iterations when this chain's species set last changed (by a move or a swap)
+    #[serde(default)]
+    pub membership_changed_at: usize,
+    /// Kept out of the checkpoint itself; see `HistoryLog`
+    #[serde(skip)]
     pub history: Vec<ChainRecord>,
 }
 
-#[derive(Clone)]
+/// Attempted / accepted counts for one move type
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct MoveCounter {
+    pub attempted: usize,
+    pub accepted: usize,
+}
+
+impl MoveCounter {
+    pub fn rate(&self) -> f64 {
+        if self.attempted > 0 { self.accepted as f64 / self.attempted as f64 } else { 0.0 }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 #[allow(dead_code)]
 pub struct ChainRecord {
     pub iter: usize,
     pub log_likelihood: f64,
     pub move_type: String,
+    /// Sorted species membership after this step; recorded for cold (temperature 1.0)
+    /// chains only, the only ones whose membership is read, and empty otherwise
+    #[serde(default)]
+    pub species: Vec<usize>,
+}
+
+impl ChainState {
+    fn move_counter(&mut self, move_type: &MoveType) -> Option<&mut MoveCounter> {
+        match move_type {
+            MoveType::Add(_) => Some(&mut self.add_moves),
+            MoveType::Remove(_) => Some(&mut self.remove_moves),
+            MoveType::Swap(_, _) => Some(&mut self.swap_moves),
+            MoveType::None => None,
+        }
+    }
+
+    pub fn sorted_species(&self) -> Vec<usize> {
+        let mut v: Vec<usize> = self.species_set.iter().cloned().collect();
+        v.sort_unstable();
+        v
+    }
+
+    /// Membership for a history record: the sorted species of a cold chain, else empty,
+    /// so hot chains' history does not grow with the set size
+    fn record_species(&self) -> Vec<usize> {
+        if self.temperature >= 1.0 { self.sorted_species() } else { Vec::new() }
+    }
+}
+
+// ================================================================================================
+// CHECKPOINTING
+// ================================================================================================
+
+pub const CHECKPOINT_VERSION: u32 = 3;
+
+/// Snapshot of all chains after `iteration` completed iterations
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub version: u32,
+    pub iteration: usize,
+    pub chains: Vec<ChainState>,
+    /// Taxon ID of each matrix column, so the chains can be read against a different input
+    /// (empty in checkpoints written before this was recorded)
+    #[serde(default)]
+    pub taxons: Vec<String>,
+    /// Length of the history log (`history_path`) that belongs to this checkpoint; anything
+    /// past it was appended for a later checkpoint that was never completed
+    pub history_bytes: u64,
+}
+
+/// `Checkpoint` as written, borrowing the chains instead of cloning them
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    version: u32,
+    iteration: usize,
+    chains: &'a [&'a ChainState],
+    taxons: &'a [String],
+    history_bytes: u64,
+}
+
+/// Chain history log kept next to a checkpoint: one `[chain index, record]` JSON line per record
+pub fn history_path(checkpoint_path: &str) -> String {
+    format!("{}.history", checkpoint_path)
+}
+
+/// Appends chain history to the log at each checkpoint, so a checkpoint writes only the
+/// records since the previous one rather than every chain's full history
+struct HistoryLog {
+    file: File,
+    /// Records of each chain already in the log
+    written: Vec<usize>,
+}
+
+impl HistoryLog {
+    /// Starts a log holding the chains' current history (non-empty when resuming)
+    fn create(checkpoint_path: &str, chains: &[ChainState]) -> Result<Self> {
+        let path = history_path(checkpoint_path);
+        let file = File::create(&path).with_context(|| format!("Failed to create history log {}", path))?;
+        let mut log = Self { file, written: vec![0; chains.len()] };
+        log.append(&chains.iter().collect::<Vec<_>>())?;
+        Ok(log)
+    }
+
+    /// Appends the records written since the last call and returns the log's length once on disk
+    fn append(&mut self, chains: &[&ChainState]) -> Result<u64> {
+        let mut writer = BufWriter::new(&self.file);
+        for (i, chain) in chains.iter().enumerate() {
+            for record in &chain.history[self.written[i]..] {
+                serde_json::to_writer(&mut writer, &(i, record))?;
+                writer.write_all(b"\n")?;
+            }
+            self.written[i] = chain.history.len();
+        }
+        writer.flush().context("Failed to write history log")?;
+        drop(writer);
+        self.file.sync_all().context("Failed to write history log")?;
+        Ok(self.file.metadata()?.len())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CheckpointConfig {
+    pub path: String,
+    /// Write a checkpoint every N exchange blocks
+    pub every_blocks: usize,
+}
+
+/// Run-level settings for `run_mcmc_parallel`
+#[derive(Clone, Debug)]
+pub struct McmcRunConfig {
+    /// Iteration to start from (non-zero when resuming from a checkpoint)
+    pub start_iter: usize,
+    pub total_iter: usize,
+    pub exchange_interval: usize,
+    /// Lengthen the exchange interval while swaps are mostly accepted and shorten it
+    /// when they are mostly rejected (see `adapt_exchange_interval`)
+    pub adaptive_exchange: bool,
+    /// Stop once the cold chain's species set is unchanged for this many iterations
+    pub autostop_window: Option<usize>,
+    pub checkpoint: Option<CheckpointConfig>,
+    /// Adapt the temperature ladder during the first N iterations
+    pub auto_temp_warmup: Option<usize>,
+    /// Record chain history only every N iterations
+    pub thin: usize,
+    /// Stop all chains once the MCMC phase has run this long
+    pub max_runtime: Option<Duration>,
+    /// Run seed; each chain and the swap step draw from their own stream derived from it
+    pub seed: u64,
+    pub verbose: bool,
+}
+
+/// Writes the chain states; their history must already be in the log, up to `history_bytes`
+fn save_checkpoint(path: &str, iteration: usize, chains: &[&ChainState], taxons: &[String], history_bytes: u64) -> Result<()> {
+    let checkpoint = CheckpointRef { version: CHECKPOINT_VERSION, iteration, chains, taxons, history_bytes };
+    // Write to a temp file first so a crash mid-write keeps the previous checkpoint
+    let tmp_path = format!("{}.tmp", path);
+    let mut writer = BufWriter::new(File::create(&tmp_path).context("Failed to create checkpoint file")?);
+    serde_json::to_writer(&mut writer, &checkpoint)?;
+    // Only a checkpoint known to be complete on disk may replace the previous one
+    let file = writer.into_inner().map_err(|e| e.into_error()).context("Failed to write checkpoint file")?;
+    file.sync_all().context("Failed to write checkpoint file")?;
+    std::fs::rename(&tmp_path, path).context("Failed to finalize checkpoint file")?;
+    Ok(())
+}
+
+/// Just the version of a checkpoint, read before the layout it decides
+#[derive(Deserialize)]
+struct CheckpointVersion {
+    version: u32,
+}
+
+pub fn load_checkpoint(path: &str) -> Result<Checkpoint> {
+    let text = std::fs::read_to_string(path).context("Failed to open checkpoint file")?;
+    let CheckpointVersion { version } = serde_json::from_str(&text).context("Failed to parse checkpoint file")?;
+    if version != CHECKPOINT_VERSION {
+        bail!("Checkpoint version {} is not supported (expected {}); it was written by another version of this tool",
+            version, CHECKPOINT_VERSION);
+    }
+    let mut checkpoint: Checkpoint = serde_json::from_str(&text).context("Failed to parse checkpoint file")?;
+
+    if checkpoint.history_bytes > 0 {
+        let log_path = history_path(path);
+        let file = File::open(&log_path).with_context(|| format!("Failed to open history log {}", log_path))?;
+        for line in BufReader::new(file.take(checkpoint.history_bytes)).lines() {
+            let (i, record): (usize, ChainRecord) = serde_json::from_str(&line?)
+                .with_context(|| format!("Failed to parse history log {}", log_path))?;
+            let num_chains = checkpoint.chains.len();
+            checkpoint.chains.get_mut(i)
+                .with_context(|| format!("History log {} refers to chain {} of {}", log_path, i, num_chains))?
+                .history.push(record);
+        }
+    }
+    Ok(checkpoint)
+}
+
+/// Inputs of the presence penalty: `lpenalty = l_null - l_one`, with `l_null` all reads
+/// Unknown at `p_unknown_penalty_ref` and `l_one` with `read_support` reads moved to a
+/// species of `median_genome_len`
+#[derive(Clone, Copy, Debug)]
+pub struct PenaltyConfig {
+    pub median_genome_len: f64,
+    pub read_support: usize,
+    pub p_unknown_penalty_ref: f64,
 }
 
 impl McmcContext {
@@ -52,10 +369,11 @@ impl McmcContext {
         read_weights: Vec<f64>,
         taxons: Vec<String>,
         taxon_weights: Vec<f64>,
-        median_genome_len: f64,
-        read_support: usize,
-        p_unknown_penalty_ref: f64, 
+        genome_lengths: Vec<f64>,
+        penalty: PenaltyConfig,
+        unk_config: UnknownBinConfig,
     ) -> Self {
+        let PenaltyConfig { median_genome_len, read_support, p_unknown_penalty_ref } = penalty;
         println!("Step 3: Converting Matrix to Linear Space for MCMC...");
         
         let mut linear_matrix = log_matrix.clone();
@@ -86,11 +404,106 @@ impl McmcContext {
             read_weights,
             taxons,
             taxon_weights,
+            genome_lengths,
             median_genome_len,
             read_support,
             lpenalty,
+            unk_config,
+            proposal: ProposalInit::default(),
+            presence_prior: HashMap::new(),
+            adaptive_support: None,
+            anneal: None,
+            max_species: None,
+            add_weights: None,
         }
     }
+
+    /// Exponent applied to the add-proposal weights at `iter` (1.0 once annealing is over or disabled)
+    pub fn add_weight_power(&self, iter: usize) -> f64 {
+        match self.anneal {
+            Some(a) if iter < a.until_iter => {
+                let progress = iter as f64 / a.until_iter as f64;
+                1.0 + (a.power - 1.0) * (1.0 - progress)
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// Unnormalized weight of column `sp` in add proposals
+    pub fn add_weight(&self, sp: usize) -> f64 {
+        self.add_weights.as_ref().map_or(self.taxon_weights[sp], |w| w[sp])
+    }
+
+    /// Presence penalty of one species: `lpenalty` plus its prior adjustment, if any.
+    /// With adaptive support, `lpenalty` is scaled by `read_support / support` (capped), so
+    /// species backed by more reads than the threshold pay less and weaker ones pay more.
+    pub fn species_penalty(&self, sp: usize) -> f64 {
+        let base = match &self.adaptive_support {
+            Some(support) => {
+                let scale = self.read_support as f64 / support[sp].max(1) as f64;
+                self.lpenalty * scale.min(ADAPTIVE_PENALTY_MAX_SCALE)
+            }
+            None => self.lpenalty,
+        };
+        base + self.presence_prior.get(&sp).copied().unwrap_or(0.0)
+    }
+
+    /// Total presence penalty of a species set
+    pub fn set_penalty(&self, species_set: &HashSet<usize>) -> f64 {
+        species_set.iter().map(|&sp| self.species_penalty(sp)).sum()
+    }
+}
+
+/// A single temperature-1.0 state for a known species set, fitted by mini-EM
+/// from uniform abundances. Used in place of the MCMC output when the species
+/// set is fixed; its empty history makes every species' inclusion 1.0.
+pub fn fixed_species_state(ctx: &McmcContext, species_set: HashSet<usize>, em_iterations: usize) -> ChainState {
+    let uniform = 1.0 / species_set.len() as f64;
+    let init: HashMap<usize, f64> = species_set.iter().map(|&sp| (sp, uniform)).collect();
+    fitted_state(ctx, species_set, &init, em_iterations)
+}
+
+/// Cold-chain starting state holding every retained taxon, refined by mini-EM from the
+/// step 2 EM abundances (`taxon_weights`) rather than from an empty set
+pub fn em_solution_state(ctx: &McmcContext, em_iterations: usize) -> ChainState {
+    let total: f64 = ctx.taxon_weights.iter().sum();
+    let species_set: HashSet<usize> = (0..ctx.matrix.ncols()).collect();
+    let init: HashMap<usize, f64> = species_set.iter()
+        .map(|&sp| (sp, if total > 0.0 { ctx.taxon_weights[sp] / total } else { 1.0 / species_set.len() as f64 }))
+        .collect();
+    fitted_state(ctx, species_set, &init, em_iterations)
+}
+
+/// Cold-chain starting state holding the species of `init` (e.g. a previous run's
+/// community), refined by mini-EM from those abundances
+pub fn warm_start_state(ctx: &McmcContext, init: &HashMap<usize, f64>, em_iterations: usize) -> ChainState {
+    let species_set: HashSet<usize> = init.keys().cloned().collect();
+    fitted_state(ctx, species_set, init, em_iterations)
+}
+
+/// Chain 0 at temperature 1.0 holding `species_set`, with abundances and penalized
+/// log-likelihood from a mini-EM started at `init`
+fn fitted_state(ctx: &McmcContext, species_set: HashSet<usize>, init: &HashMap<usize, f64>, em_iterations: usize) -> ChainState {
+    let (log_l, abundances, unk_prob) = run_mini_em(ctx, &species_set, init, ctx.unk_config.prob_min, em_iterations);
+    let penalized = log_l + ctx.set_penalty(&species_set);
+
+    ChainState {
+        id: 0,
+        temperature: 1.0,
+        species_set,
+        abundances,
+        current_unk_prob: unk_prob,
+        current_log_likelihood: penalized,
+        moves_attempted: 0,
+        moves_accepted: 0,
+        swaps_attempted: 0,
+        swaps_accepted: 0,
+        add_moves: MoveCounter::default(),
+        remove_moves: MoveCounter::default(),
+        swap_moves: MoveCounter::default(),
+        membership_changed_at: 0,
+        history: Vec::new(),
+    }
 }
 
 pub fn run_mini_em(
@@ -100,9 +513,49 @@ pub fn run_mini_em(
     start_unk_prob: f64,
     iterations: usize,
 ) -> (f64, HashMap<usize, f64>, f64) {
+    mini_em(ctx, species_set, initial_abundances, start_unk_prob, iterations, None, &ctx.read_weights)
+}
+
+/// `run_mini_em` with `read_weights` in place of the context's (e.g. bootstrap resampling)
+pub fn run_mini_em_reweighted(
+    ctx: &McmcContext,
+    species_set: &HashSet<usize>,
+    initial_abundances: &HashMap<usize, f64>,
+    start_unk_prob: f64,
+    iterations: usize,
+    read_weights: &[f64],
+) -> (f64, HashMap<usize, f64>, f64) {
+    mini_em(ctx, species_set, initial_abundances, start_unk_prob, iterations, None, read_weights)
+}
+
+/// `run_mini_em` where the Unknown bin can explain each read aligned to column
+/// `absorbed` as well as that column does: the read's unknown probability is raised
+/// to its alignment probability. Models "this taxon's reads are novel" rather than
+/// "they belong to the remaining species".
+pub fn run_mini_em_absorbing(
+    ctx: &McmcContext,
+    species_set: &HashSet<usize>,
+    initial_abundances: &HashMap<usize, f64>,
+    start_unk_prob: f64,
+    iterations: usize,
+    absorbed: usize,
+) -> (f64, HashMap<usize, f64>, f64) {
+    mini_em(ctx, species_set, initial_abundances, start_unk_prob, iterations, Some(absorbed), &ctx.read_weights)
+}
+
+fn mini_em(
+    ctx: &McmcContext,
+    species_set: &HashSet<usize>,
+    initial_abundances: &HashMap<usize, f64>,
+    start_unk_prob: f64,
+    iterations: usize,
+    absorbed: Option<usize>,
+    read_weights: &[f64],
+) -> (f64, HashMap<usize, f64>, f64) {
     
     let num_reads = ctx.matrix.nrows();
-    let active_indices: Vec<usize> = species_set.iter().cloned().collect();
+    let mut active_indices: Vec<usize> = species_set.iter().cloned().collect();
+    active_indices.sort_unstable(); // Deterministic summation order
     let num_active = active_indices.len();
     
     let mut abundances: Vec<f64> = active_indices.iter()
@@ -128,7 +581,7 @@ pub fn run_mini_em(
         let mut valid_aligns = if iter > 1 { Vec::with_capacity(num_reads / 10) } else { Vec::new() };
 
         for (row_idx, row_vec) in ctx.matrix.row_iter().enumerate() {
-            let read_weight = ctx.read_weights[row_idx];
+            let read_weight = read_weights[row_idx];
             let mut denom_known = 0.0;
             
             for (col_idx, &p_linear) in row_vec.col_indices().iter().zip(row_vec.values()) {
@@ -137,7 +590,10 @@ pub fn run_mini_em(
                 }
             }
             
-            let term_unknown = curr_unk_prob * unk_abundance;
+            let row_unk_prob = absorbed
+                .and_then(|x| row_vec.col_indices().iter().position(|c| *c == x))
+                .map_or(curr_unk_prob, |pos| row_vec.values()[pos].max(curr_unk_prob));
+            let term_unknown = row_unk_prob * unk_abundance;
             let total_denom = denom_known + term_unknown;
             let safe_denom = total_denom + 1e-300; 
 
@@ -157,16 +613,15 @@ pub fn run_mini_em(
             next_unk_abundance += factor * term_unknown;
         }
 
-        if iter > 1 && valid_aligns.len() > 10 {
+        if iter > 1 && valid_aligns.len() > ctx.unk_config.min_reads {
             let mid = valid_aligns.len() / 2;
             valid_aligns.select_nth_unstable_by(mid, |a, b| a.partial_cmp(b).unwrap());
             let median_prob = valid_aligns[mid];
 
-            let proposed_floor = median_prob * 1e-12;
-            curr_unk_prob = (0.8 * curr_unk_prob) + (0.2 * proposed_floor);
-
-            if curr_unk_prob < 1e-300 { curr_unk_prob = 1e-300; }
-            if curr_unk_prob > 1e-5 { curr_unk_prob = 1e-5; }
+            let proposed_floor = median_prob * ctx.unk_config.floor_factor;
+            let smoothing = ctx.unk_config.smoothing;
+            curr_unk_prob = ((1.0 - smoothing) * curr_unk_prob) + (smoothing * proposed_floor);
+            curr_unk_prob = ctx.unk_config.clamp(curr_unk_prob);
         }
 
         let total_weight: f64 = next_abundances.iter().sum::<f64>() + next_unk_abundance;
@@ -212,14 +667,20 @@ pub enum MoveType {
 pub struct McmcLogic;
 
 impl McmcLogic {
-    pub fn get_move_probs(num_present_species: usize, num_total_species: usize) -> MoveProbs {
-        let can_add = num_present_species < num_total_species;
+    /// Move-type probabilities for a set of `num_present_species`. At `max_species` only
+    /// remove and swap moves are proposed (same 2:1 ratio as usual); the reverse-move
+    /// probabilities use the same function, so the chain stays reversible under the cap.
+    pub fn get_move_probs(num_present_species: usize, num_total_species: usize, max_species: Option<usize>) -> MoveProbs {
+        let can_swap = num_present_species < num_total_species;
+        let can_add = can_swap && max_species.is_none_or(|m| num_present_species < m);
         let can_remove = num_present_species > 0;
 
         if !can_remove {
             MoveProbs { add: 1.0, remove: 0.0, swap: 0.0 }
-        } else if !can_add {
+        } else if !can_swap {
             MoveProbs { add: 0.0, remove: 1.0, swap: 0.0 }
+        } else if !can_add {
+            MoveProbs { add: 0.0, remove: 2.0 / 3.0, swap: 1.0 / 3.0 }
         } else {
             MoveProbs { add: 0.4, remove: 0.4, swap: 0.2 }
         }
@@ -228,15 +689,19 @@ impl McmcLogic {
     pub fn pick_add(
         ctx: &McmcContext, 
         current_set: &HashSet<usize>, 
+        power: f64,
         rng: &mut impl Rng
     ) -> Option<(usize, f64)> {
+        // Every column already present (or an empty matrix): nothing to add
+        if current_set.len() >= ctx.matrix.ncols() { return None; }
+
         let mut candidates = Vec::new();
         let mut weights = Vec::new();
         let mut total_weight = 0.0;
 
         for id in 0..ctx.matrix.ncols() {
             if !current_set.contains(&id) {
-                let w = ctx.taxon_weights[id];
+                let w = ctx.add_weight(id).powf(power);
                 candidates.push(id);
                 weights.push(w);
                 total_weight += w;
@@ -244,7 +709,8 @@ impl McmcLogic {
         }
 
         if candidates.is_empty() { return None; }
-        if total_weight == 0.0 { return None; }
+        // All remaining candidates have zero EM weight; WeightedIndex would reject them
+        if !(total_weight > 0.0 && total_weight.is_finite()) { return None; }
 
         let dist = WeightedIndex::new(&weights).ok()?;
         let idx = dist.sample(rng);
@@ -260,9 +726,12 @@ impl McmcLogic {
         let mut candidates = Vec::with_capacity(current_abundances.len());
         let mut raw_inv_weights = Vec::with_capacity(current_abundances.len());
 
-        for (&id, &abund) in current_abundances {
+        // Sorted so the draw depends only on the RNG stream, not on hash order
+        let mut ids: Vec<usize> = current_abundances.keys().cloned().collect();
+        ids.sort_unstable();
+        for id in ids {
             candidates.push(id);
-            let inv = 1.0 / (abund + 1e-300);
+            let inv = 1.0 / (current_abundances[&id] + 1e-300);
             raw_inv_weights.push(inv);
         }
 
@@ -282,7 +751,7 @@ impl McmcLogic {
             total_weight += clamped;
         }
 
-        if total_weight == 0.0 { return None; }
+        if !(total_weight > 0.0 && total_weight.is_finite()) { return None; }
         let dist = WeightedIndex::new(&final_weights).ok()?;
         let idx = dist.sample(rng);
         Some((candidates[idx], final_weights[idx] / total_weight))
@@ -293,14 +762,15 @@ impl McmcLogic {
         move_type: &str, 
         ctx: &McmcContext,
         target_set: &HashSet<usize>,       
-        target_abundances: Option<&HashMap<usize, f64>>
+        target_abundances: Option<&HashMap<usize, f64>>,
+        power: f64,
     ) -> f64 {
         if move_type == "add" {
             let mut total_weight = 0.0;
             let mut my_weight = 0.0;
             for candidate_id in 0..ctx.matrix.ncols() {
                 if !target_set.contains(&candidate_id) {
-                    let w = ctx.taxon_weights[candidate_id];
+                    let w = ctx.add_weight(candidate_id).powf(power);
                     total_weight += w;
                     if candidate_id == id { my_weight = w; }
                 }
@@ -342,22 +812,41 @@ impl McmcLogic {
 // PART 3: ORCHESTRATOR
 // ================================================================================================
 
+/// Below this log acceptance ratio `exp` underflows to zero, so the move is rejected outright
+const MIN_LOG_ACCEPT: f64 = -745.0;
+
+/// Metropolis test on a log acceptance ratio. Large-magnitude (penalized) likelihoods can
+/// make the ratio infinite or NaN (e.g. `inf - inf`): NaN is rejected, +inf accepted and
+/// anything below `MIN_LOG_ACCEPT` rejected without drawing.
+fn metropolis_accept(log_ratio: f64, rng: &mut impl Rng) -> bool {
+    if log_ratio.is_nan() || log_ratio < MIN_LOG_ACCEPT {
+        false
+    } else if log_ratio >= 0.0 {
+        true
+    } else {
+        rng.random::<f64>() < log_ratio.exp()
+    }
+}
+
 pub fn run_chain_step(
     ctx: &McmcContext,
     state: &mut ChainState,
     rng: &mut impl Rng,
     current_iter: usize, // ADDED: Current iteration number
     em_iterations: usize,
+    thin: usize,
     verbose: bool,
 ) {
+    let record = current_iter.is_multiple_of(thin);
     let num_total = ctx.matrix.ncols();
     let num_present = state.species_set.len();
     
-    let move_probs = McmcLogic::get_move_probs(num_present, num_total);
+    let move_probs = McmcLogic::get_move_probs(num_present, num_total, ctx.max_species);
+    let power = ctx.add_weight_power(current_iter);
     let rand_val: f64 = rng.random();
     
     let move_type = if rand_val < move_probs.add {
-        if let Some((id, _)) = McmcLogic::pick_add(ctx, &state.species_set, rng) {
+        if let Some((id, _)) = McmcLogic::pick_add(ctx, &state.species_set, power, rng) {
             MoveType::Add(id)
         } else { MoveType::None }
     } else if rand_val < (move_probs.add + move_probs.remove) {
@@ -368,22 +857,26 @@ pub fn run_chain_step(
         if let Some((rem_id, _)) = McmcLogic::pick_remove(&state.abundances, rng) {
             let mut temp_set = state.species_set.clone();
             temp_set.remove(&rem_id);
-            if let Some((add_id, _)) = McmcLogic::pick_add(ctx, &temp_set, rng) {
+            if let Some((add_id, _)) = McmcLogic::pick_add(ctx, &temp_set, power, rng) {
                 MoveType::Swap(rem_id, add_id)
             } else { MoveType::None }
         } else { MoveType::None }
     };
 
     if let MoveType::None = move_type {
-        state.history.push(ChainRecord {
-            iter: current_iter, 
-            log_likelihood: state.current_log_likelihood * state.temperature,
-            move_type: "None".to_string(),
-        });
+        if record {
+            state.history.push(ChainRecord {
+                iter: current_iter, 
+                log_likelihood: state.current_log_likelihood * state.temperature,
+                move_type: "None".to_string(),
+                species: state.record_species(),
+            });
+        }
         return; 
     }
 
     state.moves_attempted += 1;
+    if let Some(counter) = state.move_counter(&move_type) { counter.attempted += 1; }
 
     let mut next_set = state.species_set.clone();
     match move_type {
@@ -393,24 +886,9 @@ pub fn run_chain_step(
         _ => {}
     }
 
-    let n_species = next_set.len();
-    let mut init_abund = HashMap::new();
-    
-    if n_species > 0 {
-        let gamma = Gamma::new(1.0, 1.0).unwrap(); 
-        let mut samples = Vec::with_capacity(n_species);
-        let mut sum = 0.0;
-        
-        for _ in 0..n_species {
-            let x: f64 = gamma.sample(rng);
-            samples.push(x);
-            sum += x;
-        }
-        
-        for (i, &id) in next_set.iter().enumerate() {
-            init_abund.insert(id, samples[i] / sum);
-        }
-    }
+    let mut next_species: Vec<usize> = next_set.iter().cloned().collect();
+    next_species.sort_unstable();
+    let init_abund = ctx.proposal.sample(&next_species, Some(&state.abundances), rng);
 
     let (new_log_l, new_abundances, new_unk_prob) = run_mini_em(
         ctx, 
@@ -420,37 +898,37 @@ pub fn run_chain_step(
         em_iterations
     );
 
-    let estimator_new = new_log_l + (next_set.len() as f64 * ctx.lpenalty);
+    let estimator_new = new_log_l + ctx.set_penalty(&next_set);
     let current_penalized = state.current_log_likelihood; 
     let new_penalized = estimator_new;
 
     let log_ratio_data = (new_penalized - current_penalized) * state.temperature;
 
-    let move_probs_new = McmcLogic::get_move_probs(next_set.len(), ctx.matrix.ncols());
+    let move_probs_new = McmcLogic::get_move_probs(next_set.len(), ctx.matrix.ncols(), ctx.max_species);
     
     let (log_q_fwd, log_q_rev) = match move_type {
         MoveType::Add(id) => {
-            let pick_add = McmcLogic::get_pick_prob(id, "add", ctx, &state.species_set, None);
-            let pick_rem = McmcLogic::get_pick_prob(id, "remove", ctx, &next_set, Some(&new_abundances));
+            let pick_add = McmcLogic::get_pick_prob(id, "add", ctx, &state.species_set, None, power);
+            let pick_rem = McmcLogic::get_pick_prob(id, "remove", ctx, &next_set, Some(&new_abundances), power);
             
             let fwd = move_probs.add.ln() + pick_add.ln();
             let rev = move_probs_new.remove.ln() + pick_rem.ln();
             (fwd, rev)
         },
         MoveType::Remove(id) => {
-            let pick_rem = McmcLogic::get_pick_prob(id, "remove", ctx, &state.species_set, Some(&state.abundances));
-            let pick_add = McmcLogic::get_pick_prob(id, "add", ctx, &next_set, None);
+            let pick_rem = McmcLogic::get_pick_prob(id, "remove", ctx, &state.species_set, Some(&state.abundances), power);
+            let pick_add = McmcLogic::get_pick_prob(id, "add", ctx, &next_set, None, power);
             
             let fwd = move_probs.remove.ln() + pick_rem.ln();
             let rev = move_probs_new.add.ln() + pick_add.ln();
             (fwd, rev)
         },
         MoveType::Swap(rem, add) => {
-            let pick_rem_fwd = McmcLogic::get_pick_prob(rem, "remove", ctx, &state.species_set, Some(&state.abundances));
-            let pick_add_fwd = McmcLogic::get_pick_prob(add, "add", ctx, &state.species_set, None); 
+            let pick_rem_fwd = McmcLogic::get_pick_prob(rem, "remove", ctx, &state.species_set, Some(&state.abundances), power);
+            let pick_add_fwd = McmcLogic::get_pick_prob(add, "add", ctx, &state.species_set, None, power); 
             
-            let pick_rem_rev = McmcLogic::get_pick_prob(add, "remove", ctx, &next_set, Some(&new_abundances));
-            let pick_add_rev = McmcLogic::get_pick_prob(rem, "add", ctx, &next_set, None);
+            let pick_rem_rev = McmcLogic::get_pick_prob(add, "remove", ctx, &next_set, Some(&new_abundances), power);
+            let pick_add_rev = McmcLogic::get_pick_prob(rem, "add", ctx, &next_set, None, power);
 
             let fwd = move_probs.swap.ln() + pick_rem_fwd.ln() + pick_add_fwd.ln();
             let rev = move_probs_new.swap.ln() + pick_rem_rev.ln() + pick_add_rev.ln();
@@ -461,15 +939,14 @@ pub fn run_chain_step(
 
     let total_log_ratio = log_ratio_data + log_q_rev - log_q_fwd;
 
-    let accept = if total_log_ratio >= 0.0 {
-        true
-    } else {
-        let r: f64 = rng.random();
-        r < total_log_ratio.exp()
-    };
+    let accept = metropolis_accept(total_log_ratio, rng);
 
     if accept {
+        if next_set != state.species_set {
+            state.membership_changed_at = current_iter + 1;
+        }
         state.moves_accepted += 1;
+        if let Some(counter) = state.move_counter(&move_type) { counter.accepted += 1; }
         state.species_set = next_set;
         state.abundances = new_abundances;
         state.current_unk_prob = new_unk_prob;
@@ -491,125 +968,581 @@ pub fn run_chain_step(
             MoveType::Swap(r, a) => format!("Swap({}->{})", r, a),
             _ => "None".to_string(),
         };
-        state.history.push(ChainRecord {
-            iter: current_iter,
-            log_likelihood: new_penalized * state.temperature,
-            move_type: m_str,
-        });
-    } else {
+        if record {
+            state.history.push(ChainRecord {
+                iter: current_iter,
+                log_likelihood: new_penalized * state.temperature,
+                move_type: m_str,
+                species: state.record_species(),
+            });
+        }
+    } else if record {
         state.history.push(ChainRecord {
             iter: current_iter,
             log_likelihood: state.current_log_likelihood * state.temperature,
             move_type: "Reject".to_string(),
+            species: state.record_species(),
         });
     }
 }
 
+/// Target swap acceptance between adjacent chains for `--auto-temp`
+const TARGET_SWAP_RATE: f64 = 0.3;
+/// Number of exchange blocks between ladder adjustments
+const TEMP_TUNE_BLOCKS: usize = 10;
+
+/// Adjusts the gaps between adjacent temperatures (inverse-temperature
+/// multipliers, 1.0 = cold) on a log scale: pairs that swap too often are
+/// spread apart and pairs that rarely swap are pulled together. The step
+/// size decays with the number of adjustments so the ladder settles.
+fn tune_temperatures(
+    chains: &[RwLock<ChainState>],
+    pair_attempts: &[usize],
+    pair_accepts: &[usize],
+    num_tunes: usize,
+) {
+    let temps: Vec<f64> = chains.iter().map(|lock| lock.read().unwrap().temperature).collect();
+    let step = 1.0 / (num_tunes as f64).sqrt();
+
+    let mut new_temps = Vec::with_capacity(temps.len());
+    new_temps.push(temps[0]);
+    for i in 0..pair_attempts.len() {
+        // Extra cold chains (--cold-chains) stay pinned at 1.0
+        if temps[i + 1] >= 1.0 {
+            new_temps.push(temps[i + 1]);
+            continue;
+        }
+        let gap = (temps[i] - temps[i + 1]).max(1e-6);
+        let log_gap = if pair_attempts[i] > 0 {
+            let rate = pair_accepts[i] as f64 / pair_attempts[i] as f64;
+            gap.ln() + step * (rate - TARGET_SWAP_RATE)
+        } else {
+            gap.ln()
+        };
+        let next = (new_temps[i] - log_gap.exp()).max(0.0);
+        new_temps.push(next);
+    }
+
+    for (lock, t) in chains.iter().zip(new_temps) {
+        lock.write().unwrap().temperature = t;
+    }
+}
+
+/// Exchange blocks between adaptive exchange-interval updates
+const EXCHANGE_ADAPT_BLOCKS: usize = 10;
+/// Swap acceptance above which the exchange interval is doubled
+const EXCHANGE_RATE_HIGH: f64 = 0.5;
+/// Swap acceptance below which the exchange interval is halved
+const EXCHANGE_RATE_LOW: f64 = 0.2;
+/// The adaptive interval stays within [1, this x --exchange-interval]
+const EXCHANGE_MAX_FACTOR: usize = 16;
+
+/// Next exchange interval given the swap acceptance rate since the last update
+fn adapt_exchange_interval(interval: usize, base: usize, attempts: usize, accepts: usize) -> usize {
+    if attempts == 0 { return interval; }
+    let rate = accepts as f64 / attempts as f64;
+    if rate > EXCHANGE_RATE_HIGH {
+        (interval * 2).min(base * EXCHANGE_MAX_FACTOR)
+    } else if rate < EXCHANGE_RATE_LOW {
+        (interval / 2).max(1)
+    } else {
+        interval
+    }
+}
+
+/// Stream offset for thread 0's replica-exchange draws (chain IDs are small, so no overlap)
+const SWAP_STREAM: u64 = u64::MAX;
+
+/// Random stream for one exchange block, from the run seed, the stream (chain ID or
+/// `SWAP_STREAM`) and the block's first iteration. Nothing needs checkpointing: a run
+/// resumed at a block boundary draws exactly what the uninterrupted run would have.
+fn block_rng(seed: u64, stream: u64, block_start: usize) -> StdRng {
+    StdRng::seed_from_u64(
+        seed ^ stream.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ (block_start as u64).wrapping_mul(0xBF58_476D_1CE4_E5B9),
+    )
+}
+
+/// The chain the posterior is read from: the first chain at temperature 1.0, or the
+/// warmest one if none is. Chain order alone says nothing once chains have failed.
+pub fn cold_chain(chains: &[ChainState]) -> Option<&ChainState> {
+    chains.iter().find(|c| c.temperature >= 1.0)
+        .or_else(|| chains.iter().max_by(|a, b| a.temperature.total_cmp(&b.temperature)))
+}
+
+/// Position of the first surviving chain at temperature 1.0 (0 if none survives)
+fn cold_index(chains: &[RwLock<ChainState>], failed: &[AtomicBool]) -> usize {
+    (0..chains.len())
+        .find(|&i| !failed[i].load(Ordering::SeqCst) && read_chain(&chains[i]).temperature >= 1.0)
+        .unwrap_or(0)
+}
+
+/// Chain locks stay readable after a panic in the exchange step poisoned them
+fn read_chain(lock: &RwLock<ChainState>) -> std::sync::RwLockReadGuard<'_, ChainState> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Message carried by a caught panic
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    payload.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
 pub fn run_mcmc_parallel(
     ctx: Arc<McmcContext>,
     initial_states: Vec<ChainState>,
-    total_iter: usize,
-    exchange_interval: usize,
-    verbose: bool,
+    config: McmcRunConfig,
 ) -> Result<Vec<ChainState>> {
+    let McmcRunConfig { start_iter, total_iter, exchange_interval, autostop_window, verbose, .. } = config;
     let num_chains = initial_states.len();
+    let stop_flag = Arc::new(AtomicBool::new(false));
+    let started = Instant::now();
     
+    // Created up front so the history recorded before this run is in the log
+    let mut history_log = match &config.checkpoint {
+        Some(cp) => Some(HistoryLog::create(&cp.path, &initial_states)?),
+        None => None,
+    };
+
     let shared_chains: Arc<Vec<RwLock<ChainState>>> = Arc::new(
-        initial_states.into_iter().map(|s| RwLock::new(s)).collect()
+        initial_states.into_iter().map(RwLock::new).collect()
+    );
+
+    // Advanced by thread 0 at each barrier. Hidden in verbose mode (per-move logging
+    // would garble it) and automatically when stderr is not a TTY.
+    let progress = ProgressBar::new(total_iter as u64);
+    progress.set_style(
+        ProgressStyle::with_template("MCMC [{bar:40}] {pos}/{len} iter ({eta}) {msg}")
+            .unwrap()
+            .progress_chars("=> "),
     );
+    if verbose {
+        progress.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    progress.set_position(start_iter as u64);
 
     let barrier = Arc::new(Barrier::new(num_chains));
+    // Current exchange interval: written by thread 0 between the two barriers, read by all after
+    let shared_interval = Arc::new(AtomicUsize::new(exchange_interval));
+    // Chains whose step panicked. Their threads keep meeting the barriers (so the others
+    // cannot deadlock) but stop stepping, and they take no part in swaps.
+    let failed: Arc<Vec<AtomicBool>> = Arc::new((0..num_chains).map(|_| AtomicBool::new(false)).collect());
     let mut handles = vec![];
 
     for t_id in 0..num_chains {
         let chains_ref = shared_chains.clone();
         let bar_ref = barrier.clone();
         let ctx_ref = ctx.clone();
+        let stop_ref = stop_flag.clone();
+        let config_ref = config.clone();
+        let progress_ref = progress.clone();
+        let interval_ref = shared_interval.clone();
+        let failed_ref = failed.clone();
+        // Checkpoints are written by thread 0
+        let mut history_log = if t_id == 0 { history_log.take() } else { None };
         
         let handle = thread::spawn(move || {
-            let mut rng = rand::rng(); 
+            // Streams depend only on the seed, chain ID and block, never on thread scheduling
+            let chain_id = chains_ref[t_id].read().unwrap().id as u64;
             let em_iter = 10; 
             
-            let num_blocks = total_iter / exchange_interval;
-            
-            for block in 0..num_blocks {
-                let current_iter_base = block * exchange_interval;
-
-                // 1. Run MCMC Steps
-                for i in 0..exchange_interval {
+            // Ladder tuning bookkeeping (only used by thread 0): swap stats per adjacent pair
+            let mut pair_attempts = vec![0usize; num_chains.saturating_sub(1)];
+            let mut pair_accepts = vec![0usize; num_chains.saturating_sub(1)];
+            let mut num_tunes = 0usize;
+
+            // Adaptive exchange bookkeeping (only used by thread 0)
+            let (mut window_attempts, mut window_accepts) = (0usize, 0usize);
+
+            let mut block = start_iter / exchange_interval;
+            let mut current_iter_base = start_iter;
+            loop {
+                let interval = interval_ref.load(Ordering::SeqCst);
+                if current_iter_base + interval > total_iter { break; }
+
+                let mut rng = block_rng(config_ref.seed, chain_id, current_iter_base);
+
+                // 1. Run MCMC Steps. A panic (e.g. a NaN comparison) is caught while the
+                // guard is held, so the lock is not poisoned; the chain is then retired.
+                for i in 0..interval {
+                    if failed_ref[t_id].load(Ordering::SeqCst) { break; }
                     let mut state_guard = chains_ref[t_id].write().unwrap();
                     let iter_idx = current_iter_base + i;
-                    run_chain_step(&ctx_ref, &mut *state_guard, &mut rng, iter_idx, em_iter, verbose);
+                    let step = catch_unwind(AssertUnwindSafe(|| {
+                        run_chain_step(&ctx_ref, &mut state_guard, &mut rng, iter_idx, em_iter, config_ref.thin, verbose);
+                    }));
+                    if let Err(payload) = step {
+                        eprintln!("Warning: Chain {} failed at iter {}: {}. Continuing without it.",
+                            state_guard.id, iter_idx, panic_message(payload.as_ref()));
+                        failed_ref[t_id].store(true, Ordering::SeqCst);
+                    }
                 }
 
                 bar_ref.wait();
 
                 // 2. Thread 0 performs swaps & logging
                 if t_id == 0 {
-                    let current_iter = (block + 1) * exchange_interval;
-                    if verbose && (current_iter % 50 == 0 || current_iter == total_iter) {
-                        let c0 = chains_ref[0].read().unwrap();
-                        println!("Iter: {} / {} | C1 Floor: {:.2e}", current_iter, total_iter, c0.current_unk_prob);
-                    }
+                    // A panic here would leave the other threads waiting at the barrier, so it
+                    // is caught and ends the run at this block instead.
+                    let exchange = catch_unwind(AssertUnwindSafe(|| {
+                        let cold = cold_index(&chains_ref, &failed_ref);
+                        let current_iter = current_iter_base + interval;
+                        if verbose && (current_iter.is_multiple_of(50) || current_iter == total_iter) {
+                            let c0 = chains_ref[cold].read().unwrap();
+                            println!("Iter: {} / {} | Cold Floor: {:.2e}", current_iter, total_iter, c0.current_unk_prob);
+                        }
 
-                    let odd_flag = block % 2; 
-                    let start_idx = if odd_flag == 1 { 1 } else { 0 };
+                        let mut swap_rng = block_rng(config_ref.seed, SWAP_STREAM, current_iter_base);
+                        let odd_flag = block % 2; 
+                        let start_idx = if odd_flag == 1 { 1 } else { 0 };
                     
-                    let mut c = start_idx;
-                    while c + 1 < num_chains {
-                        let mut chain_a = chains_ref[c].write().unwrap();
-                        let mut chain_b = chains_ref[c+1].write().unwrap();
+                        let mut c = start_idx;
+                        while c + 1 < num_chains {
+                            if failed_ref[c].load(Ordering::SeqCst) || failed_ref[c + 1].load(Ordering::SeqCst) {
+                                c += 2;
+                                continue;
+                            }
+                            let mut chain_a = chains_ref[c].write().unwrap();
+                            let mut chain_b = chains_ref[c+1].write().unwrap();
                         
-                        chain_a.swaps_attempted += 1;
-                        chain_b.swaps_attempted += 1;
-
-                        let l1 = chain_a.current_log_likelihood;
-                        let l2 = chain_b.current_log_likelihood;
-                        let t1 = chain_a.temperature;
-                        let t2 = chain_b.temperature;
+                            chain_a.swaps_attempted += 1;
+                            chain_b.swaps_attempted += 1;
+                            pair_attempts[c] += 1;
+                            window_attempts += 1;
+
+                            let l1 = chain_a.current_log_likelihood;
+                            let l2 = chain_b.current_log_likelihood;
+                            let t1 = chain_a.temperature;
+                            let t2 = chain_b.temperature;
                         
-                        let log_ratio = (l2 - l1) * (t1 - t2);
+                            let log_ratio = (l2 - l1) * (t1 - t2);
                         
-                        if log_ratio >= 0.0 || rng.random::<f64>() < log_ratio.exp() {
-                            std::mem::swap(&mut chain_a.species_set, &mut chain_b.species_set);
-                            std::mem::swap(&mut chain_a.abundances, &mut chain_b.abundances);
-                            std::mem::swap(&mut chain_a.current_unk_prob, &mut chain_b.current_unk_prob);
-                            std::mem::swap(&mut chain_a.current_log_likelihood, &mut chain_b.current_log_likelihood);
+                            if metropolis_accept(log_ratio, &mut swap_rng) {
+                                let record = current_iter.is_multiple_of(config_ref.thin);
+                                if chain_a.species_set != chain_b.species_set {
+                                    chain_a.membership_changed_at = current_iter;
+                                    chain_b.membership_changed_at = current_iter;
+                                }
+                                std::mem::swap(&mut chain_a.species_set, &mut chain_b.species_set);
+                                std::mem::swap(&mut chain_a.abundances, &mut chain_b.abundances);
+                                std::mem::swap(&mut chain_a.current_unk_prob, &mut chain_b.current_unk_prob);
+                                std::mem::swap(&mut chain_a.current_log_likelihood, &mut chain_b.current_log_likelihood);
                             
-                            chain_a.swaps_accepted += 1;
-                            chain_b.swaps_accepted += 1;
+                                chain_a.swaps_accepted += 1;
+                                chain_b.swaps_accepted += 1;
+                                pair_accepts[c] += 1;
+                                window_accepts += 1;
+
+                                // LOG THE SWAP (Fix for confusion)
+                                let swap_rec_a = ChainRecord {
+                                    iter: current_iter,
+                                    log_likelihood: chain_a.current_log_likelihood,
+                                    move_type: format!("Swapped from Chain {}", c+1),
+                                    species: chain_a.record_species(),
+                                };
+                                if record { chain_a.history.push(swap_rec_a); }
+
+                                let swap_rec_b = ChainRecord {
+                                    iter: current_iter,
+                                    log_likelihood: chain_b.current_log_likelihood,
+                                    move_type: format!("Swapped from Chain {}", c),
+                                    species: chain_b.record_species(),
+                                };
+                                if record { chain_b.history.push(swap_rec_b); }
+                            }
+                            c += 2;
+                        }
+
+                        // 2b. Auto-tune temperature ladder during warmup
+                        if let Some(warmup) = config_ref.auto_temp_warmup
+                            && current_iter <= warmup
+                            && (block + 1).is_multiple_of(TEMP_TUNE_BLOCKS)
+                        {
+                            num_tunes += 1;
+                            tune_temperatures(&chains_ref, &pair_attempts, &pair_accepts, num_tunes);
+                            pair_attempts.fill(0);
+                            pair_accepts.fill(0);
+                            if verbose || current_iter == warmup {
+                                let temps: Vec<String> = chains_ref.iter()
+                                    .map(|lock| format!("{:.4}", lock.read().unwrap().temperature))
+                                    .collect();
+                                println!("Iter {}: tuned temperatures [{}]", current_iter, temps.join(", "));
+                            }
+                        }
+
+                        // 2c. Adaptive exchange interval; the last block is shortened to end at total_iter
+                        if config_ref.adaptive_exchange {
+                            let mut next = interval;
+                            if (block + 1).is_multiple_of(EXCHANGE_ADAPT_BLOCKS) {
+                                next = adapt_exchange_interval(interval, exchange_interval, window_attempts, window_accepts);
+                                if verbose && next != interval {
+                                    println!("Iter {}: exchange interval {} -> {}", current_iter, interval, next);
+                                }
+                                window_attempts = 0;
+                                window_accepts = 0;
+                            }
+                            if current_iter < total_iter {
+                                next = next.min(total_iter - current_iter);
+                            }
+                            interval_ref.store(next, Ordering::SeqCst);
+                        }
+
+                        // 3. Autostop: cold chain membership unchanged for a full window, counted
+                        // from the iteration of its last actual change
+                        if let Some(window) = autostop_window {
+                            let mut c0 = chains_ref[cold].write().unwrap();
+                            if current_iter.saturating_sub(c0.membership_changed_at) >= window && current_iter < total_iter {
+                                println!("Autostop: cold chain stable for {} iterations. Stopping at iter {}.", window, current_iter);
+                                let record = ChainRecord {
+                                    iter: current_iter,
+                                    log_likelihood: c0.current_log_likelihood,
+                                    move_type: "AutoStop".to_string(),
+                                    species: c0.record_species(),
+                                };
+                                c0.history.push(record);
+                                stop_ref.store(true, Ordering::SeqCst);
+                            }
+                        }
 
-                            // LOG THE SWAP (Fix for confusion)
-                            let swap_rec_a = ChainRecord {
+                        // 3b. Wall-clock budget
+                        if let Some(budget) = config_ref.max_runtime
+                            && !stop_ref.load(Ordering::SeqCst)
+                            && current_iter < total_iter
+                            && started.elapsed() >= budget
+                        {
+                            println!("Max runtime of {}s reached. Stopping MCMC at iter {}.", budget.as_secs(), current_iter);
+                            let mut c0 = chains_ref[cold].write().unwrap();
+                            let record = ChainRecord {
                                 iter: current_iter,
-                                log_likelihood: chain_a.current_log_likelihood,
-                                move_type: format!("Swapped from Chain {}", c+1),
+                                log_likelihood: c0.current_log_likelihood,
+                                move_type: "Timeout".to_string(),
+                                species: c0.record_species(),
                             };
-                            chain_a.history.push(swap_rec_a);
+                            c0.history.push(record);
+                            stop_ref.store(true, Ordering::SeqCst);
+                        }
 
-                            let swap_rec_b = ChainRecord {
-                                iter: current_iter,
-                                log_likelihood: chain_b.current_log_likelihood,
-                                move_type: format!("Swapped from Chain {}", c),
+                        // 4. Progress
+                        {
+                            let c0 = chains_ref[cold].read().unwrap();
+                            progress_ref.set_position(current_iter as u64);
+                            progress_ref.set_message(format!(
+                                "| Cold LogL: {:.2} | Species: {}", c0.current_log_likelihood, c0.species_set.len()
+                            ));
+                        }
+
+                        // 5. Checkpoint
+                        if let Some(cp) = &config_ref.checkpoint
+                            && (block + 1).is_multiple_of(cp.every_blocks)
+                        {
+                            let guards: Vec<_> = chains_ref.iter().map(read_chain).collect();
+                            let chains: Vec<&ChainState> = guards.iter().map(|g| &**g).collect();
+                            let saved = match history_log.as_mut() {
+                                Some(log) => log.append(&chains)
+                                    .and_then(|bytes| save_checkpoint(&cp.path, current_iter, &chains, &ctx_ref.taxons, bytes)),
+                                None => Ok(()),
                             };
-                            chain_b.history.push(swap_rec_b);
+                            match saved {
+                                Ok(()) => if verbose { println!("Checkpoint written at iter {}", current_iter) },
+                                Err(e) => eprintln!("Warning: Failed to write checkpoint: {:#}", e),
+                            }
                         }
-                        c += 2;
+                    }));
+                    if let Err(payload) = exchange {
+                        eprintln!("Warning: Exchange step failed after iter {}: {}. Stopping MCMC early.",
+                            current_iter_base + interval, panic_message(payload.as_ref()));
+                        stop_ref.store(true, Ordering::SeqCst);
                     }
                 }
                 bar_ref.wait();
+
+                if stop_ref.load(Ordering::SeqCst) {
+                    break;
+                }
+                current_iter_base += interval;
+                block += 1;
             }
         });
         handles.push(handle);
     }
 
-    for h in handles {
-        h.join().unwrap();
+    for (t_id, h) in handles.into_iter().enumerate() {
+        if let Err(payload) = h.join() {
+            eprintln!("Warning: MCMC thread {} panicked: {}", t_id, panic_message(payload.as_ref()));
+            failed[t_id].store(true, Ordering::SeqCst);
+        }
     }
-    
-    let result: Vec<ChainState> = shared_chains.iter()
-        .map(|lock| lock.read().unwrap().clone())
+    progress.finish_and_clear();
+
+    let result: Vec<ChainState> = shared_chains.iter().enumerate()
+        .filter(|(t_id, _)| !failed[*t_id].load(Ordering::SeqCst))
+        .map(|(_, lock)| read_chain(lock).clone())
         .collect();
+    let num_failed = num_chains - result.len();
+    if num_failed > 0 {
+        if !result.iter().any(|c| c.temperature >= 1.0) {
+            bail!("All cold chains failed ({} of {} chains panicked); see the warnings above. \
+                   NaN abundances usually point to degenerate input (e.g. reads with no usable alignments).",
+                num_failed, num_chains);
+        }
+        eprintln!("Warning: {} of {} chains failed; continuing with the {} surviving chains.", num_failed, num_chains, result.len());
+    }
 
     Ok(result)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra_sparse::coo::CooMatrix;
+
+    /// 40 reads over 3 taxa: 20 to taxon 0, 10 to taxon 1, 5 shared by both, 5 to taxon 2
+    fn toy_context() -> McmcContext {
+        let mut coo = CooMatrix::new(40, 3);
+        for r in 0..40 {
+            let cols: &[usize] = match r {
+                0..20 => &[0],
+                20..30 => &[1],
+                30..35 => &[0, 1],
+                _ => &[2],
+            };
+            for &c in cols {
+                coo.push(r, c, (1e-3f64).ln());
+            }
+        }
+        McmcContext::new(
+            &CsrMatrix::from(&coo),
+            vec![1.0; 40],
+            vec!["1".to_string(), "2".to_string(), "3".to_string()],
+            vec![0.5, 0.35, 0.15],
+            vec![1000.0; 3],
+            PenaltyConfig { median_genome_len: 1000.0, read_support: 2, p_unknown_penalty_ref: 1e-20 },
+            UnknownBinConfig::default(),
+        )
+    }
+
+    fn empty_chain(id: usize, temperature: f64) -> ChainState {
+        ChainState {
+            id,
+            temperature,
+            species_set: HashSet::new(),
+            abundances: HashMap::new(),
+            current_unk_prob: 1e-300,
+            current_log_likelihood: -1e10,
+            moves_attempted: 0,
+            moves_accepted: 0,
+            swaps_attempted: 0,
+            swaps_accepted: 0,
+            add_moves: MoveCounter::default(),
+            remove_moves: MoveCounter::default(),
+            swap_moves: MoveCounter::default(),
+            membership_changed_at: 0,
+            history: Vec::new(),
+        }
+    }
+
+    fn run_config(start_iter: usize, total_iter: usize, checkpoint: Option<CheckpointConfig>) -> McmcRunConfig {
+        McmcRunConfig {
+            start_iter,
+            total_iter,
+            exchange_interval: 5,
+            adaptive_exchange: false,
+            autostop_window: None,
+            checkpoint,
+            auto_temp_warmup: None,
+            thin: 1,
+            max_runtime: None,
+            seed: 9,
+            verbose: false,
+        }
+    }
+
+    #[test]
+    fn resumed_run_matches_uninterrupted_run() {
+        let ctx = Arc::new(toy_context());
+        let initial = || vec![empty_chain(0, 1.0), empty_chain(1, 0.7), empty_chain(2, 0.4)];
+        let straight = run_mcmc_parallel(ctx.clone(), initial(), run_config(0, 40, None)).unwrap();
+        assert!(straight[0].moves_accepted > 0);
+
+        let path = std::env::temp_dir().join(format!("metamix_resume_test_{}.json", std::process::id()));
+        let path = path.display().to_string();
+        let checkpoint = CheckpointConfig { path: path.clone(), every_blocks: 1 };
+        let first_half = run_mcmc_parallel(ctx.clone(), initial(), run_config(0, 20, Some(checkpoint))).unwrap();
+        // Each checkpoint appended only its new records: the log holds every record exactly once
+        let log_lines = std::fs::read_to_string(history_path(&path)).unwrap().lines().count();
+        assert_eq!(log_lines, first_half.iter().map(|c| c.history.len()).sum::<usize>());
+        let saved = load_checkpoint(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(history_path(&path)).ok();
+        assert_eq!(saved.iteration, 20);
+        let resumed = run_mcmc_parallel(ctx, saved.chains, run_config(20, 40, None)).unwrap();
+
+        for (a, b) in straight.iter().zip(&resumed) {
+            assert_eq!(a.sorted_species(), b.sorted_species());
+            assert_eq!(a.current_log_likelihood, b.current_log_likelihood);
+            assert_eq!((a.moves_accepted, a.swaps_accepted), (b.moves_accepted, b.swaps_accepted));
+            let trace = |c: &ChainState| c.history.iter()
+                .map(|r| (r.iter, r.log_likelihood, r.move_type.clone()))
+                .collect::<Vec<_>>();
+            assert_eq!(trace(a), trace(b));
+        }
+    }
+
+    #[test]
+    fn cold_chain_is_chosen_by_temperature() {
+        // As left by run_mcmc_parallel when the first cold chain failed and a second survived
+        let chains = vec![empty_chain(1, 0.5), empty_chain(2, 1.0), empty_chain(3, 0.25)];
+        assert_eq!(cold_chain(&chains).map(|c| c.id), Some(2));
+        let hot = vec![empty_chain(1, 0.25), empty_chain(2, 0.5)];
+        assert_eq!(cold_chain(&hot).map(|c| c.id), Some(2));
+        assert!(cold_chain(&[]).is_none());
+    }
+
+    #[test]
+    fn metropolis_accept_handles_non_finite_and_tiny_ratios() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let untouched = rng.clone();
+        assert!(!metropolis_accept(f64::NAN, &mut rng));
+        assert!(metropolis_accept(f64::INFINITY, &mut rng));
+        assert!(!metropolis_accept(f64::NEG_INFINITY, &mut rng));
+        assert!(!metropolis_accept(MIN_LOG_ACCEPT - 1.0, &mut rng));
+        assert!(metropolis_accept(0.0, &mut rng));
+        // None of the above consumed a draw
+        assert_eq!(rng.random::<u64>(), untouched.clone().random::<u64>());
+
+        let accepted = (0..10_000).filter(|_| metropolis_accept(0.5f64.ln(), &mut rng)).count();
+        assert!((4_700..5_300).contains(&accepted), "{accepted}");
+    }
+
+    #[test]
+    fn only_cold_chains_record_membership() {
+        let ctx = Arc::new(toy_context());
+        let chains = vec![empty_chain(0, 1.0), empty_chain(1, 0.5)];
+        let result = run_mcmc_parallel(ctx, chains, run_config(0, 20, None)).unwrap();
+        assert!(result[0].history.iter().any(|r| !r.species.is_empty()));
+        assert!(result[1].history.iter().all(|r| r.species.is_empty()));
+    }
+
+    #[test]
+    fn older_checkpoint_versions_are_rejected_by_version() {
+        let path = std::env::temp_dir().join(format!("metamix_old_checkpoint_{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"version":1,"iteration":10,"chains":[{"id":0}]}"#).unwrap();
+        let err = load_checkpoint(&path.display().to_string()).err().unwrap();
+        std::fs::remove_file(&path).ok();
+        assert!(err.to_string().contains("version 1 is not supported"), "{err}");
+    }
+
+    #[test]
+    fn autostop_waits_for_a_full_window_without_membership_changes() {
+        let ctx = Arc::new(toy_context());
+        let mut config = run_config(0, 2000, None);
+        config.autostop_window = Some(50);
+        let chains = vec![empty_chain(0, 1.0), empty_chain(1, 0.5)];
+        let cold = run_mcmc_parallel(ctx, chains, config).unwrap().remove(0);
+
+        let stop = cold.history.iter().find(|r| r.move_type == "AutoStop").expect("run should autostop").iter;
+        assert!(stop - cold.membership_changed_at >= 50);
+        // Every recorded set in the window, including those between exchange barriers, is the final one
+        let final_set = cold.sorted_species();
+        assert!(cold.history.iter().filter(|r| r.iter > stop - 50).all(|r| r.species == final_set));
+    }
+}
@@ -1,18 +1,37 @@
-use crate::step3_mcmc::{McmcContext, ChainState, run_mini_em};
+use crate::step2_reduce::SeparatedReads;
+use crate::step3_mcmc::{McmcContext, ChainState, ChainRecord, cold_chain, run_mini_em, run_mini_em_absorbing, run_mini_em_reweighted};
 use anyhow::{Context, Result};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use rayon::prelude::*;
 use rand::prelude::*;
-use rand_distr::{Gamma, Distribution};
-use std::collections::{HashMap, HashSet};
+use rand::rngs::StdRng;
+use rand::distr::weighted::WeightedIndex;
+use rand_distr::{Gamma, Distribution, Poisson};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufRead, BufReader, BufWriter, Write};
 
-/// Parses NCBI names.dmp file: ID | Name | UniqueName | Class |
+/// Opens a text file for line reading, transparently decompressing gzip
+/// (detected by the `1f 8b` magic bytes, so the extension does not matter).
+pub fn open_text_reader(path: &str) -> Result<Box<dyn BufRead>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let mut reader = BufReader::new(file);
+    let is_gzip = reader.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+    if is_gzip {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Parses NCBI names.dmp file (optionally gzipped): ID | Name | UniqueName | Class |
 /// Returns a map of TaxonID (String) -> Scientific Name
 pub fn load_taxonomy_names(path: &str) -> Result<HashMap<String, String>> {
     println!("Loading taxonomy names from: {}", path);
-    let file = File::open(path).context("Failed to open names.dmp")?;
-    let reader = BufReader::new(file);
+    let reader = open_text_reader(path).context("Failed to open names.dmp")?;
     let mut map = HashMap::new();
 
     for line in reader.lines() {
@@ -32,329 +51,2105 @@ pub fn load_taxonomy_names(path: &str) -> Result<HashMap<String, String>> {
     Ok(map)
 }
 
+/// Parses NCBI nodes.dmp (optionally gzipped): ID | ParentID | Rank | ...
+/// Returns a map of TaxonID -> (ParentID, Rank)
+pub fn load_taxonomy_nodes(path: &str) -> Result<HashMap<String, (String, String)>> {
+    println!("Loading taxonomy nodes from: {}", path);
+    let reader = open_text_reader(path).context("Failed to open nodes.dmp")?;
+    let mut map = HashMap::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        let parts: Vec<&str> = line.split('|').map(|s| s.trim()).collect();
+        if parts.len() >= 3 {
+            map.insert(parts[0].to_string(), (parts[1].to_string(), parts[2].to_string()));
+        }
+    }
+    println!("Loaded {} taxonomy nodes.", map.len());
+    Ok(map)
+}
+
+/// EM-vs-Gibbs Jensen-Shannon divergence (bits) above which a warning is printed
+const JSD_WARN_THRESHOLD: f64 = 0.1;
+
+/// Scientific name of a taxon, or "NA" when there is no name for it (no --taxonomy-names,
+/// or the ID is missing from it). "Unknown" is reserved for the unclassified bin.
+fn scientific_name<'a>(taxonomy_map: Option<&'a HashMap<String, String>>, tid: &str) -> &'a str {
+    taxonomy_map.and_then(|m| m.get(tid)).map(|s| s.as_str()).unwrap_or("NA")
+}
+
+/// Standard ranks reported by `export_rank_profiles`, from the top down
+const STANDARD_RANKS: [&str; 7] = ["domain", "phylum", "class", "order", "family", "genus", "species"];
+
+/// Ancestor of `taxon_id` (or the taxon itself) at each standard rank.
+/// NCBI's older "superkingdom" rank is reported as domain.
+fn standard_lineage(taxon_id: &str, nodes: &HashMap<String, (String, String)>) -> HashMap<&'static str, String> {
+    let mut lineage = HashMap::new();
+    let mut current = taxon_id.to_string();
+    // Bounded walk: a malformed nodes.dmp could contain a cycle
+    for _ in 0..256 {
+        let Some((parent, rank)) = nodes.get(&current) else { break };
+        let rank = if rank == "superkingdom" { "domain" } else { rank.as_str() };
+        if let Some(standard) = STANDARD_RANKS.iter().find(|r| **r == rank) {
+            lineage.entry(*standard).or_insert_with(|| current.clone());
+        }
+        if *parent == current { break; }
+        current = parent.clone();
+    }
+    lineage
+}
+
+/// Writes the reported taxa's mean abundances summed up the lineage at every standard rank,
+/// in long format (one row per rank and taxon). Taxa with no ancestor at a rank are pooled as
+/// "unclassified", so the percentages at each rank sum to 100 over the reported taxa.
+pub fn export_rank_profiles(
+    path: &str,
+    taxa: &[TaxonResult],
+    nodes: &HashMap<String, (String, String)>,
+    taxonomy_map: Option<&HashMap<String, String>>,
+) -> Result<()> {
+    println!("Exporting per-rank profiles to: {}", path);
+    let total: f64 = taxa.iter().map(|t| t.stats.mean).sum();
+    let lineages: Vec<HashMap<&str, String>> = taxa.iter().map(|t| standard_lineage(&t.taxon_id, nodes)).collect();
+
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "Rank\tTaxonID\tScientificName\tMeanAbundance\tPercent")?;
+    for rank in STANDARD_RANKS {
+        let mut sums: BTreeMap<&str, f64> = BTreeMap::new();
+        for (taxon, lineage) in taxa.iter().zip(&lineages) {
+            let tid = lineage.get(rank).map(|s| s.as_str()).unwrap_or("unclassified");
+            *sums.entry(tid).or_insert(0.0) += taxon.stats.mean;
+        }
+        for (tid, mean) in sums {
+            let name = scientific_name(taxonomy_map, tid);
+            let percent = if total > 0.0 { 100.0 * mean / total } else { 0.0 };
+            writeln!(file, "{}\t{}\t{}\t{:.6}\t{:.4}", rank, tid, name, mean, percent)?;
+        }
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Format of the main results file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Tsv,
+    Json,
+}
+
+/// Row order of the TSV results. Ties are broken by taxon ID.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortBy {
+    /// Descending mean abundance
+    Abundance,
+    /// Descending log10 Bayes factor
+    Bayes,
+    /// Scientific name, A-Z
+    Name,
+    /// Ascending credible interval width (best-determined first)
+    CiWidth,
+    /// Taxon ID, ascending (stable across runs regardless of the estimates)
+    #[default]
+    TaxonId,
+}
+
+/// How the 95% credible interval is computed from the Gibbs samples
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CiMethod {
+    /// Equal-tailed 2.5% / 97.5% empirical quantiles
+    #[default]
+    Quantile,
+    /// Shortest interval containing 95% of the samples (better for skewed, near-zero posteriors)
+    Hdi,
+    /// Mean ± 1.96 standard deviations, clamped to [0, 1]
+    Wald,
+}
+
+impl CiMethod {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CiMethod::Quantile => "quantile",
+            CiMethod::Hdi => "hdi",
+            CiMethod::Wald => "wald",
+        }
+    }
+}
+
+/// Burn-in discarded from the MCMC chain histories. Applies to the trace, the
+/// convergence report, posterior inclusion and multi-chain pooling; the final Gibbs
+/// sampler has its own absolute burn-in (`GibbsConfig::burnin`).
+#[derive(Clone, Copy, Debug)]
+pub enum McmcBurnin {
+    /// Fraction of each chain's recorded history
+    Ratio(f64),
+    /// Records before this MCMC iteration
+    Iterations(usize),
+}
+
+impl McmcBurnin {
+    /// Index of the first post-burnin record in a chain's history
+    fn start(&self, history: &[ChainRecord]) -> usize {
+        match *self {
+            McmcBurnin::Ratio(ratio) => (history.len() as f64 * ratio) as usize,
+            McmcBurnin::Iterations(n) => history.partition_point(|rec| rec.iter < n),
+        }
+    }
+}
+
+/// Settings for the final Gibbs sampler.
+/// The abundance update draws from Dirichlet(counts + alpha); the Unknown bin
+/// uses its own concentration `unk_alpha`. Large alphas pull the posterior
+/// towards uniform, small alphas let it concentrate on few taxa.
+#[derive(Clone, Copy, Debug)]
+pub struct GibbsConfig {
+    pub iterations: usize,
+    pub burnin: usize,
+    pub alpha: f64,
+    pub unk_alpha: f64,
+    /// Keep only every Nth post-burnin sample
+    pub thin: usize,
+    pub ci_method: CiMethod,
+    /// Mark a read ambiguous when its runner-up is within this relative distance of the best
+    pub tie_epsilon: Option<f64>,
+    /// Independent sampler runs whose retained samples are pooled; replicate 0 starts from
+    /// uniform abundances, the others from a random Dirichlet(1) draw
+    pub replicates: usize,
+}
+
 // Holds Summary Statistics for a Species
+#[derive(Clone, Debug, Default)]
 pub struct AbundanceStats {
     pub mean: f64,
     pub ci_low: f64,  // 2.5th percentile
     pub ci_high: f64, // 97.5th percentile
 }
 
+/// Per-taxon inference result
+#[derive(Clone, Debug)]
+pub struct TaxonResult {
+    pub taxon_id: String,
+    pub stats: AbundanceStats,
+    pub log10_bf: f64,
+    pub posterior_inclusion: f64,
+    /// Bayes factor against the species' reads being Unknown (with `bf_unknown`)
+    pub log10_bf_unknown: Option<f64>,
+    /// Fraction of the genome covered by at least one read (with coverage tracking)
+    pub coverage_breadth: Option<f64>,
+    /// Listed as a host taxon; excluded from microbial normalization
+    pub is_host: bool,
+    /// Taxa with identical alignment profiles merged into this one
+    pub merged_taxa: Vec<String>,
+}
+
+/// In-memory result of step 4
+#[derive(Clone, Debug, Default)]
+pub struct InferenceResult {
+    /// Reported taxa, ordered by matrix column
+    pub taxa: Vec<TaxonResult>,
+    /// Per read: (index into `taxa`, probability). An index of `taxa.len()` means Unknown,
+    /// `taxa.len() + 1` ambiguous (see `GibbsConfig::tie_epsilon`).
+    pub read_assignments: Vec<(usize, f64)>,
+    pub unknown_floor: f64,
+    /// Posterior abundance of the Unknown bin
+    pub unknown: AbundanceStats,
+}
+
+/// Which files `run_inference` writes and what they contain
+#[derive(Clone, Debug)]
+pub struct ReportConfig {
+    pub output_prefix: String,
+    pub output_format: OutputFormat,
+    /// Row order of the TSV results
+    pub sort_by: SortBy,
+    /// Write per-iteration Gibbs read counts
+    pub output_posterior: bool,
+    /// Write the names of reads assigned to Unknown to this file
+    pub unknown_reads_path: Option<String>,
+    /// Write a posterior predictive check to `_ppc.tsv`
+    pub posterior_predictive: bool,
+    /// Add an Unknown row to the results
+    pub report_unknown: bool,
+    /// Add integer estimated reads (largest-remainder rounding) to the results
+    pub integer_reads: bool,
+    /// Taxon IDs reported as host and excluded from microbial normalization
+    pub host_taxa: HashSet<String>,
+    /// Gzip the read-assignment and posterior-sample files
+    pub compress: bool,
+    /// Representative taxon ID -> merged taxon IDs; adds a MergedTaxa column when non-empty
+    pub merged_taxa: HashMap<String, Vec<String>>,
+    /// Write the cold chain's species set per recorded iteration to this file
+    pub species_trace_path: Option<String>,
+    /// Write the per-iteration Gibbs abundances (Unknown included) to this file
+    pub abundance_history_path: Option<String>,
+    /// Also compute Bayes factors against the species' reads being Unknown
+    pub bf_unknown: bool,
+    /// Write one line per read-taxon pair with its posterior weight instead of one best taxon per read
+    pub fractional_assignments: bool,
+    /// Drop species whose breadth of coverage is below this (needs coverage tracking);
+    /// they are listed in `_filtered.tsv` and their reads reclassified
+    pub min_breadth: Option<f64>,
+    /// Drop species whose Gibbs mean abundance is below this; they are listed in
+    /// `_filtered.tsv` and the sampler is rerun without them
+    pub min_abundance: Option<f64>,
+    /// Host-only and excluded-only reads removed in step 2; reported as comment lines / JSON fields
+    pub separated_reads: Option<SeparatedReads>,
+    /// Widen credible intervals by sqrt(weighted reads / effective reads) when read weights vary
+    pub ess_adjusted_ci: bool,
+    /// Bootstrap replicates over reads for frequentist intervals on the final species set
+    pub bootstrap: Option<usize>,
+}
+
+/// Path of an output file: `<prefix>_<name>`, or `<prefix><name>` when the prefix
+/// is a directory (ends with `/`, as with `--output-dir`)
+pub fn output_file(prefix: &str, name: &str) -> String {
+    if prefix.ends_with('/') { format!("{}{}", prefix, name) } else { format!("{}_{}", prefix, name) }
+}
+
+/// Output file, optionally gzip-compressed. Call `finish` once written: dropping a gzip
+/// writer would leave the stream without its trailer and hide the error.
+enum OutputWriter {
+    Plain(BufWriter<File>),
+    Gzip(BufWriter<GzEncoder<File>>),
+}
+
+impl OutputWriter {
+    /// Flushes buffered data and, when compressed, writes the gzip trailer
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            OutputWriter::Plain(mut w) => w.flush(),
+            OutputWriter::Gzip(w) => {
+                w.into_inner().map_err(|e| e.into_error())?.finish()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutputWriter::Plain(w) => w.write(buf),
+            OutputWriter::Gzip(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputWriter::Plain(w) => w.flush(),
+            OutputWriter::Gzip(w) => w.flush(),
+        }
+    }
+}
+
+/// Creates an output file, gzip-compressed when `compress` is set.
+/// Returns the writer and the final path (with `.gz` appended when compressed).
+fn create_output(path: &str, compress: bool) -> Result<(OutputWriter, String)> {
+    if compress {
+        let path = format!("{}.gz", path);
+        let file = File::create(&path).with_context(|| format!("Failed to create {}", path))?;
+        Ok((OutputWriter::Gzip(BufWriter::new(GzEncoder::new(file, Compression::default()))), path))
+    } else {
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path))?;
+        Ok((OutputWriter::Plain(BufWriter::new(file)), path.to_string()))
+    }
+}
+
+/// Read names and weights must line up with the matrix rows; a mismatch would
+/// misattribute reads (or index out of bounds) in every per-read output
+fn check_row_counts(ctx: &McmcContext, index_to_read_name: &[String]) -> Result<()> {
+    let nrows = ctx.matrix.nrows();
+    anyhow::ensure!(
+        ctx.read_weights.len() == nrows,
+        "Internal error: {} read weights for {} matrix rows", ctx.read_weights.len(), nrows
+    );
+    anyhow::ensure!(
+        index_to_read_name.is_empty() || index_to_read_name.len() == nrows,
+        "Internal error: {} read names for {} matrix rows; read names are out of sync with the matrix",
+        index_to_read_name.len(), nrows
+    );
+    Ok(())
+}
+
+/// Run data reported alongside the inference results
+pub struct InferenceInputs<'a> {
+    pub taxonomy_map: Option<&'a HashMap<String, String>>,
+    /// Read names by matrix row (empty when names are not needed)
+    pub read_names: &'a [String],
+    pub coverage_breadth: Option<&'a HashMap<String, f64>>,
+    pub run_metadata: &'a Value,
+}
+
 pub fn run_inference(
     ctx: &McmcContext,
     chains: &[ChainState],
-    burnin_ratio: f64,
-    output_prefix: &str,
-    taxonomy_map: Option<&HashMap<String, String>>,
-    index_to_read_name: &[String], 
-    output_posterior: bool,
-    gibbs_iter: usize,
-    gibbs_burnin: usize,
-) -> Result<()> {
+    mcmc_burnin: McmcBurnin,
+    inputs: InferenceInputs,
+    gibbs: GibbsConfig,
+    report: &ReportConfig,
+    seed: u64,
+) -> Result<InferenceResult> {
+    let InferenceInputs { taxonomy_map, read_names: index_to_read_name, coverage_breadth, run_metadata } = inputs;
     println!("\n=== STEP 4: INFERENCE & REPORTING ===");
+    check_row_counts(ctx, index_to_read_name)?;
+    let output_prefix = report.output_prefix.as_str();
+    let host_taxa = &report.host_taxa;
 
     // 1. Dynamic Unknown Integration (Median of all chains)
     let mut floors: Vec<f64> = chains.iter().map(|c| c.current_unk_prob).collect();
     let mid = floors.len() / 2;
     floors.select_nth_unstable_by(mid, |a, b| a.partial_cmp(b).unwrap());
-    let learned_floor = floors[mid];
+    let learned_floor = ctx.unk_config.clamp(floors[mid]);
     println!("Learned Unknown Probability Floor (Median): {:.4e}", learned_floor);
 
     // 2. Select Best Chain (Coldest)
-    let cold_chain = &chains[0];
+    let cold_chain = cold_chain(chains).context("No MCMC chains to analyze")?;
     println!("Analyzing Cold Chain (ID: {}) with {} species.", cold_chain.id, cold_chain.species_set.len());
+    report_cold_acceptance(cold_chain);
 
     // 3. Export MCMC Trace (QC)
-    let trace_path = format!("{}_mcmc_trace.tsv", output_prefix);
-    export_trace(&trace_path, cold_chain, burnin_ratio)?;
+    let trace_path = output_file(output_prefix, "mcmc_trace.tsv");
+    export_trace(&trace_path, cold_chain, mcmc_burnin)?;
 
-    // 4. Bayes Factors (Parallel)
-    println!("Computing Bayes Factors for {} species...", cold_chain.species_set.len());
-    
-    let mut candidate_indices: Vec<usize> = cold_chain.species_set.iter().cloned().collect();
-    candidate_indices.sort_unstable(); // Deterministic
+    if let Some(path) = &report.species_trace_path {
+        export_species_trace(path, ctx, cold_chain)?;
+    }
 
-    let h1_log_l = cold_chain.current_log_likelihood;
+    // Goodness of fit: the cold set vs the all-Unknown null, both at the learned floor
+    let (log_lr_null, penalty) = log_lr_vs_null(ctx, cold_chain, learned_floor);
+    println!("Log-likelihood ratio vs all-Unknown null: {:.2}", log_lr_null);
+    if log_lr_null + penalty <= 0.0 {
+        eprintln!("Warning: the selected model does not beat the all-Unknown null model after the presence penalty; there may be no real signal in this sample.");
+    }
 
-    let bayes_factors: HashMap<usize, f64> = candidate_indices.par_iter().map(|&sp_idx| {
-        let mut h0_set = cold_chain.species_set.clone();
-        h0_set.remove(&sp_idx);
-        
-        let n_h0 = h0_set.len();
-        let mut init_abund = HashMap::new();
-        if n_h0 > 0 {
-            let mut rng = rand::rng();
-            let gamma = Gamma::new(1.0, 1.0).unwrap();
-            let mut sum = 0.0;
-            let mut samples = Vec::with_capacity(n_h0);
-            for _ in 0..n_h0 {
-                let x = gamma.sample(&mut rng);
-                samples.push(x);
-                sum += x;
-            }
-            for (i, &id) in h0_set.iter().enumerate() {
-                init_abund.insert(id, samples[i] / sum);
+    let convergence_path = output_file(output_prefix, "convergence.tsv");
+    export_convergence(&convergence_path, chains, mcmc_burnin)?;
+
+    let chain_stats_path = output_file(output_prefix, "chain_stats.tsv");
+    export_chain_stats(&chain_stats_path, chains)?;
+
+    // 3b. Pool all chains at temperature 1.0
+    let cold_chains: Vec<&ChainState> = chains.iter().filter(|c| c.temperature >= 1.0).collect();
+    let inclusion = posterior_inclusion(&cold_chains, mcmc_burnin);
+
+    let (mut species_set, mut h1_log_l) = if cold_chains.len() > 1 {
+        pool_cold_chains(ctx, &cold_chains, &inclusion, learned_floor)
+    } else {
+        (cold_chain.species_set.clone(), cold_chain.current_log_likelihood)
+    };
+
+    // 3c. Breadth filter: refit without low-breadth species so their reads move elsewhere
+    let mut filtered: Vec<FilteredTaxon> = Vec::new();
+    if let (Some(min_breadth), Some(coverage)) = (report.min_breadth, coverage_breadth) {
+        let low = low_breadth_species(ctx, &species_set, coverage, min_breadth);
+        if !low.is_empty() {
+            println!("Removed {} species with coverage breadth below {}.", low.len(), min_breadth);
+            for &sp in &low {
+                species_set.remove(&sp);
+                filtered.push(FilteredTaxon { idx: sp, reason: "min_breadth", mean_abundance: None });
             }
+            h1_log_l = refit_log_likelihood(ctx, &species_set, learned_floor);
         }
+    }
 
-        let (h0_log_l, _, _) = run_mini_em(
-            ctx, 
-            &h0_set, 
-            &init_abund, 
-            learned_floor, 
-            10 
-        );
-
-        let bf = h1_log_l - h0_log_l + ctx.lpenalty; 
-        let log10_bf = bf / 10.0_f64.ln(); 
-        
-        (sp_idx, log10_bf)
-    }).collect();
+    // 4. Bayes Factors (Parallel)
+    let bayes_factors_for = |species_set: &HashSet<usize>, h1_log_l: f64| {
+        println!("Computing Bayes Factors for {} species...", species_set.len());
+        let removal = compute_bayes_factors(ctx, species_set, h1_log_l, learned_floor, seed, BfNull::Removal);
+        let unknown = report.bf_unknown.then(|| {
+            println!("Computing Bayes Factors against the Unknown bin...");
+            compute_bayes_factors(ctx, species_set, h1_log_l, learned_floor, seed, BfNull::Unknown)
+        });
+        (removal, unknown)
+    };
+    let (mut bayes_factors, mut bayes_factors_unknown) = bayes_factors_for(&species_set, h1_log_l);
 
     // 5. Final Gibbs Sampler (Read Assignments + CI)
-    println!("Running Final Gibbs Sampler ({} iter + {} burnin)...", gibbs_iter, gibbs_burnin);
-    let (final_stats, read_assignments, count_history) = run_gibbs_sampler(
+    println!("Running Final Gibbs Sampler ({} iter + {} burnin)...", gibbs.iterations, gibbs.burnin);
+    let (mut final_stats, mut read_assignments, mut count_history, mut abund_history) = run_gibbs_sampler(
         ctx,
-        &cold_chain.species_set,
+        &species_set,
         learned_floor,
-        gibbs_iter,
-        gibbs_burnin,
+        &gibbs,
+        seed,
     );
 
+    // 5b. Abundance filter: drop trace species and rerun so the rest are renormalized
+    // and their reads reassigned
+    if let Some(min_abundance) = report.min_abundance {
+        let mut low: Vec<(usize, f64)> = final_stats.iter()
+            .filter(|(_, stats)| stats.mean < min_abundance)
+            .map(|(&sp, stats)| (sp, stats.mean))
+            .collect();
+        low.sort_unstable_by_key(|&(sp, _)| sp);
+        if !low.is_empty() {
+            println!("Removed {} species with mean abundance below {}; rerunning the Gibbs sampler.", low.len(), min_abundance);
+            for &(sp, mean) in &low {
+                species_set.remove(&sp);
+                filtered.push(FilteredTaxon { idx: sp, reason: "min_abundance", mean_abundance: Some(mean) });
+            }
+            h1_log_l = refit_log_likelihood(ctx, &species_set, learned_floor);
+            (bayes_factors, bayes_factors_unknown) = bayes_factors_for(&species_set, h1_log_l);
+            (final_stats, read_assignments, count_history, abund_history) =
+                run_gibbs_sampler(ctx, &species_set, learned_floor, &gibbs, seed);
+        }
+    }
+    if report.min_breadth.is_some() || report.min_abundance.is_some() {
+        let filtered_path = output_file(output_prefix, "filtered.tsv");
+        export_filtered(&filtered_path, ctx, &filtered, coverage_breadth, &inclusion, taxonomy_map)?;
+    }
+
     // 6. Export Main Results
-    let results_path = format!("{}_results.tsv", output_prefix);
-    export_summary(
-        &results_path, 
-        ctx, 
-        &final_stats, 
-        &bayes_factors, 
-        learned_floor,
-        taxonomy_map
-    )?;
+    let num_active = species_set.len();
+    let unknown_reads = read_assignments.iter().filter(|(best_idx, _)| *best_idx == num_active).count();
+    if gibbs.tie_epsilon.is_some() {
+        let ambiguous = read_assignments.iter().filter(|(best_idx, _)| *best_idx > num_active).count();
+        println!("Ambiguous read assignments: {}", ambiguous);
+    }
+    let mut unknown_stats = abundance_stats(abund_history.iter().map(|v| v[num_active]).collect(), gibbs.ci_method);
+    append_em_divergence(&convergence_path, ctx, &final_stats)?;
+    let mut read_intervals = read_count_intervals(&species_set, &count_history);
+    let weights = read_weight_summary(&ctx.read_weights);
+    println!("Reads: {} raw, {:.1} weighted, {:.1} effective", weights.raw, weights.weighted, weights.effective);
+    let ci_scale = report.ess_adjusted_ci.then(|| (weights.weighted / weights.effective).sqrt());
+    if let Some(scale) = ci_scale {
+        println!("Widening credible intervals by a factor of {:.3} for the effective read count.", scale);
+        for (idx, stats) in final_stats.iter_mut() {
+            widen_interval(stats, scale);
+            if let Some(reads) = read_intervals.taxa.get_mut(idx) {
+                *reads = widen_read_interval(*reads, stats.mean * weights.weighted, scale, weights.weighted);
+            }
+        }
+        widen_interval(&mut unknown_stats, scale);
+        read_intervals.unknown = widen_read_interval(read_intervals.unknown, unknown_stats.mean * weights.weighted, scale, weights.weighted);
+    }
+    let integer_reads = report.integer_reads.then(|| integer_read_counts(ctx, &final_stats, unknown_stats.mean));
+    let summary = Summary {
+        ctx,
+        abundances: &final_stats,
+        bayes_factors: &bayes_factors,
+        inclusion: &inclusion,
+        floor: learned_floor,
+        taxonomy_map,
+        unknown_reads,
+        coverage: coverage_breadth,
+        unknown: report.report_unknown.then_some(&unknown_stats),
+        integer_reads: integer_reads.as_ref(),
+        read_intervals: &read_intervals,
+        bayes_factors_unknown: bayes_factors_unknown.as_ref(),
+        merged_taxa: &report.merged_taxa,
+        host_taxa,
+        separated_reads: report.separated_reads,
+        log_lr_null,
+        ci_method: gibbs.ci_method,
+        sort_by: report.sort_by,
+        weights,
+        ci_scale,
+    };
+    match report.output_format {
+        OutputFormat::Tsv => {
+            let results_path = output_file(output_prefix, "results.tsv");
+            export_summary(&results_path, &summary)?;
+        }
+        OutputFormat::Json => {
+            let results_path = output_file(output_prefix, "results.json");
+            export_summary_json(&results_path, &summary, run_metadata)?;
+        }
+    }
+
+    // 6b. Bootstrap over reads (frequentist intervals, kept apart from the Bayesian results)
+    if let Some(replicates) = report.bootstrap {
+        let means: HashMap<usize, f64> = final_stats.iter().map(|(&idx, s)| (idx, s.mean)).collect();
+        let boot = bootstrap_abundances(ctx, &species_set, &means, learned_floor, replicates, seed);
+        let bootstrap_path = output_file(output_prefix, "bootstrap.tsv");
+        export_bootstrap(&bootstrap_path, ctx, &boot, replicates, taxonomy_map)?;
+    }
 
     // 7. Export Read Assignments (Always output if names exist)
-    if !index_to_read_name.is_empty() {
-        let reads_path = format!("{}_read_assignments.tsv", output_prefix);
+    if !index_to_read_name.is_empty() && report.fractional_assignments {
+        let reads_path = output_file(output_prefix, "read_assignments.tsv");
+        let mut species_means: Vec<(usize, f64)> = final_stats.iter().map(|(&idx, s)| (idx, s.mean)).collect();
+        species_means.sort_unstable_by_key(|&(idx, _)| idx);
+        export_fractional_assignments(
+            &reads_path,
+            ctx,
+            index_to_read_name,
+            taxonomy_map,
+            &species_means,
+            learned_floor * unknown_stats.mean,
+            report.compress,
+        )?;
+    } else if !index_to_read_name.is_empty() {
+        let reads_path = output_file(output_prefix, "read_assignments.tsv");
         export_read_assignments(
             &reads_path,
             ctx,
             &read_assignments,
             index_to_read_name,
             taxonomy_map,
-            &cold_chain.species_set
+            &species_set,
+            report.compress,
         )?;
     }
 
+    // 7b. Export Unknown Read List (Conditional)
+    if let Some(path) = &report.unknown_reads_path {
+        export_unknown_reads(path, &read_assignments, index_to_read_name, num_active)?;
+    }
+
+    // 7c. Posterior Predictive Check (Conditional)
+    if report.posterior_predictive {
+        let ppc_path = output_file(output_prefix, "ppc.tsv");
+        let ppc = posterior_predictive_check(ctx, &species_set, learned_floor, &abund_history, &count_history, seed);
+        export_ppc(&ppc_path, ctx, &species_set, &ppc)?;
+    }
+
     // 8. Export Posterior Samples (Conditional)
-    if output_posterior {
-        let post_path = format!("{}_posterior_samples.tsv", output_prefix);
+    if report.output_posterior {
+        let post_path = output_file(output_prefix, "posterior_samples.tsv");
         export_posterior_samples(
             &post_path,
             ctx,
             &count_history,
-            &cold_chain.species_set
+            &species_set,
+            &gibbs,
+            report.compress,
         )?;
     }
 
-    Ok(())
-}
-
-fn run_gibbs_sampler(
-    ctx: &McmcContext,
-    species_set: &HashSet<usize>,
-    unk_prob: f64,
-    iterations: usize,
-    burnin: usize,
-) -> (HashMap<usize, AbundanceStats>, Vec<(usize, f64)>, Vec<Vec<f64>>) {
-    
-    let mut active_indices: Vec<usize> = species_set.iter().cloned().collect();
-    active_indices.sort_unstable();
+    // 8b. Export Abundance History (Conditional)
+    if let Some(path) = &report.abundance_history_path {
+        println!("Exporting abundance history to: {}", path);
+        let mut file = BufWriter::new(File::create(path)?);
+        write_history(&mut file, ctx, &abund_history, &species_set, &gibbs, 6)?;
+        file.flush()?;
+    }
 
-    let num_active = active_indices.len();
-    let mut abundances = vec![1.0 / (num_active + 1) as f64; num_active + 1];
+    let mut active_vec: Vec<usize> = species_set.iter().cloned().collect();
+    active_vec.sort_unstable();
+    let taxa = active_vec.iter()
+        .map(|idx| TaxonResult {
+            taxon_id: ctx.taxons[*idx].clone(),
+            stats: final_stats[idx].clone(),
+            log10_bf: bayes_factors.get(idx).copied().unwrap_or(0.0),
+            posterior_inclusion: inclusion.get(idx).copied().unwrap_or(0.0),
+            log10_bf_unknown: bayes_factors_unknown.as_ref().map(|bf| bf.get(idx).copied().unwrap_or(0.0)),
+            coverage_breadth: coverage_breadth.map(|c| c.get(&ctx.taxons[*idx]).copied().unwrap_or(0.0)),
+            is_host: host_taxa.contains(&ctx.taxons[*idx]),
+            merged_taxa: report.merged_taxa.get(&ctx.taxons[*idx]).cloned().unwrap_or_default(),
+        })
+        .collect();
 
-    let mut abund_history: Vec<Vec<f64>> = Vec::with_capacity(iterations);
-    let mut count_history: Vec<Vec<f64>> = Vec::with_capacity(iterations);
+    Ok(InferenceResult {
+        taxa,
+        read_assignments,
+        unknown_floor: learned_floor,
+        unknown: unknown_stats,
+    })
+}
 
-    for i in 0..(iterations + burnin) {
-        let mut counts = vec![0.0; num_active + 1];
+/// Fast path for `--em-only`: assigns each read to its maximum-posterior taxon under the
+/// step 2 EM abundances (renormalized over the retained taxa) and writes `_results.tsv`
+/// and `_read_assignments.tsv`. No MCMC, Unknown bin, Bayes factors or credible intervals;
+/// reads without alignments are reported as Unknown.
+pub fn run_em_only(
+    ctx: &McmcContext,
+    taxonomy_map: Option<&HashMap<String, String>>,
+    index_to_read_name: &[String],
+    report: &ReportConfig,
+) -> Result<InferenceResult> {
+    println!("\n=== EM-ONLY CLASSIFICATION ===");
+    check_row_counts(ctx, index_to_read_name)?;
+    let num_taxa = ctx.taxons.len();
+    let weight_sum: f64 = ctx.taxon_weights.iter().sum();
+    let abundances: Vec<f64> = ctx.taxon_weights.iter()
+        .map(|w| if weight_sum > 0.0 { w / weight_sum } else { 0.0 })
+        .collect();
 
-        let iter_counts: Vec<Vec<f64>> = ctx.matrix.row_iter()
-            .enumerate()
-            .collect::<Vec<_>>()
-            .par_iter()
-            .map(|(row_idx, row_vec)| {
-                let mut rng = rand::rng();
-                let mut local_counts = vec![0.0; num_active + 1];
-                let mut weights = Vec::with_capacity(num_active + 1);
-                let mut total_w = 0.0;
+    let read_assignments: Vec<(usize, f64)> = ctx.matrix.row_iter()
+        .map(|row_vec| {
+            let mut best = (num_taxa, 0.0);
+            let mut total = 0.0;
+            for (&col, &p) in row_vec.col_indices().iter().zip(row_vec.values()) {
+                let term = p * abundances[col];
+                total += term;
+                if term > best.1 { best = (col, term); }
+            }
+            if best.0 < num_taxa && total > 0.0 { (best.0, best.1 / total) } else { (num_taxa, 1.0) }
+        })
+        .collect();
 
-                for j in 0..num_active {
-                    let col_idx = active_indices[j];
-                    let mut p_val = 0.0;
-                    for (c, &v) in row_vec.col_indices().iter().zip(row_vec.values()) {
-                        if *c == col_idx { p_val = v; break; }
-                    }
-                    let w = p_val * abundances[j];
-                    weights.push(w);
-                    total_w += w;
-                }
+    let mut assigned = vec![0.0; num_taxa + 1];
+    for (row_idx, &(idx, _)) in read_assignments.iter().enumerate() {
+        assigned[idx] += ctx.read_weights[row_idx];
+    }
+    let total_reads: f64 = ctx.read_weights.iter().sum();
 
-                let w_unk = unk_prob * abundances[num_active];
-                weights.push(w_unk);
-                total_w += w_unk;
+    let results_path = output_file(&report.output_prefix, "results.tsv");
+    println!("Exporting EM-only results to: {}", results_path);
+    let mut file = BufWriter::new(File::create(&results_path)?);
+    writeln!(file, "# Mode: em-only (step 2 EM abundances; no credible intervals)")?;
+    writeln!(file, "TaxonID\tScientificName\tMeanAbundance\tEstimatedReads\tAssignedReads")?;
+    for (idx, tid) in ctx.taxons.iter().enumerate() {
+        let name = scientific_name(taxonomy_map, tid);
+        writeln!(file, "{}\t{}\t{:.6}\t{:.2}\t{:.2}", tid, name, abundances[idx], abundances[idx] * total_reads, assigned[idx])?;
+    }
+    writeln!(file, "# Unassigned_Reads: {:.0}", assigned[num_taxa])?;
+    file.flush()?;
 
-                let sample = rng.random::<f64>() * total_w;
-                let mut cumulative = 0.0;
-                let mut chosen_idx = num_active;
+    let all_taxa: HashSet<usize> = (0..num_taxa).collect();
+    if !index_to_read_name.is_empty() {
+        let reads_path = output_file(&report.output_prefix, "read_assignments.tsv");
+        export_read_assignments(&reads_path, ctx, &read_assignments, index_to_read_name, taxonomy_map, &all_taxa, report.compress)?;
+    }
 
-                for (k, &w) in weights.iter().enumerate() {
-                    cumulative += w;
-                    if sample <= cumulative { chosen_idx = k; break; }
-                }
+    let taxa = ctx.taxons.iter().enumerate()
+        .map(|(idx, tid)| TaxonResult {
+            taxon_id: tid.clone(),
+            stats: AbundanceStats { mean: abundances[idx], ci_low: abundances[idx], ci_high: abundances[idx] },
+            log10_bf: 0.0,
+            posterior_inclusion: 1.0,
+            log10_bf_unknown: None,
+            coverage_breadth: None,
+            is_host: report.host_taxa.contains(tid),
+            merged_taxa: report.merged_taxa.get(tid).cloned().unwrap_or_default(),
+        })
+        .collect();
 
-                local_counts[chosen_idx] += ctx.read_weights[*row_idx];
-                local_counts
-            })
-            .collect();
+    Ok(InferenceResult {
+        taxa,
+        read_assignments,
+        unknown_floor: 0.0,
+        unknown: AbundanceStats::default(),
+    })
+}
 
-        for c in iter_counts {
-            for (idx, val) in c.iter().enumerate() { counts[idx] += val; }
+/// Fraction of post-burnin records (pooled over the given chains) in which each species is present
+fn posterior_inclusion(chains: &[&ChainState], burnin: McmcBurnin) -> HashMap<usize, f64> {
+    let mut counts: HashMap<usize, usize> = HashMap::new();
+    let mut total = 0;
+    for chain in chains {
+        let start = burnin.start(&chain.history);
+        for rec in &chain.history[start..] {
+            total += 1;
+            for &sp in &rec.species {
+                *counts.entry(sp).or_insert(0) += 1;
+            }
         }
+    }
+    if total == 0 {
+        // No history (e.g. zero iterations): fall back to final membership
+        return chains.iter()
+            .flat_map(|c| c.species_set.iter())
+            .map(|&sp| (sp, 1.0))
+            .collect();
+    }
+    counts.into_iter().map(|(sp, n)| (sp, n as f64 / total as f64)).collect()
+}
 
-        let alpha: Vec<f64> = counts.iter().map(|&n| n + 1.0).collect();
-        let mut rng = rand::rng(); 
-        let mut samples = Vec::with_capacity(num_active + 1);
-        let mut sum = 0.0;
-        for &a in &alpha {
-            let x = Gamma::new(a, 1.0).unwrap().sample(&mut rng);
-            samples.push(x);
-            sum += x;
-        }
-        for x in &mut samples { *x /= sum; }
-        abundances = samples;
+/// Builds a consensus state from several temperature-1.0 chains: species with
+/// pooled inclusion >= 0.5 (median probability model), re-fitted by mini-EM
+/// starting from the chains' averaged final abundances.
+/// Returns the species set and its penalized log-likelihood.
+fn pool_cold_chains(
+    ctx: &McmcContext,
+    cold_chains: &[&ChainState],
+    inclusion: &HashMap<usize, f64>,
+    unk_prob: f64,
+) -> (HashSet<usize>, f64) {
+    let species_set: HashSet<usize> = inclusion.iter()
+        .filter(|&(_, &p)| p >= 0.5)
+        .map(|(&sp, _)| sp)
+        .collect();
 
-        if i >= burnin {
-            abund_history.push(abundances.clone());
-            count_history.push(counts);
+    let mut init_abund: HashMap<usize, f64> = HashMap::new();
+    for chain in cold_chains {
+        for &sp in &species_set {
+            *init_abund.entry(sp).or_insert(0.0) += chain.abundances.get(&sp).copied().unwrap_or(0.0) / cold_chains.len() as f64;
         }
     }
+    // Species never held at the end of any chain still need a starting weight
+    let fallback = 1.0 / (species_set.len().max(1) as f64);
+    for v in init_abund.values_mut() {
+        if *v <= 0.0 { *v = fallback; }
+    }
 
-    let mut final_stats_map = HashMap::new();
-    let mut mean_abundances_vec = vec![0.0; num_active + 1];
+    let (log_l, _, _) = run_mini_em(ctx, &species_set, &init_abund, unk_prob, 10);
+    let penalized = log_l + ctx.set_penalty(&species_set);
 
-    for i in 0..num_active {
-        let mut vals: Vec<f64> = abund_history.iter().map(|vec| vec[i]).collect();
-        vals.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    println!("Pooled {} cold chains: {} species with inclusion >= 0.5.", cold_chains.len(), species_set.len());
+    (species_set, penalized)
+}
 
-        let mean: f64 = vals.iter().sum::<f64>() / iterations as f64;
-        let ci_low = vals[(iterations as f64 * 0.025).round() as usize].clone();
-        let ci_high = vals[(iterations as f64 * 0.975).round() as usize].clone();
+/// Penalized log-likelihood of `species_set` from a mini-EM started at uniform abundances
+fn refit_log_likelihood(ctx: &McmcContext, species_set: &HashSet<usize>, unk_prob: f64) -> f64 {
+    let init = 1.0 / species_set.len().max(1) as f64;
+    let init_abund: HashMap<usize, f64> = species_set.iter().map(|&sp| (sp, init)).collect();
+    let (log_l, _, _) = run_mini_em(ctx, species_set, &init_abund, unk_prob, 10);
+    log_l + ctx.set_penalty(species_set)
+}
 
-        final_stats_map.insert(active_indices[i], AbundanceStats { mean, ci_low, ci_high });
-        mean_abundances_vec[i] = mean;
-    }
-    
-    mean_abundances_vec[num_active] = abund_history.iter().map(|v| v[num_active]).sum::<f64>() / iterations as f64;
+/// Unpenalized log-likelihood of `abundances` (Unknown taking the remainder) with the
+/// per-read Unknown probability fixed at `floor`
+fn log_likelihood_at(ctx: &McmcContext, abundances: &HashMap<usize, f64>, floor: f64) -> f64 {
+    let unk_abundance = (1.0 - abundances.values().sum::<f64>()).max(0.0);
+    ctx.matrix.row_iter().zip(&ctx.read_weights)
+        .map(|(row, w)| {
+            let known: f64 = row.col_indices().iter().zip(row.values())
+                .filter_map(|(c, p)| abundances.get(c).map(|a| p * a))
+                .sum();
+            w * (known + floor * unk_abundance + 1e-300).ln()
+        })
+        .sum()
+}
 
-    let read_assignments: Vec<(usize, f64)> = ctx.matrix.row_iter()
-        .map(|row_vec| {
-            let mut best_idx = num_active;
-            let mut best_prob = unk_prob * mean_abundances_vec[num_active];
-            let mut total_prob = best_prob;
+/// Unpenalized log-likelihood ratio of `chain`'s fit against the all-Unknown model, both
+/// at the same Unknown probability `floor`, and the set's presence penalty (the null has
+/// no species, so no penalty)
+fn log_lr_vs_null(ctx: &McmcContext, chain: &ChainState, floor: f64) -> (f64, f64) {
+    let model = log_likelihood_at(ctx, &chain.abundances, floor);
+    let null = log_likelihood_at(ctx, &HashMap::new(), floor);
+    (model - null, ctx.set_penalty(&chain.species_set))
+}
 
-            for (i, &col_idx) in active_indices.iter().enumerate() {
-                let mut p_val = 0.0;
-                for (c, &v) in row_vec.col_indices().iter().zip(row_vec.values()) {
-                    if *c == col_idx { p_val = v; break; }
-                }
-                let term = p_val * mean_abundances_vec[i];
-                total_prob += term;
-                if term > best_prob {
-                    best_prob = term;
-                    best_idx = i;
-                }
-            }
-            (best_idx, if total_prob > 0.0 { best_prob / total_prob } else { 1.0 })
-        })
-        .collect();
+/// A species removed from the report by `--min-breadth` or `--min-abundance`
+struct FilteredTaxon {
+    idx: usize,
+    reason: &'static str,
+    /// Gibbs mean abundance before removal (abundance filter only)
+    mean_abundance: Option<f64>,
+}
 
-    (final_stats_map, read_assignments, count_history)
+/// Species in `species_set` whose breadth of coverage is below `min_breadth`, sorted
+fn low_breadth_species(
+    ctx: &McmcContext,
+    species_set: &HashSet<usize>,
+    coverage: &HashMap<String, f64>,
+    min_breadth: f64,
+) -> Vec<usize> {
+    let mut filtered: Vec<usize> = species_set.iter().cloned()
+        .filter(|&sp| coverage.get(&ctx.taxons[sp]).copied().unwrap_or(0.0) < min_breadth)
+        .collect();
+    filtered.sort_unstable();
+    filtered
 }
 
-fn export_posterior_samples(
+fn export_filtered(
     path: &str,
     ctx: &McmcContext,
-    history: &[Vec<f64>],
-    active_indices_set: &HashSet<usize>,
+    filtered: &[FilteredTaxon],
+    coverage: Option<&HashMap<String, f64>>,
+    inclusion: &HashMap<usize, f64>,
+    taxonomy_map: Option<&HashMap<String, String>>,
 ) -> Result<()> {
-    println!("Exporting posterior distribution to: {}", path);
+    println!("Exporting filtered species to: {}", path);
     let mut file = BufWriter::new(File::create(path)?);
-    let mut active_vec: Vec<usize> = active_indices_set.iter().cloned().collect();
-    active_vec.sort_unstable();
-
-    write!(file, "Iteration")?;
-    for &idx in &active_vec { write!(file, "\t{}", ctx.taxons[idx])?; }
-    writeln!(file, "\tUnknown")?;
-
-    for (i, counts) in history.iter().enumerate() {
-        write!(file, "{}", i)?;
-        for val in counts { write!(file, "\t{:.2}", val)?; }
-        writeln!(file)?;
+    writeln!(file, "TaxonID\tScientificName\tReason\tCoverageBreadth\tMeanAbundance\tPosterior")?;
+    for taxon in filtered {
+        let tid = &ctx.taxons[taxon.idx];
+        let name = scientific_name(taxonomy_map, tid);
+        let breadth = coverage.map(|c| format!("{:.6}", c.get(tid).copied().unwrap_or(0.0))).unwrap_or_else(|| "NA".to_string());
+        let mean = taxon.mean_abundance.map(|m| format!("{:.6}", m)).unwrap_or_else(|| "NA".to_string());
+        writeln!(file, "{}\t{}\t{}\t{}\t{}\t{:.4}", tid, name, taxon.reason, breadth, mean,
+            inclusion.get(&taxon.idx).copied().unwrap_or(0.0))?;
     }
+    file.flush()?;
     Ok(())
 }
 
-fn export_summary(
+/// Mini-EM iterations per bootstrap replicate (started from the Gibbs means)
+const BOOTSTRAP_EM_ITER: usize = 50;
+
+/// Bootstrap abundance distributions (sorted by species index, Unknown last)
+struct BootstrapResult {
+    taxa: Vec<(usize, AbundanceStats)>,
+    unknown: AbundanceStats,
+}
+
+/// Poisson bootstrap over reads: each replicate multiplies every read weight by a
+/// Poisson(1) draw and refits the abundances of the fixed `species_set` by mini-EM.
+/// Replicates run in parallel, each on its own stream derived from `seed`.
+fn bootstrap_abundances(
+    ctx: &McmcContext,
+    species_set: &HashSet<usize>,
+    init: &HashMap<usize, f64>,
+    unk_prob: f64,
+    replicates: usize,
+    seed: u64,
+) -> BootstrapResult {
+    println!("Running {} bootstrap replicates over reads...", replicates);
+    let poisson = Poisson::new(1.0).unwrap();
+    let fits: Vec<HashMap<usize, f64>> = (0..replicates).into_par_iter()
+        .map(|rep| {
+            let mut rng = StdRng::seed_from_u64(seed ^ (rep as u64).wrapping_mul(0xA076_1D64_78BD_642F));
+            let weights: Vec<f64> = ctx.read_weights.iter().map(|w| w * poisson.sample(&mut rng)).collect();
+            let (_, abundances, _) = run_mini_em_reweighted(ctx, species_set, init, unk_prob, BOOTSTRAP_EM_ITER, &weights);
+            abundances
+        })
+        .collect();
+
+    let mut indices: Vec<usize> = species_set.iter().cloned().collect();
+    indices.sort_unstable();
+    let taxa = indices.iter()
+        .map(|&idx| (idx, abundance_stats(fits.iter().map(|f| f[&idx]).collect(), CiMethod::Quantile)))
+        .collect();
+    let unknown = abundance_stats(fits.iter().map(|f| (1.0 - f.values().sum::<f64>()).max(0.0)).collect(), CiMethod::Quantile);
+    BootstrapResult { taxa, unknown }
+}
+
+fn export_bootstrap(
     path: &str,
     ctx: &McmcContext,
-    abundances: &HashMap<usize, AbundanceStats>,
-    bayes_factors: &HashMap<usize, f64>,
-    floor: f64,
+    boot: &BootstrapResult,
+    replicates: usize,
     taxonomy_map: Option<&HashMap<String, String>>,
 ) -> Result<()> {
+    println!("Exporting bootstrap intervals to: {}", path);
     let mut file = BufWriter::new(File::create(path)?);
-    writeln!(file, "TaxonID\tScientificName\tMeanAbundance\tCI_Lower\tCI_Upper\tEstimatedReads\tLog10BF\tPosterior")?;
-    let total_reads: f64 = ctx.read_weights.iter().sum();
-    for (&idx, stats) in abundances {
-        let tid = &ctx.taxons[idx];
-        let name = taxonomy_map.and_then(|m| m.get(tid)).map(|s| s.as_str()).unwrap_or("Unknown");
-        writeln!(file, "{}\t{}\t{:.6}\t{:.6}\t{:.6}\t{:.2}\t{:.2}\t1.00", 
-            tid, name, stats.mean, stats.ci_low, stats.ci_high, stats.mean * total_reads, bayes_factors.get(&idx).unwrap_or(&0.0))?;
+    writeln!(file, "# Bootstrap_Replicates: {}", replicates)?;
+    writeln!(file, "# Frequentist 2.5%/97.5% percentile intervals over reads resampled with the species set fixed; not Bayesian credible intervals")?;
+    writeln!(file, "TaxonID\tScientificName\tBootstrapMean\tBootstrap_CI_Lower\tBootstrap_CI_Upper")?;
+    for (idx, stats) in &boot.taxa {
+        let tid = &ctx.taxons[*idx];
+        writeln!(file, "{}\t{}\t{:.6}\t{:.6}\t{:.6}", tid, scientific_name(taxonomy_map, tid), stats.mean, stats.ci_low, stats.ci_high)?;
     }
-    writeln!(file, "# Unknown_Bin_Probability_Floor: {:.4e}", floor)?;
+    let unk = &boot.unknown;
+    writeln!(file, "Unknown\tUnknown\t{:.6}\t{:.6}\t{:.6}", unk.mean, unk.ci_low, unk.ci_high)?;
+    file.flush()?;
     Ok(())
 }
 
-fn export_read_assignments(
-    path: &str,
-    ctx: &McmcContext,
+/// Null hypothesis of a Bayes factor for species X
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BfNull {
+    /// X is absent; its reads are redistributed over the remaining species
+    Removal,
+    /// X is absent and the Unknown bin can explain its reads as well as X did
+    /// (evidence that X exists rather than its reads coming from a novel organism)
+    Unknown,
+}
+
+/// Log10 Bayes factor per species in `species_set`. For species X:
+/// `BF = logL(H1) - logL(H0: set without X) + penalty(X)`, where H0 is refit by
+/// mini-EM from random Dirichlet starting abundances (see `ProposalInit`). Each species draws
+/// these from its own stream derived from `seed` and its index, so results are
+/// reproducible and independent of rayon scheduling.
+pub fn compute_bayes_factors(
+    ctx: &McmcContext,
+    species_set: &HashSet<usize>,
+    h1_log_l: f64,
+    unk_prob: f64,
+    seed: u64,
+    null: BfNull,
+) -> HashMap<usize, f64> {
+    let mut candidate_indices: Vec<usize> = species_set.iter().cloned().collect();
+    candidate_indices.sort_unstable(); // Deterministic
+
+    candidate_indices.par_iter().map(|&sp_idx| {
+        let h0_species: Vec<usize> = candidate_indices.iter().cloned().filter(|&i| i != sp_idx).collect();
+        let h0_set: HashSet<usize> = h0_species.iter().cloned().collect();
+
+        let mut rng = StdRng::seed_from_u64(seed ^ (sp_idx as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        let init_abund = ctx.proposal.sample(&h0_species, None, &mut rng);
+
+        let (h0_log_l, _, _) = match null {
+            BfNull::Removal => run_mini_em(ctx, &h0_set, &init_abund, unk_prob, 10),
+            BfNull::Unknown => run_mini_em_absorbing(ctx, &h0_set, &init_abund, unk_prob, 10, sp_idx),
+        };
+
+        let bf = h1_log_l - h0_log_l + ctx.species_penalty(sp_idx);
+        let log10_bf = bf / 10.0_f64.ln(); 
+        
+        (sp_idx, log10_bf)
+    }).collect()
+}
+
+/// (stats per species, read assignments, retained read counts, retained abundances).
+/// Count and abundance vectors are ordered by sorted species index with Unknown last.
+type GibbsOutput = (HashMap<usize, AbundanceStats>, Vec<(usize, f64)>, Vec<Vec<f64>>, Vec<Vec<f64>>);
+
+/// Retained (read counts, abundances) of one Gibbs run, in the same layout as `GibbsOutput`
+type GibbsHistory = (Vec<Vec<f64>>, Vec<Vec<f64>>);
+
+/// Stream offset for the Gibbs replicates, keeping replicate 0 apart from the streams
+/// derived from the bare `seed` (e.g. the Bayes factor of species 0)
+const GIBBS_STREAM: u64 = 0x6A09_E667_F3BC_C908;
+
+/// Runs `config.replicates` independent samplers (in parallel) and pools their retained
+/// samples, so disagreement between replicates widens the credible intervals. Each
+/// replicate draws its start and all of its samples from its own stream derived from `seed`.
+fn run_gibbs_sampler(
+    ctx: &McmcContext,
+    species_set: &HashSet<usize>,
+    unk_prob: f64,
+    config: &GibbsConfig,
+    seed: u64,
+) -> GibbsOutput {
+    let mut active_indices: Vec<usize> = species_set.iter().cloned().collect();
+    active_indices.sort_unstable();
+    let num_active = active_indices.len();
+
+    let replicates: Vec<GibbsHistory> = (0..config.replicates.max(1))
+        .into_par_iter()
+        .map(|rep| {
+            let mut rng = StdRng::seed_from_u64(seed ^ GIBBS_STREAM ^ (rep as u64).wrapping_mul(0xD1B5_4A32_D192_ED03));
+            let init = if rep == 0 {
+                vec![1.0 / (num_active + 1) as f64; num_active + 1]
+            } else {
+                let draws: Vec<f64> = (0..=num_active).map(|_| Gamma::new(1.0, 1.0).unwrap().sample(&mut rng)).collect();
+                let sum: f64 = draws.iter().sum();
+                draws.iter().map(|x| x / sum).collect()
+            };
+            gibbs_chain(ctx, &active_indices, unk_prob, config, init, &mut rng)
+        })
+        .collect();
+    if replicates.len() > 1 {
+        println!("Pooled {} Gibbs replicates.", replicates.len());
+    }
+    let (count_history, abund_history): GibbsHistory = replicates.into_iter()
+        .fold((Vec::new(), Vec::new()), |(mut counts, mut abunds), (c, a)| {
+            counts.extend(c);
+            abunds.extend(a);
+            (counts, abunds)
+        });
+
+    let mut final_stats_map = HashMap::new();
+    let mut mean_abundances_vec = vec![0.0; num_active + 1];
+
+    for i in 0..num_active {
+        let stats = abundance_stats(abund_history.iter().map(|vec| vec[i]).collect(), config.ci_method);
+        mean_abundances_vec[i] = stats.mean;
+        final_stats_map.insert(active_indices[i], stats);
+    }
+    
+    mean_abundances_vec[num_active] = abund_history.iter().map(|v| v[num_active]).sum::<f64>() / abund_history.len() as f64;
+
+    // Argmax of p * mean abundance. Exact ties keep the earlier candidate: Unknown first,
+    // then species in ascending matrix column order. With a tie epsilon, reads whose
+    // runner-up is within that relative distance of the best are marked ambiguous instead.
+    let read_assignments: Vec<(usize, f64)> = ctx.matrix.row_iter()
+        .map(|row_vec| {
+            let mut best_idx = num_active;
+            let mut best_prob = unk_prob * mean_abundances_vec[num_active];
+            let mut second_prob = 0.0;
+            let mut total_prob = best_prob;
+
+            for (i, &col_idx) in active_indices.iter().enumerate() {
+                let mut p_val = 0.0;
+                for (c, &v) in row_vec.col_indices().iter().zip(row_vec.values()) {
+                    if *c == col_idx { p_val = v; break; }
+                }
+                let term = p_val * mean_abundances_vec[i];
+                total_prob += term;
+                if term > best_prob {
+                    second_prob = best_prob;
+                    best_prob = term;
+                    best_idx = i;
+                } else if term > second_prob {
+                    second_prob = term;
+                }
+            }
+            let prob = if total_prob > 0.0 { best_prob / total_prob } else { 1.0 };
+            let tied = config.tie_epsilon.is_some_and(|eps| best_prob > 0.0 && best_prob - second_prob <= eps * best_prob);
+            (if tied { num_active + 1 } else { best_idx }, prob)
+        })
+        .collect();
+
+    (final_stats_map, read_assignments, count_history, abund_history)
+}
+
+/// Matrix rows per Gibbs sampling block (the unit of parallel work and of random streams)
+const GIBBS_ROW_BLOCK: usize = 4096;
+
+/// One Gibbs run from `init` abundances (Unknown last). Each iteration takes a seed from
+/// `rng` for the per-block row draws.
+fn gibbs_chain(
+    ctx: &McmcContext,
+    active_indices: &[usize],
+    unk_prob: f64,
+    config: &GibbsConfig,
+    init: Vec<f64>,
+    rng: &mut StdRng,
+) -> GibbsHistory {
+    let iterations = config.iterations;
+    let burnin = config.burnin;
+    let thin = config.thin;
+    let num_active = active_indices.len();
+    let mut abundances = init;
+
+    let mut abund_history: Vec<Vec<f64>> = Vec::with_capacity(iterations / thin + 1);
+    let mut count_history: Vec<Vec<f64>> = Vec::with_capacity(iterations / thin + 1);
+
+    // Rows are collected once and split into fixed blocks. Each block reuses its count and
+    // weight buffers across rows and draws from its own stream seeded from the iteration
+    // seed and block index; block counts are summed in order, so neither the draws nor the
+    // floating-point totals depend on rayon scheduling.
+    let rows: Vec<_> = ctx.matrix.row_iter().enumerate().collect();
+
+    for i in 0..(iterations + burnin) {
+        let iter_seed: u64 = rng.random();
+        let block_counts: Vec<Vec<f64>> = rows
+            .par_chunks(GIBBS_ROW_BLOCK)
+            .enumerate()
+            .map(|(block, chunk)| {
+                let mut rng = StdRng::seed_from_u64(iter_seed ^ (block as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+                let mut local_counts = vec![0.0; num_active + 1];
+                let mut weights = Vec::with_capacity(num_active + 1);
+                for (row_idx, row_vec) in chunk {
+                    weights.clear();
+                    let mut total_w = 0.0;
+
+                    for j in 0..num_active {
+                        let col_idx = active_indices[j];
+                        let mut p_val = 0.0;
+                        for (c, &v) in row_vec.col_indices().iter().zip(row_vec.values()) {
+                            if *c == col_idx { p_val = v; break; }
+                        }
+                        let w = p_val * abundances[j];
+                        weights.push(w);
+                        total_w += w;
+                    }
+
+                    let w_unk = unk_prob * abundances[num_active];
+                    weights.push(w_unk);
+                    total_w += w_unk;
+
+                    let sample = rng.random::<f64>() * total_w;
+                    let mut cumulative = 0.0;
+                    let mut chosen_idx = num_active;
+
+                    for (k, &w) in weights.iter().enumerate() {
+                        cumulative += w;
+                        if sample <= cumulative { chosen_idx = k; break; }
+                    }
+
+                    local_counts[chosen_idx] += ctx.read_weights[*row_idx];
+                }
+                local_counts
+            })
+            .collect();
+        let mut counts = vec![0.0; num_active + 1];
+        for local in &block_counts {
+            for (t, v) in counts.iter_mut().zip(local) { *t += v; }
+        }
+
+        let alpha: Vec<f64> = counts.iter()
+            .enumerate()
+            .map(|(k, &n)| n + if k == num_active { config.unk_alpha } else { config.alpha })
+            .collect();
+        let mut samples = Vec::with_capacity(num_active + 1);
+        let mut sum = 0.0;
+        for &a in &alpha {
+            let x = Gamma::new(a, 1.0).unwrap().sample(rng);
+            samples.push(x);
+            sum += x;
+        }
+        for x in &mut samples { *x /= sum; }
+        abundances = samples;
+
+        if i >= burnin && (i - burnin).is_multiple_of(thin) {
+            abund_history.push(abundances.clone());
+            count_history.push(counts);
+        }
+    }
+
+    (count_history, abund_history)
+}
+
+/// Mean and 95% credible interval of a series of abundance samples
+fn abundance_stats(mut vals: Vec<f64>, method: CiMethod) -> AbundanceStats {
+    vals.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let n = vals.len();
+    let mean: f64 = vals.iter().sum::<f64>() / n as f64;
+    let (ci_low, ci_high) = match method {
+        CiMethod::Quantile => (
+            vals[((n as f64 * 0.025).round() as usize).min(n - 1)],
+            vals[((n as f64 * 0.975).round() as usize).min(n - 1)],
+        ),
+        CiMethod::Hdi => {
+            // Narrowest window covering 95% of the sorted samples
+            let width = ((n as f64 * 0.95).ceil() as usize).clamp(1, n);
+            let start = (0..=n - width)
+                .min_by(|&a, &b| {
+                    let wa = vals[a + width - 1] - vals[a];
+                    let wb = vals[b + width - 1] - vals[b];
+                    wa.partial_cmp(&wb).unwrap()
+                })
+                .unwrap_or(0);
+            (vals[start], vals[start + width - 1])
+        }
+        CiMethod::Wald => {
+            let var = vals.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n.max(2) - 1) as f64;
+            let half = 1.96 * var.sqrt();
+            ((mean - half).max(0.0), (mean + half).min(1.0))
+        }
+    };
+
+    AbundanceStats { mean, ci_low, ci_high }
+}
+
+/// Posterior predictive check over the retained Gibbs samples (Unknown last)
+struct PpcResult {
+    observed_mean: Vec<f64>,
+    replicated_mean: Vec<f64>,
+    /// Per category: P(replicated count >= observed count)
+    p_values: Vec<f64>,
+    /// P(chi-square discrepancy of replicate >= that of the observed counts)
+    overall_p: f64,
+}
+
+/// Retained samples used by the posterior predictive check (evenly spaced when there are more)
+const PPC_MAX_REPLICATES: usize = 200;
+
+/// Stream offset for the posterior predictive replicates
+const PPC_STREAM: u64 = 0xBB67_AE85_84CA_A73B;
+
+/// For each retained sample (up to `PPC_MAX_REPLICATES`), simulates a replicate data set
+/// from the model: each read picks a category from the sampled abundances, then a matrix
+/// row with probability proportional to that category's likelihood for it (uniform for
+/// Unknown, whose likelihood is the same for every row). The replicate reads are then
+/// assigned as the Gibbs sampler assigns observed reads, and their counts are compared to
+/// the Gibbs counts of that sample. Extreme per-species p-values (near 0 or 1) flag taxa
+/// whose reads the fitted model does not reproduce.
+fn posterior_predictive_check(
+    ctx: &McmcContext,
+    species_set: &HashSet<usize>,
+    unk_prob: f64,
+    abund_history: &[Vec<f64>],
+    count_history: &[Vec<f64>],
+    seed: u64,
+) -> PpcResult {
+    let mut active: Vec<usize> = species_set.iter().cloned().collect();
+    active.sort_unstable();
+    let num_cat = active.len() + 1;
+    let nrows = ctx.matrix.nrows();
+    let position: HashMap<usize, usize> = active.iter().enumerate().map(|(j, &c)| (c, j)).collect();
+
+    // Rows aligned to each species, weighted by their likelihood under it
+    let mut species_rows: Vec<(Vec<usize>, Vec<f64>)> = vec![(Vec::new(), Vec::new()); active.len()];
+    for (r, row) in ctx.matrix.row_iter().enumerate() {
+        for (c, &p) in row.col_indices().iter().zip(row.values()) {
+            if let Some(&j) = position.get(c) {
+                species_rows[j].0.push(r);
+                species_rows[j].1.push(p);
+            }
+        }
+    }
+    let row_samplers: Vec<Option<WeightedIndex<f64>>> = species_rows.iter()
+        .map(|(_, p)| WeightedIndex::new(p).ok())
+        .collect();
+
+    let step = abund_history.len().div_ceil(PPC_MAX_REPLICATES).max(1);
+    let picked: Vec<usize> = (0..abund_history.len()).step_by(step).collect();
+    let num_samples = picked.len().max(1) as f64;
+
+    let discrepancy = |counts: &[f64], expected: &[f64]| -> f64 {
+        counts.iter().zip(expected)
+            .filter(|&(_, &e)| e > 0.0)
+            .map(|(&c, &e)| (c - e).powi(2) / e)
+            .sum()
+    };
+
+    let replicates: Vec<Vec<f64>> = picked.par_iter()
+        .map(|&s| {
+            let theta = &abund_history[s];
+            let mut rng = StdRng::seed_from_u64(seed ^ PPC_STREAM ^ (s as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+            let total = count_history[s].iter().sum::<f64>().round() as usize;
+            let category = WeightedIndex::new(theta).ok();
+            let mut replicate = vec![0.0; num_cat];
+            let mut weights = Vec::with_capacity(num_cat);
+            for _ in 0..total {
+                let Some(k) = category.as_ref().map(|d| d.sample(&mut rng)) else { break };
+                let r = match row_samplers.get(k).and_then(|d| d.as_ref()) {
+                    Some(d) => species_rows[k].0[d.sample(&mut rng)],
+                    None => rng.random_range(0..nrows.max(1)),
+                };
+                if nrows == 0 { break; }
+                // Assign the simulated read from its row, as in the Gibbs sampler
+                let row = ctx.matrix.row(r);
+                weights.clear();
+                weights.resize(num_cat, 0.0);
+                for (c, &p) in row.col_indices().iter().zip(row.values()) {
+                    if let Some(&j) = position.get(c) {
+                        weights[j] = p * theta[j];
+                    }
+                }
+                weights[num_cat - 1] = unk_prob * theta[num_cat - 1];
+                let assigned = WeightedIndex::new(&weights).map(|d| d.sample(&mut rng)).unwrap_or(num_cat - 1);
+                replicate[assigned] += 1.0;
+            }
+            replicate
+        })
+        .collect();
+
+    let mut observed_mean = vec![0.0; num_cat];
+    let mut replicated_mean = vec![0.0; num_cat];
+    let mut exceed = vec![0.0; num_cat];
+    let mut overall_exceed = 0.0;
+    for (&s, replicate) in picked.iter().zip(&replicates) {
+        let observed = &count_history[s];
+        let total: f64 = replicate.iter().sum();
+        let expected: Vec<f64> = abund_history[s].iter().map(|&p| p * total).collect();
+        for k in 0..num_cat {
+            observed_mean[k] += observed[k];
+            replicated_mean[k] += replicate[k];
+            if replicate[k] >= observed[k] { exceed[k] += 1.0; }
+        }
+        if discrepancy(replicate, &expected) >= discrepancy(observed, &expected) {
+            overall_exceed += 1.0;
+        }
+    }
+
+    for k in 0..num_cat {
+        observed_mean[k] /= num_samples;
+        replicated_mean[k] /= num_samples;
+        exceed[k] /= num_samples;
+    }
+
+    PpcResult { observed_mean, replicated_mean, p_values: exceed, overall_p: overall_exceed / num_samples }
+}
+
+fn export_ppc(path: &str, ctx: &McmcContext, species_set: &HashSet<usize>, ppc: &PpcResult) -> Result<()> {
+    println!("Exporting posterior predictive check to: {}", path);
+    let mut file = BufWriter::new(File::create(path)?);
+    let mut active_vec: Vec<usize> = species_set.iter().cloned().collect();
+    active_vec.sort_unstable();
+
+    writeln!(file, "TaxonID\tObservedReads\tReplicatedReads\tBayesianP")?;
+    let names = active_vec.iter().map(|&idx| ctx.taxons[idx].as_str()).chain(std::iter::once("Unknown"));
+    for (k, name) in names.enumerate() {
+        writeln!(file, "{}\t{:.2}\t{:.2}\t{:.4}", name, ppc.observed_mean[k], ppc.replicated_mean[k], ppc.p_values[k])?;
+    }
+    writeln!(file, "# Overall_ChiSquare_BayesianP: {:.4}", ppc.overall_p)?;
+    Ok(())
+}
+
+/// 95% interval (2.5% / 97.5% quantiles) of the Gibbs read counts per taxon and for Unknown
+struct ReadCountIntervals {
+    taxa: HashMap<usize, (f64, f64)>,
+    unknown: (f64, f64),
+}
+
+/// Quantile intervals over the retained count samples (sorted species index, Unknown last)
+fn read_count_intervals(species_set: &HashSet<usize>, count_history: &[Vec<f64>]) -> ReadCountIntervals {
+    let mut indices: Vec<usize> = species_set.iter().cloned().collect();
+    indices.sort_unstable();
+    let interval = |k: usize| {
+        let stats = abundance_stats(count_history.iter().map(|v| v[k]).collect(), CiMethod::Quantile);
+        (stats.ci_low, stats.ci_high)
+    };
+    ReadCountIntervals {
+        taxa: indices.iter().enumerate().map(|(k, &idx)| (idx, interval(k))).collect(),
+        unknown: interval(indices.len()),
+    }
+}
+
+/// Read totals behind the estimated read counts
+#[derive(Clone, Copy, Debug)]
+struct ReadWeightSummary {
+    /// Matrix rows
+    raw: usize,
+    /// Sum of the read weights (what EstimatedReads is scaled to)
+    weighted: f64,
+    /// Kish effective sample size, (sum w)^2 / sum w^2
+    effective: f64,
+}
+
+fn read_weight_summary(weights: &[f64]) -> ReadWeightSummary {
+    let weighted: f64 = weights.iter().sum();
+    let sum_sq: f64 = weights.iter().map(|w| w * w).sum();
+    let effective = if sum_sq > 0.0 { weighted * weighted / sum_sq } else { 0.0 };
+    ReadWeightSummary { raw: weights.len(), weighted, effective }
+}
+
+/// Scales the interval's distance from the mean by `scale`, clamped to [0, 1]
+fn widen_interval(stats: &mut AbundanceStats, scale: f64) {
+    stats.ci_low = (stats.mean - (stats.mean - stats.ci_low) * scale).max(0.0);
+    stats.ci_high = (stats.mean + (stats.ci_high - stats.mean) * scale).min(1.0);
+}
+
+/// Scales a read count interval's distance from `center` by `scale`, clamped to [0, total]
+fn widen_read_interval((low, high): (f64, f64), center: f64, scale: f64, total: f64) -> (f64, f64) {
+    ((center - (center - low) * scale).max(0.0), (center + (high - center) * scale).min(total))
+}
+
+/// Estimated read counts rounded to integers that sum to the total read count
+struct IntegerReads {
+    taxa: HashMap<usize, u64>,
+    unknown: u64,
+}
+
+/// Allocates the (rounded) total read weight over the reported taxa and Unknown
+/// in proportion to their mean abundances, using the largest-remainder method.
+fn integer_read_counts(ctx: &McmcContext, abundances: &HashMap<usize, AbundanceStats>, unknown_mean: f64) -> IntegerReads {
+    let total = ctx.read_weights.iter().sum::<f64>().round() as u64;
+    let mut indices: Vec<usize> = abundances.keys().cloned().collect();
+    indices.sort_unstable();
+    // Unknown goes last
+    let mut shares: Vec<f64> = indices.iter().map(|idx| abundances[idx].mean).collect();
+    shares.push(unknown_mean);
+    let share_sum: f64 = shares.iter().sum();
+
+    let quotas: Vec<f64> = shares.iter()
+        .map(|s| if share_sum > 0.0 { s / share_sum * total as f64 } else { 0.0 })
+        .collect();
+    let mut counts: Vec<u64> = quotas.iter().map(|q| q.floor() as u64).collect();
+    let leftover = total.saturating_sub(counts.iter().sum());
+
+    // Hand the remaining reads to the largest fractional parts (ties to the lower index)
+    let mut order: Vec<usize> = (0..quotas.len()).collect();
+    order.sort_by(|&a, &b| {
+        let (ra, rb) = (quotas[a] - quotas[a].floor(), quotas[b] - quotas[b].floor());
+        rb.partial_cmp(&ra).unwrap_or(std::cmp::Ordering::Equal).then(a.cmp(&b))
+    });
+    for &i in order.iter().take(leftover as usize) {
+        counts[i] += 1;
+    }
+
+    let unknown = counts.pop().unwrap_or(0);
+    IntegerReads { taxa: indices.into_iter().zip(counts).collect(), unknown }
+}
+
+/// Converts read-fraction abundances into organism (cell) abundances by
+/// dividing by genome length and renormalizing over the reported non-host taxa.
+/// Taxa with no known length fall back to the median genome length.
+fn organism_abundances(
+    ctx: &McmcContext,
+    abundances: &HashMap<usize, AbundanceStats>,
+    host_taxa: &HashSet<String>,
+) -> HashMap<usize, f64> {
+    let mut per_length: HashMap<usize, f64> = abundances.iter()
+        .filter(|&(&idx, _)| !host_taxa.contains(&ctx.taxons[idx]))
+        .map(|(&idx, stats)| {
+            let len = ctx.genome_lengths.get(idx).copied().filter(|&l| l > 0.0).unwrap_or(ctx.median_genome_len);
+            (idx, stats.mean / len)
+        })
+        .collect();
+
+    let mut indices: Vec<usize> = per_length.keys().cloned().collect();
+    indices.sort_unstable();
+    let total: f64 = indices.iter().map(|idx| per_length[idx]).sum();
+    if total > 0.0 {
+        for v in per_length.values_mut() { *v /= total; }
+    }
+    per_length
+}
+
+/// Combined mean abundance of the host taxa
+fn host_fraction(ctx: &McmcContext, abundances: &HashMap<usize, AbundanceStats>, host_taxa: &HashSet<String>) -> f64 {
+    // Summed in index order so the result does not depend on HashMap iteration order
+    let mut indices: Vec<usize> = abundances.keys().cloned()
+        .filter(|&idx| host_taxa.contains(&ctx.taxons[idx]))
+        .collect();
+    indices.sort_unstable();
+    indices.iter().map(|idx| abundances[idx].mean).sum()
+}
+
+/// Abundance relative to the non-host part of the sample (microbial taxa plus Unknown)
+fn microbial_abundance(mean: f64, host_fraction: f64) -> f64 {
+    if host_fraction < 1.0 { mean / (1.0 - host_fraction) } else { 0.0 }
+}
+
+fn export_posterior_samples(
+    path: &str,
+    ctx: &McmcContext,
+    history: &[Vec<f64>],
+    active_indices_set: &HashSet<usize>,
+    gibbs: &GibbsConfig,
+    compress: bool,
+) -> Result<()> {
+    let (mut file, path) = create_output(path, compress)?;
+    println!("Exporting posterior distribution to: {}", path);
+    write_history(&mut file, ctx, history, active_indices_set, gibbs, 2)?;
+    file.finish().with_context(|| format!("Failed to write {}", path))
+}
+
+/// Writes one row per recorded Gibbs iteration: `Iteration`, one column per active
+/// taxon (ascending index, matching the history's layout), then `Unknown`.
+/// Pooled replicates are written one after another, led by a `Replicate` column.
+fn write_history(
+    file: &mut dyn Write,
+    ctx: &McmcContext,
+    history: &[Vec<f64>],
+    active_indices_set: &HashSet<usize>,
+    gibbs: &GibbsConfig,
+    decimals: usize,
+) -> Result<()> {
+    let mut active_vec: Vec<usize> = active_indices_set.iter().cloned().collect();
+    active_vec.sort_unstable();
+    let per_replicate = (history.len() / gibbs.replicates.max(1)).max(1);
+
+    if gibbs.replicates > 1 { write!(file, "Replicate\t")?; }
+    write!(file, "Iteration")?;
+    for &idx in &active_vec { write!(file, "\t{}", ctx.taxons[idx])?; }
+    writeln!(file, "\tUnknown")?;
+
+    for (i, values) in history.iter().enumerate() {
+        if gibbs.replicates > 1 { write!(file, "{}\t", i / per_replicate)?; }
+        write!(file, "{}", (i % per_replicate) * gibbs.thin)?;
+        for val in values { write!(file, "\t{:.*}", decimals, val)?; }
+        writeln!(file)?;
+    }
+    Ok(())
+}
+
+/// Everything reported in the main results file
+struct Summary<'a> {
+    ctx: &'a McmcContext,
+    abundances: &'a HashMap<usize, AbundanceStats>,
+    bayes_factors: &'a HashMap<usize, f64>,
+    inclusion: &'a HashMap<usize, f64>,
+    floor: f64,
+    taxonomy_map: Option<&'a HashMap<String, String>>,
+    unknown_reads: usize,
+    /// Breadth of coverage per taxon ID; adds a CoverageBreadth column when present
+    coverage: Option<&'a HashMap<String, f64>>,
+    /// Unknown-bin abundance; adds an "Unknown" row when present
+    unknown: Option<&'a AbundanceStats>,
+    /// Adds an EstimatedReadsInt column when present
+    integer_reads: Option<&'a IntegerReads>,
+    /// Credible interval of the estimated reads
+    read_intervals: &'a ReadCountIntervals,
+    /// Adds a Log10BF_Unknown column when present
+    bayes_factors_unknown: Option<&'a HashMap<usize, f64>>,
+    /// Merged taxa per representative; adds a MergedTaxa column when non-empty
+    merged_taxa: &'a HashMap<String, Vec<String>>,
+    /// Host taxon IDs; adds Category and MicrobialAbundance columns when non-empty
+    host_taxa: &'a HashSet<String>,
+    /// Adds HostOnly/ExcludedOnly read counts when present
+    separated_reads: Option<SeparatedReads>,
+    /// Unpenalized cold-chain log-likelihood minus the all-Unknown null's, at the learned floor
+    log_lr_null: f64,
+    ci_method: CiMethod,
+    sort_by: SortBy,
+    weights: ReadWeightSummary,
+    /// Factor the credible intervals were widened by (`--ess-adjusted-ci`)
+    ci_scale: Option<f64>,
+}
+
+/// Reported species in `summary.sort_by` order
+fn sorted_species(summary: &Summary) -> Vec<usize> {
+    let Summary { ctx, abundances, bayes_factors, taxonomy_map, .. } = *summary;
+    let name = |idx: usize| scientific_name(taxonomy_map, &ctx.taxons[idx]);
+    let bf = |idx: usize| bayes_factors.get(&idx).copied().unwrap_or(0.0);
+    let width = |idx: usize| abundances[&idx].ci_high - abundances[&idx].ci_low;
+
+    let mut order: Vec<usize> = abundances.keys().cloned().collect();
+    order.sort_by(|&a, &b| {
+        let primary = match summary.sort_by {
+            SortBy::Abundance => abundances[&b].mean.total_cmp(&abundances[&a].mean),
+            SortBy::Bayes => bf(b).total_cmp(&bf(a)),
+            SortBy::Name => name(a).cmp(name(b)),
+            SortBy::CiWidth => width(a).total_cmp(&width(b)),
+            SortBy::TaxonId => std::cmp::Ordering::Equal,
+        };
+        primary.then_with(|| ctx.taxons[a].cmp(&ctx.taxons[b]))
+    });
+    order
+}
+
+fn export_summary(path: &str, summary: &Summary) -> Result<()> {
+    let Summary { ctx, abundances, bayes_factors, inclusion, floor, taxonomy_map, .. } = *summary;
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "# CI_Method: {}", summary.ci_method.name())?;
+    if let Some(scale) = summary.ci_scale {
+        writeln!(file, "# CI_ESS_Scale: {:.4}", scale)?;
+    }
+    writeln!(file, "# OrganismAbundance = MeanAbundance / GenomeLength, renormalized over reported non-host taxa (assumes single-copy genomes and uniform coverage)")?;
+    write!(file, "TaxonID\tScientificName\tMeanAbundance\tCI_Lower\tCI_Upper\tCI_Width\tEstimatedReads\tEstimatedReads_CI_Lower\tEstimatedReads_CI_Upper\tLog10BF\tPosterior\tOrganismAbundance")?;
+    if summary.integer_reads.is_some() { write!(file, "\tEstimatedReadsInt")?; }
+    if summary.bayes_factors_unknown.is_some() { write!(file, "\tLog10BF_Unknown")?; }
+    if summary.coverage.is_some() { write!(file, "\tCoverageBreadth")?; }
+    let report_host = !summary.host_taxa.is_empty();
+    if report_host { write!(file, "\tCategory\tMicrobialAbundance")?; }
+    let report_merged = !summary.merged_taxa.is_empty();
+    if report_merged { write!(file, "\tMergedTaxa")?; }
+    writeln!(file)?;
+    let total_reads: f64 = ctx.read_weights.iter().sum();
+    let organism = organism_abundances(ctx, abundances, summary.host_taxa);
+    let host = host_fraction(ctx, abundances, summary.host_taxa);
+    for idx in sorted_species(summary) {
+        let stats = &abundances[&idx];
+        let tid = &ctx.taxons[idx];
+        let name = scientific_name(taxonomy_map, tid);
+        let (reads_low, reads_high) = summary.read_intervals.taxa.get(&idx).copied().unwrap_or((0.0, 0.0));
+        write!(file, "{}\t{}\t{:.6}\t{:.6}\t{:.6}\t{:.6}\t{:.2}\t{:.2}\t{:.2}\t{:.2}\t{:.2}\t{:.6}", 
+            tid, name, stats.mean, stats.ci_low, stats.ci_high, stats.ci_high - stats.ci_low, stats.mean * total_reads,
+            reads_low, reads_high, bayes_factors.get(&idx).unwrap_or(&0.0),
+            inclusion.get(&idx).copied().unwrap_or(0.0), organism.get(&idx).copied().unwrap_or(0.0))?;
+        if let Some(ints) = summary.integer_reads {
+            write!(file, "\t{}", ints.taxa.get(&idx).copied().unwrap_or(0))?;
+        }
+        if let Some(bf_unknown) = summary.bayes_factors_unknown {
+            write!(file, "\t{:.2}", bf_unknown.get(&idx).copied().unwrap_or(0.0))?;
+        }
+        if let Some(coverage) = summary.coverage {
+            write!(file, "\t{:.6}", coverage.get(tid).copied().unwrap_or(0.0))?;
+        }
+        if report_host {
+            if summary.host_taxa.contains(tid) {
+                write!(file, "\tHost\tNA")?;
+            } else {
+                write!(file, "\tMicrobial\t{:.6}", microbial_abundance(stats.mean, host))?;
+            }
+        }
+        if report_merged {
+            let members = summary.merged_taxa.get(tid).map(|m| m.join(",")).unwrap_or_else(|| "-".to_string());
+            write!(file, "\t{}", members)?;
+        }
+        writeln!(file)?;
+    }
+    if let Some(stats) = summary.unknown {
+        let (reads_low, reads_high) = summary.read_intervals.unknown;
+        write!(file, "Unknown\tUnknown\t{:.6}\t{:.6}\t{:.6}\t{:.6}\t{:.2}\t{:.2}\t{:.2}\tNA\tNA\tNA",
+            stats.mean, stats.ci_low, stats.ci_high, stats.ci_high - stats.ci_low, stats.mean * total_reads, reads_low, reads_high)?;
+        if let Some(ints) = summary.integer_reads { write!(file, "\t{}", ints.unknown)?; }
+        if summary.bayes_factors_unknown.is_some() { write!(file, "\tNA")?; }
+        if summary.coverage.is_some() { write!(file, "\tNA")?; }
+        if report_host { write!(file, "\tUnknown\t{:.6}", microbial_abundance(stats.mean, host))?; }
+        if report_merged { write!(file, "\t-")?; }
+        writeln!(file)?;
+    }
+    if report_host {
+        writeln!(file, "# MicrobialAbundance = MeanAbundance / (1 - Host_Fraction)")?;
+        writeln!(file, "# Host_Fraction: {:.6}", host)?;
+    }
+    writeln!(file, "# Unknown_Bin_Probability_Floor: {:.4e}", floor)?;
+    writeln!(file, "# Unknown_Reads: {}", summary.unknown_reads)?;
+    writeln!(file, "# LogLR_vs_Null: {:.4}", summary.log_lr_null)?;
+    writeln!(file, "# Raw_Reads: {}", summary.weights.raw)?;
+    writeln!(file, "# Weighted_Reads: {:.2}", summary.weights.weighted)?;
+    writeln!(file, "# Effective_Reads: {:.2}", summary.weights.effective)?;
+    if let Some(separated) = summary.separated_reads {
+        writeln!(file, "# HostOnly_Reads: {}", separated.host_only)?;
+        writeln!(file, "# ExcludedOnly_Reads: {}", separated.excluded_only)?;
+    }
+    Ok(())
+}
+
+fn export_summary_json(path: &str, summary: &Summary, run_metadata: &Value) -> Result<()> {
+    let Summary { ctx, abundances, bayes_factors, inclusion, floor, taxonomy_map, .. } = *summary;
+    println!("Exporting JSON results to: {}", path);
+    let total_reads: f64 = ctx.read_weights.iter().sum();
+    let organism = organism_abundances(ctx, abundances, summary.host_taxa);
+    let host = host_fraction(ctx, abundances, summary.host_taxa);
+
+    let mut taxa = Map::new();
+    for idx in sorted_species(summary) {
+        let stats = &abundances[&idx];
+        let tid = &ctx.taxons[idx];
+        // null rather than "NA" when the taxon has no name
+        let name = taxonomy_map.and_then(|m| m.get(tid));
+        let (reads_low, reads_high) = summary.read_intervals.taxa.get(&idx).copied().unwrap_or((0.0, 0.0));
+        let mut entry = json!({
+            "scientific_name": name,
+            "mean_abundance": stats.mean,
+            "organism_abundance": organism.get(&idx).copied().unwrap_or(0.0),
+            "ci_lower": stats.ci_low,
+            "ci_upper": stats.ci_high,
+            "ci_width": stats.ci_high - stats.ci_low,
+            "estimated_reads": stats.mean * total_reads,
+            "estimated_reads_ci_lower": reads_low,
+            "estimated_reads_ci_upper": reads_high,
+            "log10_bf": bayes_factors.get(&idx).copied().unwrap_or(0.0),
+            "posterior_inclusion": inclusion.get(&idx).copied().unwrap_or(0.0),
+        });
+        if let Some(ints) = summary.integer_reads {
+            entry["estimated_reads_int"] = json!(ints.taxa.get(&idx).copied().unwrap_or(0));
+        }
+        if let Some(bf_unknown) = summary.bayes_factors_unknown {
+            entry["log10_bf_unknown"] = json!(bf_unknown.get(&idx).copied().unwrap_or(0.0));
+        }
+        if let Some(coverage) = summary.coverage {
+            entry["coverage_breadth"] = json!(coverage.get(tid).copied().unwrap_or(0.0));
+        }
+        if !summary.host_taxa.is_empty() {
+            let is_host = summary.host_taxa.contains(tid);
+            entry["category"] = json!(if is_host { "host" } else { "microbial" });
+            entry["microbial_abundance"] = if is_host { Value::Null } else { json!(microbial_abundance(stats.mean, host)) };
+        }
+        if let Some(members) = summary.merged_taxa.get(tid) {
+            entry["merged_taxa"] = json!(members);
+        }
+        taxa.insert(tid.clone(), entry);
+    }
+
+    let mut doc = json!({
+        "metadata": run_metadata,
+        "total_reads": total_reads,
+        "unknown_probability_floor": floor,
+        "unknown_reads": summary.unknown_reads,
+        "log_lr_vs_null": summary.log_lr_null,
+        "ci_method": summary.ci_method,
+        "raw_reads": summary.weights.raw,
+        "effective_reads": summary.weights.effective,
+        "taxa": taxa,
+    });
+    if let Some(scale) = summary.ci_scale {
+        doc["ci_ess_scale"] = json!(scale);
+    }
+    if !summary.host_taxa.is_empty() {
+        doc["host_fraction"] = json!(host);
+    }
+    if let Some(separated) = summary.separated_reads {
+        doc["host_only_reads"] = json!(separated.host_only);
+        doc["excluded_only_reads"] = json!(separated.excluded_only);
+    }
+    if let Some(stats) = summary.unknown {
+        doc["unknown"] = json!({
+            "mean_abundance": stats.mean,
+            "ci_lower": stats.ci_low,
+            "ci_upper": stats.ci_high,
+            "ci_width": stats.ci_high - stats.ci_low,
+            "estimated_reads": stats.mean * total_reads,
+            "estimated_reads_ci_lower": summary.read_intervals.unknown.0,
+            "estimated_reads_ci_upper": summary.read_intervals.unknown.1,
+        });
+        if let Some(ints) = summary.integer_reads {
+            doc["unknown"]["estimated_reads_int"] = json!(ints.unknown);
+        }
+    }
+
+    let file = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(file, &doc)?;
+    Ok(())
+}
+
+fn export_read_assignments(
+    path: &str,
+    ctx: &McmcContext,
     assignments: &[(usize, f64)],
     read_names: &[String],
     taxonomy_map: Option<&HashMap<String, String>>,
     active_indices_set: &HashSet<usize>,
+    compress: bool,
 ) -> Result<()> {
-    let mut file = BufWriter::new(File::create(path)?);
-    writeln!(file, "ReadName\tAssignedTaxonID\tAssignedName\tProbability")?;
+    let (mut file, path) = create_output(path, compress)?;
+    writeln!(file, "ReadName\tAssignedTaxonID\tAssignedName\tProbability\tMLTaxonID\tMLLogProb")?;
     let mut active_vec: Vec<usize> = active_indices_set.iter().cloned().collect();
     active_vec.sort_unstable();
     for (r_idx, (best_idx, prob)) in assignments.iter().enumerate() {
-        if *best_idx >= active_vec.len() {
-            writeln!(file, "{}\tUnknown\tUnknown\t{:.4}", read_names[r_idx], prob)?;
+        if *best_idx == active_vec.len() {
+            write!(file, "{}\tUnknown\tUnknown\t{:.4}", read_names[r_idx], prob)?;
+        } else if *best_idx > active_vec.len() {
+            write!(file, "{}\tAmbiguous\tAmbiguous\t{:.4}", read_names[r_idx], prob)?;
         } else {
             let tid = &ctx.taxons[active_vec[*best_idx]];
-            let name = taxonomy_map.and_then(|m| m.get(tid)).map(|s| s.as_str()).unwrap_or("Unknown");
-            writeln!(file, "{}\t{}\t{}\t{:.4}", read_names[r_idx], tid, name, prob)?;
+            let name = scientific_name(taxonomy_map, tid);
+            write!(file, "{}\t{}\t{}\t{:.4}", read_names[r_idx], tid, name, prob)?;
+        }
+        match max_likelihood_taxon(ctx, r_idx) {
+            Some((col, p)) => writeln!(file, "\t{}\t{:.4}", ctx.taxons[col], p.ln())?,
+            None => writeln!(file, "\tNA\tNA")?,
+        }
+    }
+    file.finish().with_context(|| format!("Failed to write {}", path))
+}
+
+/// Writes one `ReadName, TaxonID, Name, Weight` line per read-taxon pair with a nonzero
+/// posterior weight under the mean abundances. `unknown_term` is the Unknown bin's
+/// probability times its mean abundance. Weights of a read sum to 1.
+fn export_fractional_assignments(
+    path: &str,
+    ctx: &McmcContext,
+    read_names: &[String],
+    taxonomy_map: Option<&HashMap<String, String>>,
+    species_means: &[(usize, f64)],
+    unknown_term: f64,
+    compress: bool,
+) -> Result<()> {
+    let (mut file, path) = create_output(path, compress)?;
+    println!("Exporting fractional read assignments to: {}", path);
+    writeln!(file, "ReadName\tTaxonID\tName\tWeight")?;
+    let num_active = species_means.len();
+    let mut terms = Vec::with_capacity(num_active + 1);
+    for (r_idx, row_vec) in ctx.matrix.row_iter().enumerate() {
+        terms.clear();
+        for &(col_idx, mean) in species_means {
+            let mut p_val = 0.0;
+            for (c, &v) in row_vec.col_indices().iter().zip(row_vec.values()) {
+                if *c == col_idx { p_val = v; break; }
+            }
+            terms.push(p_val * mean);
+        }
+        terms.push(unknown_term);
+        let total: f64 = terms.iter().sum();
+
+        for (i, &term) in terms.iter().enumerate() {
+            if term <= 0.0 { continue; }
+            let weight = if total > 0.0 { term / total } else { 1.0 };
+            if i == num_active {
+                writeln!(file, "{}\tUnknown\tUnknown\t{:.6}", read_names[r_idx], weight)?;
+            } else {
+                let tid = &ctx.taxons[species_means[i].0];
+                let name = scientific_name(taxonomy_map, tid);
+                writeln!(file, "{}\t{}\t{}\t{:.6}", read_names[r_idx], tid, name, weight)?;
+            }
+        }
+    }
+    file.finish().with_context(|| format!("Failed to write {}", path))
+}
+
+/// Column with the highest alignment probability in a read's row, ignoring abundances
+/// and the MCMC species set. `None` for reads without alignments.
+fn max_likelihood_taxon(ctx: &McmcContext, row_idx: usize) -> Option<(usize, f64)> {
+    let row = ctx.matrix.row(row_idx);
+    row.col_indices().iter().zip(row.values())
+        .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(&col, &p)| (col, p))
+}
+
+/// Writes `ReadName<TAB>Probability` for every read assigned to the Unknown bin
+fn export_unknown_reads(
+    path: &str,
+    assignments: &[(usize, f64)],
+    read_names: &[String],
+    num_active: usize,
+) -> Result<()> {
+    println!("Exporting Unknown-bin reads to: {}", path);
+    let mut file = BufWriter::new(File::create(path)?);
+    for (r_idx, (best_idx, prob)) in assignments.iter().enumerate() {
+        if *best_idx == num_active {
+            writeln!(file, "{}\t{:.4}", read_names[r_idx], prob)?;
         }
     }
     Ok(())
 }
 
-fn export_trace(path: &str, chain: &ChainState, ratio: f64) -> Result<()> {
+fn export_trace(path: &str, chain: &ChainState, burnin: McmcBurnin) -> Result<()> {
     let mut file = BufWriter::new(File::create(path)?);
     writeln!(file, "Iteration\tLogLikelihood\tMoveType")?;
-    let start = (chain.history.len() as f64 * ratio) as usize;
+    let start = burnin.start(&chain.history);
     for rec in &chain.history[start..] {
         writeln!(file, "{}\t{:.4}\t{}", rec.iter, rec.log_likelihood, rec.move_type)?;
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Writes `Iteration<TAB>NumSpecies<TAB>TaxonIDs` (sorted, comma-separated) for every
+/// recorded iteration of a chain, burn-in included
+fn export_species_trace(path: &str, ctx: &McmcContext, chain: &ChainState) -> Result<()> {
+    println!("Exporting species trace to: {}", path);
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "Iteration\tNumSpecies\tTaxonIDs")?;
+    for rec in &chain.history {
+        let taxa: Vec<&str> = rec.species.iter().map(|&sp| ctx.taxons[sp].as_str()).collect();
+        writeln!(file, "{}\t{}\t{}", rec.iter, taxa.len(), taxa.join(","))?;
+    }
+    Ok(())
+}
+
+/// Effective sample size of a series via the autocorrelation-sum method.
+/// The sum over lags is truncated at the first non-positive autocorrelation.
+pub fn effective_sample_size(series: &[f64]) -> f64 {
+    let n = series.len();
+    if n < 2 { return n as f64; }
+
+    let mean = series.iter().sum::<f64>() / n as f64;
+    let var = series.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n as f64;
+    if var <= 0.0 { return n as f64; }
+
+    let mut rho_sum = 0.0;
+    for lag in 1..n {
+        let mut acov = 0.0;
+        for t in 0..(n - lag) {
+            acov += (series[t] - mean) * (series[t + lag] - mean);
+        }
+        let rho = acov / (n as f64 * var);
+        if rho <= 0.0 { break; }
+        rho_sum += rho;
+    }
+
+    n as f64 / (1.0 + 2.0 * rho_sum)
+}
+
+fn rate(accepted: usize, attempted: usize) -> f64 {
+    if attempted > 0 { accepted as f64 / attempted as f64 } else { 0.0 }
+}
+
+/// Healthy band for the cold chain's within-chain move acceptance
+const COLD_ACCEPT_LOW: f64 = 0.1;
+const COLD_ACCEPT_HIGH: f64 = 0.5;
+
+/// Prints the cold chain's move acceptance by type and whether it falls in the healthy band
+fn report_cold_acceptance(chain: &ChainState) {
+    if chain.moves_attempted == 0 { return; }
+    let overall = rate(chain.moves_accepted, chain.moves_attempted);
+    println!("Cold chain acceptance: {:.3} (add {:.3}, remove {:.3}, species swap {:.3})",
+        overall, chain.add_moves.rate(), chain.remove_moves.rate(), chain.swap_moves.rate());
+    if overall < COLD_ACCEPT_LOW {
+        eprintln!("Warning: Cold chain acceptance {:.3} is below {}; the penalty or proposals are too aggressive. Consider raising --unknown-penalty-ref (a lower penalty).",
+            overall, COLD_ACCEPT_LOW);
+    } else if overall > COLD_ACCEPT_HIGH {
+        eprintln!("Warning: Cold chain acceptance {:.3} is above {}; the chain is not selective. Consider lowering --unknown-penalty-ref (a higher penalty).",
+            overall, COLD_ACCEPT_HIGH);
+    } else {
+        println!("Cold chain acceptance is within the healthy range ({}-{}).", COLD_ACCEPT_LOW, COLD_ACCEPT_HIGH);
+    }
+}
+
+/// Per-chain move and swap acceptance. Chains are listed in ladder order, so a
+/// low swap rate on neighbouring rows means the temperatures are too far apart.
+/// SpeciesSwap is the within-chain remove-one-add-one move, not a replica exchange.
+fn export_chain_stats(path: &str, chains: &[ChainState]) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "ChainID\tTemperature\tMovesAttempted\tMovesAccepted\tMoveAcceptance\tAddAcceptance\tRemoveAcceptance\tSpeciesSwapAcceptance\tSwapsAttempted\tSwapsAccepted\tSwapAcceptance")?;
+    for chain in chains {
+        let swap_rate = rate(chain.swaps_accepted, chain.swaps_attempted);
+        writeln!(file, "{}\t{:.4}\t{}\t{}\t{:.4}\t{:.4}\t{:.4}\t{:.4}\t{}\t{}\t{:.4}",
+            chain.id, chain.temperature,
+            chain.moves_attempted, chain.moves_accepted, rate(chain.moves_accepted, chain.moves_attempted),
+            chain.add_moves.rate(), chain.remove_moves.rate(), chain.swap_moves.rate(),
+            chain.swaps_attempted, chain.swaps_accepted, swap_rate)?;
+
+        if chain.swaps_attempted > 0 && swap_rate < 0.05 {
+            eprintln!("Warning: Chain {} accepts only {:.1}% of swaps. Consider more --chains or a smaller --temp-k.", chain.id, swap_rate * 100.0);
+        }
+    }
+    Ok(())
+}
+
+/// Jensen-Shannon divergence (base 2, so in [0, 1]) between two distributions of equal length
+fn jensen_shannon_divergence(p: &[f64], q: &[f64]) -> f64 {
+    let kl_to_mid = |a: &[f64], b: &[f64]| -> f64 {
+        a.iter().zip(b)
+            .filter(|&(&x, _)| x > 0.0)
+            .map(|(&x, &y)| x * (2.0 * x / (x + y)).log2())
+            .sum()
+    };
+    (0.5 * kl_to_mid(p, q) + 0.5 * kl_to_mid(q, p)).max(0.0)
+}
+
+/// Appends the divergence between the step 2 EM abundances and the Gibbs mean abundances,
+/// both restricted to the final species set and renormalized, to the convergence report
+fn append_em_divergence(path: &str, ctx: &McmcContext, final_stats: &HashMap<usize, AbundanceStats>) -> Result<()> {
+    let mut indices: Vec<usize> = final_stats.keys().cloned().collect();
+    indices.sort_unstable();
+    let normalize = |v: Vec<f64>| -> Vec<f64> {
+        let total: f64 = v.iter().sum();
+        if total > 0.0 { v.iter().map(|x| x / total).collect() } else { v }
+    };
+    let em = normalize(indices.iter().map(|&idx| ctx.taxon_weights[idx]).collect());
+    let gibbs = normalize(indices.iter().map(|idx| final_stats[idx].mean).collect());
+    let jsd = jensen_shannon_divergence(&em, &gibbs);
+
+    println!("EM vs Gibbs abundance JSD: {:.4}", jsd);
+    if jsd > JSD_WARN_THRESHOLD {
+        eprintln!("Warning: Gibbs abundances diverge from the step 2 EM estimate (JSD {:.3} > {}); model selection substantially changed the quantification.", jsd, JSD_WARN_THRESHOLD);
+    }
+    let mut file = std::fs::OpenOptions::new().append(true).open(path)?;
+    writeln!(file, "# EM_Gibbs_JSD: {:.6}", jsd)?;
+    Ok(())
+}
+
+fn export_convergence(path: &str, chains: &[ChainState], burnin: McmcBurnin) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "ChainID\tTemperature\tSamples\tESS")?;
+    for chain in chains {
+        let start = burnin.start(&chain.history);
+        let series: Vec<f64> = chain.history[start..].iter().map(|r| r.log_likelihood).collect();
+        let ess = effective_sample_size(&series);
+        writeln!(file, "{}\t{:.4}\t{}\t{:.2}", chain.id, chain.temperature, series.len(), ess)?;
+
+        if chain.id == 0 {
+            println!("Cold Chain ESS (LogL): {:.1} from {} samples", ess, series.len());
+        }
+        if !series.is_empty() && ess < 100.0 {
+            eprintln!("Warning: Chain {} has low ESS ({:.1} < 100). Consider increasing --mcmc-iter.", chain.id, ess);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::step3_mcmc::{PenaltyConfig, UnknownBinConfig};
+    use nalgebra_sparse::{coo::CooMatrix, CsrMatrix};
+
+    /// 40 reads over 3 taxa: 20 to taxon 0, 10 to taxon 1, 5 shared by both, 5 to taxon 2
+    fn toy_context() -> McmcContext {
+        scaled_context(1, 1.0)
+    }
+
+    /// `toy_context` with every read repeated `copies` times and weighted `weight`
+    fn scaled_context(copies: usize, weight: f64) -> McmcContext {
+        let n = 40 * copies;
+        let mut coo = CooMatrix::new(n, 3);
+        for r in 0..n {
+            let cols: &[usize] = match r % 40 {
+                0..20 => &[0],
+                20..30 => &[1],
+                30..35 => &[0, 1],
+                _ => &[2],
+            };
+            for &c in cols {
+                coo.push(r, c, (1e-3f64).ln());
+            }
+        }
+        McmcContext::new(
+            &CsrMatrix::from(&coo),
+            vec![weight; n],
+            vec!["1".to_string(), "2".to_string(), "3".to_string()],
+            vec![0.5, 0.35, 0.15],
+            vec![1000.0; 3],
+            PenaltyConfig { median_genome_len: 1000.0, read_support: 2, p_unknown_penalty_ref: 1e-20 },
+            UnknownBinConfig::default(),
+        )
+    }
+
+    fn gibbs_config() -> GibbsConfig {
+        GibbsConfig {
+            iterations: 200,
+            burnin: 50,
+            alpha: 1.0,
+            unk_alpha: 1.0,
+            thin: 1,
+            ci_method: CiMethod::Quantile,
+            tie_epsilon: None,
+            replicates: 1,
+        }
+    }
+
+    fn all_species() -> HashSet<usize> {
+        [0, 1, 2].into_iter().collect()
+    }
+
+    #[test]
+    fn fixed_seed_gives_identical_bayes_factors() {
+        let ctx = toy_context();
+        let set = all_species();
+        let h1 = refit_log_likelihood(&ctx, &set, 1e-8);
+        let a = compute_bayes_factors(&ctx, &set, h1, 1e-8, 42, BfNull::Removal);
+        let b = compute_bayes_factors(&ctx, &set, h1, 1e-8, 42, BfNull::Removal);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn fixed_seed_gives_identical_gibbs_samples() {
+        let ctx = toy_context();
+        let (stats_a, assign_a, counts_a, abund_a) = run_gibbs_sampler(&ctx, &all_species(), 1e-8, &gibbs_config(), 7);
+        let (stats_b, assign_b, counts_b, abund_b) = run_gibbs_sampler(&ctx, &all_species(), 1e-8, &gibbs_config(), 7);
+        assert_eq!(abund_a, abund_b);
+        assert_eq!(counts_a, counts_b);
+        assert_eq!(assign_a, assign_b);
+        for (idx, s) in &stats_a {
+            assert_eq!((s.mean, s.ci_low, s.ci_high), (stats_b[idx].mean, stats_b[idx].ci_low, stats_b[idx].ci_high));
+        }
+        let (_, _, _, abund_c) = run_gibbs_sampler(&ctx, &all_species(), 1e-8, &gibbs_config(), 8);
+        assert_ne!(abund_a, abund_c);
+    }
+
+    #[test]
+    fn gibbs_samples_do_not_depend_on_thread_count() {
+        // Several row blocks with non-integer weights, so both the draws and the summation order matter
+        let ctx = scaled_context(300, 0.3);
+        let mut config = gibbs_config();
+        config.iterations = 20;
+        config.burnin = 5;
+        let run = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| run_gibbs_sampler(&ctx, &all_species(), 1e-8, &config, 3).3)
+        };
+        assert_eq!(run(1), run(4));
+    }
+
+    #[test]
+    fn gibbs_replicates_use_independent_streams() {
+        let ctx = toy_context();
+        let mut config = gibbs_config();
+        config.replicates = 2;
+        let (_, _, _, pooled) = run_gibbs_sampler(&ctx, &all_species(), 1e-8, &config, 11);
+        let (first, second) = pooled.split_at(config.iterations);
+        assert_ne!(first, second);
+        // Replicate 0 alone matches the first half of the pool
+        config.replicates = 1;
+        let (_, _, _, single) = run_gibbs_sampler(&ctx, &all_species(), 1e-8, &config, 11);
+        assert_eq!(single, first);
+    }
+
+    #[test]
+    fn empty_model_has_no_likelihood_gain_over_null() {
+        let ctx = toy_context();
+        let floor = 1e-8;
+        let empty = crate::step3_mcmc::fixed_species_state(&ctx, HashSet::new(), 10);
+        let (lr, penalty) = log_lr_vs_null(&ctx, &empty, floor);
+        assert!(lr.abs() < 1e-9 && penalty == 0.0);
+        let full = crate::step3_mcmc::fixed_species_state(&ctx, all_species(), 10);
+        let (lr, _) = log_lr_vs_null(&ctx, &full, floor);
+        assert!(lr > 0.0);
+    }
+
+    #[test]
+    fn posterior_predictive_check_is_seeded_and_calibrated() {
+        let ctx = toy_context();
+        let (_, _, counts, abund) = run_gibbs_sampler(&ctx, &all_species(), 1e-8, &gibbs_config(), 5);
+        let a = posterior_predictive_check(&ctx, &all_species(), 1e-8, &abund, &counts, 11);
+        let b = posterior_predictive_check(&ctx, &all_species(), 1e-8, &abund, &counts, 11);
+        assert_eq!((a.p_values, a.overall_p), (b.p_values.clone(), b.overall_p));
+        // The fitted model explains its own reads, so no species sits in the extreme tails
+        for &p in &b.p_values[..3] {
+            assert!(p > 0.01 && p < 0.99, "p-value {p}");
+        }
+    }
+
+    #[test]
+    fn dirichlet_alpha_controls_shrinkage() {
+        let ctx = toy_context();
+        let mean_abundances = |alpha: f64| -> Vec<f64> {
+            let mut config = gibbs_config();
+            config.alpha = alpha;
+            config.unk_alpha = alpha;
+            let abund = run_gibbs_sampler(&ctx, &all_species(), 1e-8, &config, 21).3;
+            (0..4).map(|k| abund.iter().map(|a| a[k]).sum::<f64>() / abund.len() as f64).collect()
+        };
+        let entropy = |p: &[f64]| -> f64 { p.iter().filter(|&&x| x > 0.0).map(|&x| -x * x.ln()).sum() };
+
+        // A strong prior swamps 40 reads: every bin, Unknown included, sits near 1/4
+        let flat = mean_abundances(1e5);
+        assert!(flat.iter().all(|&p| (p - 0.25).abs() < 0.01), "{flat:?}");
+
+        // A weak prior leaves the unsupported Unknown bin empty
+        let sparse = mean_abundances(1e-3);
+        assert!(sparse[3] < 1e-3, "{sparse:?}");
+
+        let default = mean_abundances(1.0);
+        assert!(entropy(&sparse) < entropy(&default) && entropy(&default) < entropy(&flat));
+    }
+
+    #[test]
+    fn credible_intervals_are_stable_under_thinning() {
+        let ctx = toy_context();
+        let mut config = gibbs_config();
+        config.iterations = 5000;
+        config.burnin = 200;
+        let full = run_gibbs_sampler(&ctx, &all_species(), 1e-8, &config, 13);
+        config.thin = 5;
+        let thinned = run_gibbs_sampler(&ctx, &all_species(), 1e-8, &config, 13);
+
+        assert_eq!(thinned.3.len(), full.3.len().div_ceil(5));
+        for (idx, a) in &full.0 {
+            let b = &thinned.0[idx];
+            for (x, y) in [(a.mean, b.mean), (a.ci_low, b.ci_low), (a.ci_high, b.ci_high)] {
+                assert!((x - y).abs() < 0.03, "taxon {idx}: {x} vs {y}");
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_output_is_a_complete_gzip_stream() {
+        let dir = std::env::temp_dir().join(format!("metamix_gz_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (mut file, path) = create_output(&dir.join("out.tsv").display().to_string(), true).unwrap();
+        writeln!(file, "a\tb").unwrap();
+        file.finish().unwrap();
+
+        let mut text = String::new();
+        std::io::Read::read_to_string(&mut MultiGzDecoder::new(File::open(&path).unwrap()), &mut text).unwrap();
+        assert_eq!(text, "a\tb\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use metamix_rs::step1_parser::InputFormat;
+use metamix_rs::{run_pipeline, MetaBayesConfig};
+use std::path::PathBuf;
+
+/// 190 reads in BLAST tabular format: 120 to taxon 100, 60 to taxon 200 and 10 shared by both
+fn fixture() -> String {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/tiny.m8").display().to_string()
+}
+
+fn small_config(output: &str) -> MetaBayesConfig {
+    let mut config = MetaBayesConfig::new(&[fixture()]).unwrap();
+    config.input_format = InputFormat::M8;
+    config.output = output.to_string();
+    config.chains = 2;
+    config.mcmc_iter = 100;
+    config.gibbs_iter = 200;
+    config.gibbs_burnin = 50;
+    config.seed = Some(7);
+    config
+}
+
+#[test]
+fn pipeline_recovers_both_taxa_from_a_tiny_fixture() {
+    let dir = std::env::temp_dir().join(format!("metamix_pipeline_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let output = dir.join("tiny").display().to_string();
+
+    let result = run_pipeline(&small_config(&output)).unwrap();
+
+    let mut taxa: Vec<(&str, f64)> = result.taxa.iter().map(|t| (t.taxon_id.as_str(), t.stats.mean)).collect();
+    taxa.sort_by(|a, b| a.0.cmp(b.0));
+    assert_eq!(taxa.iter().map(|t| t.0).collect::<Vec<_>>(), ["100", "200"]);
+    // 125 and 65 reads of 190: the larger taxon keeps roughly twice the abundance
+    assert!(taxa[0].1 > 0.5 && taxa[0].1 < 0.75, "{taxa:?}");
+    assert!(taxa[1].1 > 0.2 && taxa[1].1 < 0.45, "{taxa:?}");
+    assert_eq!(result.read_assignments.len(), 190);
+
+    let rerun = run_pipeline(&small_config(&output)).unwrap();
+    assert_eq!(rerun.taxa.iter().map(|t| t.stats.mean).collect::<Vec<_>>(),
+        result.taxa.iter().map(|t| t.stats.mean).collect::<Vec<_>>());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn fewer_taxa_are_retained_as_the_unknown_penalty_ref_drops() {
+    let dir = std::env::temp_dir().join(format!("metamix_penalty_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let output = dir.join("tiny").display().to_string();
+
+    let retained: Vec<usize> = [1e-10, 1e-20, 1e-40, 1e-80].iter()
+        .map(|&p| {
+            let mut config = small_config(&output);
+            config.unknown_penalty_ref = p;
+            run_pipeline(&config).unwrap().taxa.len()
+        })
+        .collect();
+    assert!(retained.windows(2).all(|w| w[0] >= w[1]), "{retained:?}");
+    assert_eq!(retained.first(), Some(&2));
+    assert!(retained.last() < Some(&2), "{retained:?}");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn em_only_rejects_outputs_it_cannot_write() {
+    let mut config = small_config("unused");
+    config.em_only = true;
+    assert!(config.validate().is_ok());
+
+    config.output_format = metamix_rs::step4_inference::OutputFormat::Json;
+    assert!(config.validate().is_err());
+
+    config.output_format = metamix_rs::step4_inference::OutputFormat::Tsv;
+    config.report_unknown = true;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn adaptive_exchange_cannot_be_resumed() {
+    let mut config = small_config("unused");
+    config.adaptive_exchange = true;
+    assert!(config.validate().is_ok());
+    config.resume = Some("checkpoint.json".to_string());
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn config_without_inputs_is_an_error() {
+    assert!(MetaBayesConfig::new::<&str>(&[]).is_err());
+}